@@ -0,0 +1,258 @@
+//! What the admin API does, without the HTTP around it. Bindings are referred to by their index in
+//! `text_channel_bindings`, and every change to them is written back to the config file, then loaded like a SIGHUP reload.
+//! The file keeps its keys in the order they were written, and what it was before the last change is kept as
+//! `<config>.bak`.
+use crate::*;
+use serde::Serialize;
+use serde_json::Value as JsValue;
+use std::collections::{BTreeMap, BTreeSet};
+
+lazy_static::lazy_static! {
+    /// Held from reading the config file to writing it back, so two edits can't undo each other
+    static ref EDITING: Mutex<()> = Mutex::new(());
+}
+
+/// Every binding in the config file, paused ones included, in order
+pub async fn bindings(env: &Environment) -> Result<Vec<JsValue>, BridgeError> {
+    let mut config = read_config(&env.settings.config_path)?;
+    Ok(bindings_mut(&mut config)?.clone())
+}
+
+/// Appends `binding`, as it would be written in `text_channel_bindings`, and returns its index. Refused when it
+/// duplicates or conflicts with what's already bound.
+pub async fn add_binding(env: &Arc<Environment>, binding: JsValue) -> Result<usize, BridgeError> {
+    edit_config(env, |config| {
+        let bindings = bindings_mut(config)?;
+        bindings.push(binding);
+        let index = bindings.len() - 1;
+        let location = format!("text_channel_bindings[{}]", index);
+        let problems = parse(config)?.problems().into_iter().filter(|problem| problem.contains(&location)).collect::<Vec<_>>();
+        if !problems.is_empty() { return Err(BridgeError::config(problems.join("; "))) };
+        Ok(index)
+    }).await
+}
+
+/// Removes the binding at `index` and returns it. The bindings after it move up one.
+pub async fn remove_binding(env: &Arc<Environment>, index: usize) -> Result<JsValue, BridgeError> {
+    edit_config(env, |config| {
+        let bindings = bindings_mut(config)?;
+        if index >= bindings.len() { return Err(no_binding(index)) };
+        Ok(bindings.remove(index))
+    }).await
+}
+
+/// Stops or starts relaying through the binding at `index`, leaving it in the config
+pub async fn set_paused(env: &Arc<Environment>, index: usize, paused: bool) -> Result<(), BridgeError> {
+    edit_config(env, |config| {
+        let binding = bindings_mut(config)?.get_mut(index).and_then(JsValue::as_object_mut).ok_or_else(|| no_binding(index))?;
+        if paused { binding.insert("paused".to_owned(), JsValue::Bool(true)) } else { binding.shift_remove("paused") };
+        Ok(())
+    }).await
+}
+
+/// Every stored webhook by table name, with the tokens left out of the urls
+pub async fn webhooks(env: &Environment) -> Result<BTreeMap<String, Webhooks>, BridgeError> {
+    let mut all = BTreeMap::new();
+    for table in env.storage.webhook_tables().await? {
+        let mut webhooks = env.storage.webhooks(&table).await?;
+        for webhook in webhooks.values_mut().flat_map(|users| users.values_mut()) {
+            *webhook = logging::scrub(webhook).into_owned();
+        }
+        all.insert(table.name().to_owned(), webhooks);
+    }
+    Ok(all)
+}
+
+/// Mapping kind for users whose messages aren't relayed, keyed by `<channel> <user id>` of the channel the ignore
+/// was asked for in. Keys from before ignores were per channel are `<platform>:<user id>`, and still apply everywhere.
+const IGNORED_USERS: &str = "ignored_users";
+
+/// Stops or starts relaying messages from `user` in `channel`, a user on the channel's own platform. Moderators of one
+/// channel have no say over what's relayed from any other.
+pub async fn set_ignored(env: &Environment, channel: &ChannelRef, user: &str, ignored: bool) -> Result<(), BridgeError> {
+    let key = format!("{} {}", channel, user);
+    if ignored { return env.storage.put_mapping(IGNORED_USERS, &key, "").await };
+    env.storage.remove_mapping(IGNORED_USERS, &key).await?;
+    env.storage.remove_mapping(IGNORED_USERS, &format!("{}:{}", channel.platform(), user)).await
+}
+
+/// Whether messages from `user` in `channel` are ignored
+pub async fn is_ignored(env: &Environment, channel: &ChannelRef, user: &BridgeUser) -> Result<bool, BridgeError> {
+    for key in [format!("{} {}", channel, user.id), format!("{}:{}", user.platform, user.id)] {
+        if env.storage.get_mapping(IGNORED_USERS, &key).await?.is_some() { return Ok(true) };
+    }
+    Ok(false)
+}
+
+/// A stored webhook that was dropped
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct Evicted {
+    pub table: String,
+    pub channel: String,
+    pub user: String,
+}
+
+/// Forgets the webhooks for `user`, or everyone, in `channel`. The platform makes a new one next time it needs one.
+/// The webhooks themselves stay on the platform.
+pub async fn evict_webhooks(env: &Environment, table: &str, channel: &str, user: Option<&str>) -> Result<Vec<Evicted>, BridgeError> {
+    let table = env.storage.webhook_tables().await?.into_iter().find(|known| known.name() == table)
+        .ok_or_else(|| BridgeError::config(format!("no webhook table {}", table)))?;
+    let users = env.storage.webhooks(&table).await?.remove(channel).unwrap_or_default();
+    let mut evicted = Vec::new();
+    for user in users.into_keys().filter(|stored| user.is_none_or(|user| user == stored)) {
+        env.storage.remove_webhook(&table, channel, &user).await?;
+        evicted.push(Evicted { table: table.name().to_owned(), channel: channel.to_owned(), user });
+    }
+    Ok(evicted)
+}
+
+/// Forgets the webhooks for every channel nothing is relayed into anymore, once bindings are removed or paused
+pub async fn reconcile_webhooks(env: &Environment) -> Result<Vec<Evicted>, BridgeError> {
+    let bound = env.config.read().await.routes.values().flatten().cloned().collect::<BTreeSet<_>>();
+    let mut evicted = Vec::new();
+    for table in env.storage.webhook_tables().await? {
+        //`<user's platform>_to_<channel's platform>`
+        let platform = table.name().rsplit("_to_").next().unwrap_or_default();
+        for channel in env.storage.webhooks(&table).await?.into_keys() {
+            if bound.iter().any(|to| to.platform() == platform && to.id() == channel) { continue };
+            evicted.extend(evict_webhooks(env, table.name(), &channel, None).await?);
+        }
+    }
+    if !evicted.is_empty() { tracing::info!(evicted = evicted.len(), "Evicted webhooks for unbound channels") };
+    Ok(evicted)
+}
+
+fn no_binding(index: usize) -> BridgeError {
+    BridgeError::config(format!("no text_channel_bindings[{}]", index))
+}
+
+fn read_config(path: &str) -> Result<JsValue, BridgeError> {
+    let text = read_config_file(path).map_err(BridgeError::config)?;
+    serde_json::from_str(&text).map_err(|err| BridgeError::config(format!("Invalid {}: {}", path, err)))
+}
+
+fn parse(config: &JsValue) -> Result<RawConfig, BridgeError> {
+    serde_json::from_value(config.clone()).map_err(|err| BridgeError::config(err.to_string()))
+}
+
+fn bindings_mut(config: &mut JsValue) -> Result<&mut Vec<JsValue>, BridgeError> {
+    let config = config.as_object_mut().ok_or_else(|| BridgeError::config("the config isn't an object"))?;
+    config.entry("text_channel_bindings").or_insert_with(|| JsValue::Array(Vec::new()))
+        .as_array_mut().ok_or_else(|| BridgeError::config("text_channel_bindings isn't a list"))
+}
+
+/// Applies `edit` to the config file, and only writes it back if the result still loads. The previous file is left as
+/// `<config>.bak`.
+async fn edit_config<T>(env: &Arc<Environment>, edit: impl FnOnce(&mut JsValue) -> Result<T, BridgeError>) -> Result<T, BridgeError> {
+    let path = &env.settings.config_path;
+    let _editing = EDITING.lock().await;
+    let mut config = read_config(path)?;
+    let result = edit(&mut config)?;
+    parse(&config)?;
+    let text = serde_json::to_string_pretty(&config).expect("How did we get here?") + "\n";
+    persist::save_atomic(path, text.as_bytes()).await.map_err(|err| BridgeError::storage(format!("Failed to write {}: {}", path, err)))?;
+    reload::reload_config(env).await;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn environment(dir: &std::path::Path, config: JsValue) -> Arc<Environment> {
+        let path = dir.join("config.json").to_str().unwrap().to_owned();
+        std::fs::write(&path, config.to_string()).unwrap();
+        Arc::new(Environment {
+            settings: Settings::load(&["--config".to_owned(), path.clone()]).unwrap(),
+            storage: Box::new(SqliteStorage::open(":memory:").unwrap()),
+            config: RwLock::new(Arc::new(Config::load(&path).unwrap())),
+            config_error: Default::default(),
+        })
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("bridge7573_admin_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[async_std::test]
+    async fn edits_bindings_in_the_config_file() {
+        let dir = temp_dir("bindings");
+        let env = environment(&dir, json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a" }] })).await;
+
+        assert_eq!(add_binding(&env, json!({ "discord": "2", "guilded": "b" })).await.unwrap(), 1);
+        assert!(add_binding(&env, json!({ "discord": "2", "guilded": "b" })).await.unwrap_err().to_string().contains("linked more than once"));
+        assert!(add_binding(&env, json!({ "discord": "3" })).await.is_err());
+        assert_eq!(env.config.read().await.routes_from(&ChannelRef::Discord("2".to_owned())), [ChannelRef::Guilded("b".to_owned())]);
+
+        set_paused(&env, 0, true).await.unwrap();
+        assert!(env.config.read().await.routes_from(&ChannelRef::Discord("1".to_owned())).is_empty());
+        assert_eq!(bindings(&env).await.unwrap()[0]["paused"], true);
+        set_paused(&env, 0, false).await.unwrap();
+        assert!(bindings(&env).await.unwrap()[0].get("paused").is_none());
+
+        assert_eq!(remove_binding(&env, 0).await.unwrap()["discord"], "1");
+        assert!(remove_binding(&env, 5).await.is_err());
+        let written = read_config(&env.settings.config_path).unwrap();
+        assert_eq!(written["text_channel_bindings"], json!([{ "discord": "2", "guilded": "b" }]));
+        assert!(env.config.read().await.routes_from(&ChannelRef::Discord("1".to_owned())).is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn keeps_the_config_files_layout() {
+        let dir = temp_dir("layout");
+        let env = environment(&dir, json!({})).await;
+        let path = &env.settings.config_path;
+        let written = r#"{ "text_channel_bindings": [{ "guilded": "a", "discord": "1", "direction": "both" }], "text_channel_groups": [] }"#;
+        std::fs::write(path, written).unwrap();
+
+        set_paused(&env, 0, true).await.unwrap();
+        set_paused(&env, 0, false).await.unwrap();
+        let text = std::fs::read_to_string(path).unwrap();
+        let keys = ["text_channel_bindings", "guilded", "discord", "direction", "text_channel_groups"].map(|key| text.find(key).unwrap());
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "{}", text);
+        assert!(std::fs::read_to_string(format!("{}.bak", path)).unwrap().contains("\"paused\": true"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn ignores_users_per_channel() {
+        let dir = temp_dir("ignored");
+        let env = environment(&dir, json!({})).await;
+        let (one, two) = (ChannelRef::Discord("1".to_owned()), ChannelRef::Discord("2".to_owned()));
+        let bob = BridgeUser { platform: DISCORD.to_owned(), id: "bob".to_owned(), name: "bob".to_owned(), avatar_url: None };
+        set_ignored(&env, &one, "bob", true).await.unwrap();
+        assert!(is_ignored(&env, &one, &bob).await.unwrap());
+        assert!(!is_ignored(&env, &two, &bob).await.unwrap());
+
+        //Ignored before ignores were per channel: everywhere, until unignored anywhere
+        env.storage.put_mapping(IGNORED_USERS, "discord:bob", "").await.unwrap();
+        assert!(is_ignored(&env, &two, &bob).await.unwrap());
+        set_ignored(&env, &one, "bob", false).await.unwrap();
+        assert!(!is_ignored(&env, &one, &bob).await.unwrap() && !is_ignored(&env, &two, &bob).await.unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn evicts_webhooks_for_unbound_channels() {
+        let dir = temp_dir("webhooks");
+        let env = environment(&dir, json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a" }] })).await;
+        let table = WebhookTable::new(GUILDED, DISCORD);
+        env.storage.put_webhook(&table, "1", "u", "https://discord.com/api/webhooks/10/token1").await.unwrap();
+        env.storage.put_webhook(&table, "2", "u", "https://discord.com/api/webhooks/20/token2").await.unwrap();
+        env.storage.put_webhook(&table, "2", "v", "https://discord.com/api/webhooks/21/token3").await.unwrap();
+
+        let listed = webhooks(&env).await.unwrap();
+        assert_eq!(listed["guilded_to_discord"]["1"]["u"], "https://discord.com/api/webhooks/10/<redacted>");
+
+        assert_eq!(evict_webhooks(&env, "guilded_to_discord", "2", Some("v")).await.unwrap().len(), 1);
+        let evicted = reconcile_webhooks(&env).await.unwrap();
+        assert_eq!(evicted, [Evicted { table: "guilded_to_discord".to_owned(), channel: "2".to_owned(), user: "u".to_owned() }]);
+        assert!(env.storage.get_webhook(&table, "1", "u").await.unwrap().is_some());
+        assert!(env.storage.webhooks(&table).await.unwrap().get("2").is_none_or(|users| users.is_empty()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use crate::*;
+use http_types::headers::HeaderValues;
+
+/// `bridge7573 check-config [--online] [--discord-api <url>] [--guilded-api <url>]`
+///
+/// Parses the config file and reports everything wrong with it. With `--online` every channel is also looked up with
+/// the configured credentials, against the `discord_api` and `guilded_api` settings.
+pub async fn check_config(settings: &Settings, args: &[String]) -> bool {
+    let path = &settings.config_path;
+    let online = args.iter().any(|arg| arg == "--online");
+    let discord_api = &settings.discord_api;
+    let guilded_api = &settings.guilded_api;
+
+    let text = match read_config_file(path) {
+        Ok(text) => text,
+        Err(err) => { println!("{}", err); return false; }
+    };
+    let raw = match RawConfig::parse(&text) {
+        Ok(raw) => raw,
+        Err(err) => {
+            println!("{}:{}:{}: {}", path, err.line(), err.column(), err);
+            if let Some(line) = text.lines().nth(err.line().saturating_sub(1)) {
+                println!("    {}\n    {}^", line, " ".repeat(err.column().saturating_sub(1)));
+            }
+            return false;
+        }
+    };
+
+    let problems = raw.problems();
+    for problem in &problems { println!("{}: {}", path, problem) };
+    let mut ok = problems.is_empty();
+    if online { ok &= verify_channels(settings, &raw, discord_api, guilded_api).await };
+    if ok { println!("{} is ok, {} channels", path, raw.channels().len()) };
+    ok
+}
+
+async fn verify_channels(settings: &Settings, raw: &RawConfig, discord_api: &str, guilded_api: &str) -> bool {
+    let channels = raw.channels();
+    let mut ok = true;
+
+    if channels.iter().any(|channel| matches!(channel, ChannelRef::Discord(_))) {
+        match &settings.discord_auth {
+            Some(discord_auth_header) => for channel in &channels {
+                if let ChannelRef::Discord(id) = channel {
+                    if let Err(err) = verify_discord_channel(discord_api, discord_auth_header.expose(), id).await { println!("{}: {}", channel, err); ok = false; }
+                }
+            },
+            None => { println!("No discord_auth setting, can't check discord channels"); ok = false; }
+        }
+    }
+
+    if channels.iter().any(|channel| matches!(channel, ChannelRef::Guilded(_))) {
+        let cookies = match (&settings.guilded_email, &settings.guilded_password) {
+            (Some(email), Some(password)) => guilded::authenticate(guilded_api, email, password).await.map_err(|err| err.to_string()),
+            _ => Err("No guilded_email or guilded_password setting".to_owned()),
+        };
+        match cookies {
+            Ok(cookies) => for channel in &channels {
+                if let ChannelRef::Guilded(id) = channel {
+                    if let Err(err) = verify_guilded_channel(guilded_api, &cookies, id).await { println!("{}: {}", channel, err); ok = false; }
+                }
+            },
+            Err(err) => { println!("Can't check guilded channels: {}", err); ok = false; }
+        }
+    }
+
+    if channels.iter().any(|channel| matches!(channel, ChannelRef::Matrix(_))) {
+        match settings.matrix() {
+            Ok(Some(matrix)) => match joined_matrix_rooms(&matrix).await {
+                Ok(joined) => for channel in &channels {
+                    if let ChannelRef::Matrix(id) = channel {
+                        if !joined.contains(id) { println!("{}: the bridge bot isn't in the room", channel); ok = false; }
+                    }
+                },
+                Err(err) => { println!("Can't check matrix rooms: {}", err); ok = false; }
+            },
+            Ok(None) => { println!("No matrix_homeserver setting, can't check matrix rooms"); ok = false; },
+            Err(err) => { println!("Can't check matrix rooms: {}", err); ok = false; }
+        }
+    }
+
+    ok
+}
+
+async fn joined_matrix_rooms(matrix: &MatrixSettings) -> Result<Vec<String>, BridgeError> {
+    #[derive(serde::Deserialize)]
+    struct JoinedRooms { joined_rooms: Vec<String> }
+    let response = surf::get(format!("{}/_matrix/client/v3/joined_rooms", matrix.homeserver))
+        .header("Authorization", format!("Bearer {}", matrix.as_token.expose()))
+        .send().await?;
+    let mut response = check_status(response).await.context("joined rooms lookup failed")?;
+    Ok(response.body_json::<JoinedRooms>().await?.joined_rooms)
+}
+
+async fn verify_discord_channel(discord_api: &str, discord_auth_header: &str, channel: &str) -> Result<(), BridgeError> {
+    let response = surf::get(format!("{}/channels/{}", discord_api, channel))
+        .header("Authorization", discord_auth_header)
+        .send().await?;
+    match response.status() {
+        status if status.is_success() => (),
+        surf::StatusCode::NotFound => return Err(BridgeError::config("channel doesn't exist")),
+        surf::StatusCode::Forbidden => return Err(BridgeError::config("channel isn't visible to the bridge")),
+        _ => return Err(BridgeError::http(response).await.context("channel lookup failed")),
+    }
+
+    let response = surf::get(format!("{}/channels/{}/webhooks", discord_api, channel))
+        .header("Authorization", discord_auth_header)
+        .send().await?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        surf::StatusCode::Forbidden => Err(BridgeError::config("missing the Manage Webhooks permission")),
+        _ => Err(BridgeError::http(response).await.context("webhook lookup failed")),
+    }
+}
+
+async fn verify_guilded_channel(guilded_api: &str, guilded_cookies: &HeaderValues, channel: &str) -> Result<(), BridgeError> {
+    let response = surf::get(format!("{}/channels/{}/messages?limit=1", guilded_api, channel))
+        .header("Cookie", guilded_cookies)
+        .send().await?;
+    match response.status() {
+        status if status.is_success() => Ok(()),
+        surf::StatusCode::NotFound => Err(BridgeError::config("channel doesn't exist")),
+        surf::StatusCode::Forbidden => Err(BridgeError::config("channel isn't readable by the bridge")),
+        _ => Err(BridgeError::http(response).await.context("channel lookup failed")),
+    }
+}
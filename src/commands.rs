@@ -0,0 +1,263 @@
+//! `!bridge` commands, for managing the bridge from a Discord or Guilded channel. The router runs them instead of
+//! relaying them, and answers in the same channel.
+use crate::*;
+use serde_json::{json, Value as JsValue};
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+pub const PREFIX: &str = "!bridge";
+/// Mapping kind for `!bridge link` requests waiting on the other channel, keyed by `<from> -> <to>`, with the unix
+/// time they were made in seconds
+const LINK_REQUESTS: &str = "link_requests";
+/// How long a link request waits for the other channel to agree
+const LINK_REQUEST_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Who the bridge's answers are posted as
+fn bridge_user() -> BridgeUser {
+    BridgeUser { platform: "bridge".to_owned(), id: "bridge7573".to_owned(), name: "bridge7573".to_owned(), avatar_url: None }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Binds this channel to a channel on the other platform, once the same is asked for from over there
+    Link(ChannelRef),
+    /// Removes every binding this channel is in
+    Unlink,
+    /// This channel's bindings, and how the platforms are doing. The only one anyone can use.
+    Status,
+    Pause,
+    Resume,
+    /// Stops relaying messages from a user on the channel's platform in this channel, by id
+    IgnoreUser(String),
+    UnignoreUser(String),
+    /// The usage, after what was wrong if anything
+    Help(Option<String>),
+}
+
+const USAGE: &str = "`!bridge link <channel id>`, `!bridge link discord:<id>` or `guilded:<id>`, `!bridge unlink`, `!bridge status`, `!bridge pause`, `!bridge resume`, `!bridge ignore-user <user id>`, `!bridge unignore-user <user id>`";
+
+impl Command {
+    /// `None` for anything that isn't a `!bridge` command. Commands only work on Discord and Guilded, the platforms
+    /// bindings are made of.
+    pub fn parse(channel: &ChannelRef, content: &str) -> Option<Command> {
+        if !matches!(channel, ChannelRef::Discord(_) | ChannelRef::Guilded(_)) { return None };
+        let mut words = content.split_whitespace();
+        if words.next() != Some(PREFIX) { return None };
+        Some(Command::from_words(channel, words.collect()))
+    }
+
+    /// The words after `!bridge`, or a slash command's name and options
+    pub fn from_words(channel: &ChannelRef, words: Vec<&str>) -> Command {
+        match words.as_slice() {
+            ["link", other] => match link_target(channel, other) {
+                Ok(other) => Command::Link(other),
+                Err(err) => Command::Help(Some(err)),
+            },
+            ["ignore-user", user] => Command::IgnoreUser(user_id(user)),
+            ["unignore-user", user] => Command::UnignoreUser(user_id(user)),
+            ["unlink"] => Command::Unlink,
+            ["status"] => Command::Status,
+            ["pause"] => Command::Pause,
+            ["resume"] => Command::Resume,
+            [] | ["help", ..] => Command::Help(None),
+            _ => Command::Help(Some(format!("I don't know `{} {}`", PREFIX, words.join(" ")))),
+        }
+    }
+
+    fn needs_moderator(&self) -> bool {
+        !matches!(self, Command::Status | Command::Help(_))
+    }
+}
+
+/// Discord mentions like `<@123>` or `<@!123>` work as well as the id
+fn user_id(user: &str) -> String {
+    user.trim_start_matches("<@").trim_start_matches('!').trim_end_matches('>').to_owned()
+}
+
+/// A bare id is on the other platform of the two
+fn link_target(channel: &ChannelRef, other: &str) -> Result<ChannelRef, String> {
+    let other = match other.split_once(':') {
+        Some((DISCORD, id)) => ChannelRef::Discord(id.to_owned()),
+        Some((GUILDED, id)) => ChannelRef::Guilded(id.to_owned()),
+        Some((platform, _)) => return Err(format!("Only discord and guilded channels can be linked, not {}", platform)),
+        None => match channel {
+            ChannelRef::Discord(_) => ChannelRef::Guilded(other.to_owned()),
+            _ => ChannelRef::Discord(other.to_owned()),
+        },
+    };
+    if other.platform() == channel.platform() { return Err(format!("{} is on {} too, link it to a channel on the other platform", other, channel.platform())) };
+    if other.id().is_empty() { return Err("Which channel?".to_owned()) };
+    Ok(other)
+}
+
+/// Runs `message` if it's a command and answers it. False when it isn't one, and should be relayed as usual.
+pub async fn handle(env: &Arc<Environment>, platforms: &BTreeMap<&'static str, Arc<dyn Platform>>, message: &BridgeMessage) -> bool {
+    let command = match Command::parse(&message.channel, &message.content) { Some(command) => command, None => return false };
+    let platform = match platforms.get(message.channel.platform()) { Some(platform) => platform, None => return true };
+    let moderator = if command.needs_moderator() {
+        platform.is_moderator(message.channel.id(), &message.author.id).await.map_err(|err| {
+            warn!(channel = %message.channel, user = %message.author.id, error = %err, "Failed to check roles");
+        })
+    } else {
+        Ok(false)
+    };
+    let answer = match moderator {
+        Ok(moderator) => answer(env, platforms, &message.channel, &message.author, command, moderator).await,
+        Err(()) => "Couldn't check your roles, try again later".to_owned(),
+    };
+    let answer = BridgeMessage { id: String::new(), channel: message.channel.clone(), author: bridge_user(), content: answer, attachments: vec![] };
+    if let Err(err) = platform.send_message(message.channel.id(), &answer).await {
+        warn!(channel = %message.channel, error = %err, "Failed to answer command");
+    }
+    true
+}
+
+/// Runs `command` from `user` in `channel` if they're allowed to, and what to answer them with
+pub async fn answer(env: &Arc<Environment>, platforms: &BTreeMap<&'static str, Arc<dyn Platform>>, channel: &ChannelRef, user: &BridgeUser, command: Command, moderator: bool) -> String {
+    if !command.needs_moderator() { return run(env, platforms, channel, command).await };
+    if !moderator { return "Only moderators can do that".to_owned() };
+    let answer = run(env, platforms, channel, command.clone()).await;
+    info!(%channel, user = %user.id, ?command, answer = %answer, "Ran command");
+    answer
+}
+
+async fn run(env: &Arc<Environment>, platforms: &BTreeMap<&'static str, Arc<dyn Platform>>, channel: &ChannelRef, command: Command) -> String {
+    let result = match command {
+        Command::Link(other) => link(env, channel, &other).await,
+        Command::Unlink => edit_bindings(env, channel, "Unlinked", |index| async move {
+            admin::remove_binding(env, index).await.map(drop)
+        }).await,
+        Command::Pause => edit_bindings(env, channel, "Paused", |index| admin::set_paused(env, index, true)).await,
+        Command::Resume => edit_bindings(env, channel, "Resumed", |index| admin::set_paused(env, index, false)).await,
+        Command::IgnoreUser(user) => admin::set_ignored(env, channel, &user, true).await
+            .map(|_| format!("Not relaying anything from {} user {} in {} anymore", channel.platform(), user, channel)),
+        Command::UnignoreUser(user) => admin::set_ignored(env, channel, &user, false).await
+            .map(|_| format!("Relaying {} user {} in {} again", channel.platform(), user, channel)),
+        Command::Status => status(env, platforms, channel).await,
+        Command::Help(None) => Ok(USAGE.to_owned()),
+        Command::Help(Some(problem)) => Ok(format!("{}. {}", problem, USAGE)),
+    };
+    result.unwrap_or_else(|err| format!("That didn't work: {}", err))
+}
+
+/// Being a moderator in one channel says nothing about the other, so the link is only made once a moderator there
+/// asked for it too
+async fn link(env: &Arc<Environment>, channel: &ChannelRef, other: &ChannelRef) -> Result<String, BridgeError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0);
+    let fresh = |made: &str| made.parse::<u64>().is_ok_and(|made| now.saturating_sub(made) <= LINK_REQUEST_TTL.as_secs());
+    for (key, made) in env.storage.mappings(LINK_REQUESTS).await? {
+        if !fresh(&made) { env.storage.remove_mapping(LINK_REQUESTS, &key).await? };
+    }
+    let asked = format!("{} -> {}", other, channel);
+    if env.storage.get_mapping(LINK_REQUESTS, &asked).await?.is_none() {
+        env.storage.put_mapping(LINK_REQUESTS, &format!("{} -> {}", channel, other), &now.to_string()).await?;
+        return Ok(format!("A moderator of {} has to run `{} link {}` there within {} minutes to finish linking", other, PREFIX, channel, LINK_REQUEST_TTL.as_secs() / 60));
+    }
+    env.storage.remove_mapping(LINK_REQUESTS, &asked).await?;
+    let (discord, guilded) = if let ChannelRef::Discord(_) = channel { (channel, other) } else { (other, channel) };
+    admin::add_binding(env, json!({ DISCORD: discord.id(), GUILDED: guilded.id() })).await?;
+    Ok(format!("Linked {} and {}", channel, other))
+}
+
+/// The indices of every binding `channel` is in, with the bindings themselves
+async fn bindings_of(env: &Environment, channel: &ChannelRef) -> Result<Vec<(usize, JsValue)>, BridgeError> {
+    Ok(admin::bindings(env).await?.into_iter().enumerate()
+        .filter(|(_, binding)| binding[channel.platform()].as_str() == Some(channel.id()))
+        .collect())
+}
+
+/// Applies `edit` to every binding `channel` is in, last first so removing one doesn't move the others
+async fn edit_bindings<F: std::future::Future<Output = Result<(), BridgeError>>>(env: &Arc<Environment>, channel: &ChannelRef, done: &str, mut edit: impl FnMut(usize) -> F) -> Result<String, BridgeError> {
+    let bindings = bindings_of(env, channel).await?;
+    if bindings.is_empty() { return Ok(format!("{} isn't linked to anything", channel)) };
+    for (index, _) in bindings.iter().rev() { edit(*index).await? };
+    Ok(format!("{} {}", done, bindings.iter().map(|(_, binding)| describe(binding)).collect::<Vec<_>>().join(", ")))
+}
+
+async fn status(env: &Environment, platforms: &BTreeMap<&'static str, Arc<dyn Platform>>, channel: &ChannelRef) -> Result<String, BridgeError> {
+    let bindings = bindings_of(env, channel).await?;
+    let mut lines = if bindings.is_empty() { vec![format!("{} isn't linked to anything", channel)] }
+        else { bindings.iter().map(|(_, binding)| describe(binding)).collect() };
+    let platforms = platforms.values().map(|platform| {
+        format!("{} {}", platform.name(), if platform.status().connected { "connected" } else { "reconnecting" })
+    });
+    lines.push(platforms.collect::<Vec<_>>().join(", "));
+    Ok(lines.join("\n"))
+}
+
+/// `discord:1 + guilded:a (both)`, as it's written in `text_channel_bindings`
+fn describe(binding: &JsValue) -> String {
+    let channels = [DISCORD, GUILDED, MATRIX, IRC].iter()
+        .filter_map(|platform| binding[*platform].as_str().map(|id| format!("{}:{}", platform, id)))
+        .collect::<Vec<_>>();
+    let direction = binding["direction"].as_str().unwrap_or("both");
+    let paused = if binding["paused"] == true { ", paused" } else { "" };
+    format!("{} ({}{})", channels.join(" + "), direction, paused)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let discord = ChannelRef::Discord("1".to_owned());
+        let guilded = ChannelRef::Guilded("a".to_owned());
+        assert_eq!(Command::parse(&discord, "hello"), None);
+        assert_eq!(Command::parse(&discord, "!bridges"), None);
+        assert_eq!(Command::parse(&ChannelRef::Irc("#a".to_owned()), "!bridge status"), None);
+        assert_eq!(Command::parse(&discord, "!bridge  status "), Some(Command::Status));
+        assert_eq!(Command::parse(&discord, "!bridge link b"), Some(Command::Link(ChannelRef::Guilded("b".to_owned()))));
+        assert_eq!(Command::parse(&guilded, "!bridge link 2"), Some(Command::Link(ChannelRef::Discord("2".to_owned()))));
+        assert_eq!(Command::parse(&guilded, "!bridge link discord:2"), Some(Command::Link(ChannelRef::Discord("2".to_owned()))));
+        assert!(matches!(Command::parse(&guilded, "!bridge link guilded:b"), Some(Command::Help(Some(_)))));
+        assert!(matches!(Command::parse(&discord, "!bridge link irc:#a"), Some(Command::Help(Some(_)))));
+        assert!(matches!(Command::parse(&discord, "!bridge unlink now"), Some(Command::Help(Some(_)))));
+        assert_eq!(Command::parse(&discord, "!bridge"), Some(Command::Help(None)));
+        assert_eq!(Command::parse(&discord, "!bridge ignore-user <@!42>"), Some(Command::IgnoreUser("42".to_owned())));
+        assert_eq!(Command::parse(&discord, "!bridge frobnicate a b c"), Some(Command::Help(Some("I don't know `!bridge frobnicate a b c`".to_owned()))));
+    }
+
+    #[test]
+    fn describes_bindings() {
+        assert_eq!(describe(&json!({ "discord": "1", "guilded": "a" })), "discord:1 + guilded:a (both)");
+        assert_eq!(describe(&json!({ "discord": "1", "guilded": "a", "irc": "#a", "direction": "read_only", "paused": true })), "discord:1 + guilded:a + irc:#a (read_only, paused)");
+    }
+
+    #[async_std::test]
+    async fn links_only_once_both_channels_asked() {
+        let dir = std::env::temp_dir().join(format!("bridge7573_commands_link_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config_path = dir.join("config.json").to_str().unwrap().to_owned();
+        std::fs::write(&config_path, "{}").unwrap();
+        let env = Arc::new(Environment {
+            settings: Settings::load(&["--config".to_owned(), config_path.clone()]).unwrap(),
+            storage: Box::new(SqliteStorage::open(":memory:").unwrap()),
+            config: RwLock::new(Arc::new(Config::load(&config_path).unwrap())),
+            config_error: Default::default(),
+        });
+        let (discord, guilded) = (ChannelRef::Discord("1".to_owned()), ChannelRef::Guilded("a".to_owned()));
+        let moderator = BridgeUser { platform: DISCORD.to_owned(), id: "mod".to_owned(), name: "mod".to_owned(), avatar_url: None };
+        let link = |from: &ChannelRef, to: &ChannelRef, is_moderator| {
+            let (env, from, to, moderator) = (env.clone(), from.clone(), to.clone(), moderator.clone());
+            async move { answer(&env, &BTreeMap::new(), &from, &moderator, Command::Link(to), is_moderator).await }
+        };
+
+        //A moderator of one channel can't bind it to a channel they don't moderate
+        assert_eq!(link(&discord, &guilded, true).await, "A moderator of guilded:a has to run `!bridge link discord:1` there within 15 minutes to finish linking");
+        assert!(env.config.read().await.routes_from(&discord).is_empty());
+        assert_eq!(link(&guilded, &discord, false).await, "Only moderators can do that");
+        assert_eq!(link(&ChannelRef::Guilded("b".to_owned()), &discord, true).await.split(' ').take(4).collect::<Vec<_>>(), ["A", "moderator", "of", "discord:1"]);
+        assert!(env.config.read().await.routes_from(&discord).is_empty());
+
+        assert_eq!(link(&guilded, &discord, true).await, "Linked guilded:a and discord:1");
+        assert_eq!(env.config.read().await.routes_from(&discord), [ChannelRef::Guilded("a".to_owned())]);
+        assert_eq!(env.storage.mappings(LINK_REQUESTS).await.unwrap().keys().collect::<Vec<_>>(), ["guilded:b -> discord:1"]);
+
+        //Expired requests don't count
+        env.storage.put_mapping(LINK_REQUESTS, "discord:2 -> guilded:c", "0").await.unwrap();
+        assert!(link(&ChannelRef::Guilded("c".to_owned()), &ChannelRef::Discord("2".to_owned()), true).await.starts_with("A moderator of discord:2"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,32 +1,158 @@
 use serde::{Serialize, Deserialize};
-use std::sync::Arc;
 use std::fs::File;
 use std::io::Read;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use crate::settings::FileSettings;
 
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-struct RawConfig {
+pub struct RawConfig {
+    #[serde(default)]
     pub text_channel_bindings: Vec<ChannelBinding>,
+    #[serde(default)]
+    pub text_channel_groups: Vec<ChannelGroup>,
+    #[serde(default)]
+    pub settings: FileSettings,
 }
 
 pub struct Config {
-    pub text_channel_bindings: Vec<ChannelBinding>,
-    pub text_channel_gd: BTreeMap<String, String>,
-    pub text_channel_dg: BTreeMap<String, String>,
+    /// Where a message from each channel should be relayed to
+    pub routes: BTreeMap<ChannelRef, Vec<ChannelRef>>,
 }
 
 impl Config {
-    pub fn load_blocking() -> Config {
-        let mut string_cause_yes = String::new();
-        File::open("config.json").expect("No config.json").read_to_string(&mut string_cause_yes).expect("Died while reading config.json");
-        let raw = serde_json::from_str::<RawConfig>(&string_cause_yes).expect("Invalid config.json");
-        
-        Config {
-            text_channel_gd: raw.text_channel_bindings.iter().map(|binding| (binding.guilded.to_owned(), binding.discord.to_owned())).collect(),
-            text_channel_dg: raw.text_channel_bindings.iter().map(|binding| (binding.discord.to_owned(), binding.guilded.to_owned())).collect(),
-            text_channel_bindings: raw.text_channel_bindings,
+    pub fn load_blocking(path: &str) -> Config {
+        Config::load(path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn load(path: &str) -> Result<Config, String> {
+        let raw = RawConfig::parse(&read_config_file(path)?).map_err(|err| format!("Invalid {}: {}", path, err))?;
+        for problem in raw.problems() { tracing::warn!(path, "{}", problem) };
+        Ok(Config::from_raw(&raw))
+    }
+
+    fn from_raw(raw: &RawConfig) -> Config {
+        let mut config = Config { routes: BTreeMap::new() };
+        for binding in raw.text_channel_bindings.iter().filter(|binding| !binding.paused) { config.add_group(&binding.as_group()) };
+        for group in &raw.text_channel_groups { config.add_group(group) };
+        config
+    }
+
+    pub fn routes_from(&self, channel: &ChannelRef) -> &[ChannelRef] {
+        self.routes.get(channel).map(|routes| &**routes).unwrap_or(&[])
+    }
+
+    /// Every (from, to) pair that messages are relayed along
+    pub fn route_pairs(&self) -> BTreeSet<(ChannelRef, ChannelRef)> {
+        self.routes.iter()
+            .flat_map(|(from, to)| to.iter().map(move |to| (from.clone(), to.clone())))
+            .collect()
+    }
+
+    fn add_group(&mut self, group: &ChannelGroup) {
+        for from in group.members.iter().filter(|member| member.direction.sends()) {
+            let routes = self.routes.entry(from.channel.clone()).or_default();
+            for to in group.members.iter().filter(|member| member.direction.receives()) {
+                //Never relay a channel into itself, and only once into each channel even if it's in several groups
+                if to.channel != from.channel && !routes.contains(&to.channel) { routes.push(to.channel.clone()) };
+            }
+        }
+    }
+}
+
+pub fn read_config_file(path: &str) -> Result<String, String> {
+    let mut string_cause_yes = String::new();
+    File::open(path).map_err(|err| format!("No {}: {}", path, err))?
+        .read_to_string(&mut string_cause_yes).map_err(|err| format!("Died while reading {}: {}", path, err))?;
+    Ok(string_cause_yes)
+}
+
+impl RawConfig {
+    pub fn parse(text: &str) -> Result<RawConfig, serde_json::Error> {
+        serde_json::from_str::<RawConfig>(text)
+    }
+
+    /// Every channel mentioned anywhere in the config
+    pub fn channels(&self) -> BTreeSet<ChannelRef> {
+        self.groups().into_iter().flat_map(|(_, group)| group.members.into_iter().map(|member| member.channel)).collect()
+    }
+
+    /// Bindings and groups that are almost certainly mistakes: duplicates, conflicting directions, malformed ids
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        //Both orderings of a pair land on the same key, along with whether each way is relayed
+        let mut links = BTreeMap::<(&ChannelRef, &ChannelRef), Vec<(&str, bool, bool)>>::new();
+        let groups = self.groups();
+        for (location, group) in &groups {
+            if group.members.len() < 2 { problems.push(format!("{} needs at least two members", location)) };
+            for (i, a) in group.members.iter().enumerate() {
+                match &a.channel {
+                    ChannelRef::Discord(id) if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) => problems.push(format!("{}: {} is not a discord channel id", location, a.channel)),
+                    ChannelRef::Guilded(id) if id.is_empty() => problems.push(format!("{}: empty guilded channel id", location)),
+                    ChannelRef::Matrix(id) if !id.starts_with('!') || !id.contains(':') => problems.push(format!("{}: {} is not a matrix room id like !abc:example.org", location, a.channel)),
+                    ChannelRef::Irc(id) if !id.starts_with(['#', '&']) || id.contains([' ', ',', '\x07']) => problems.push(format!("{}: {} is not an irc channel like #abc", location, a.channel)),
+                    _ => (),
+                }
+                for b in &group.members[i + 1..] {
+                    if a.channel == b.channel { problems.push(format!("{}: {} is listed more than once", location, a.channel)); continue; }
+                    let (a, b) = if a.channel < b.channel { (a, b) } else { (b, a) };
+                    let a_to_b = a.direction.sends() && b.direction.receives();
+                    let b_to_a = b.direction.sends() && a.direction.receives();
+                    let link = links.entry((&a.channel, &b.channel)).or_default();
+                    if !link.iter().any(|(seen_in, _, _)| seen_in == location) { link.push((location, a_to_b, b_to_a)) };
+                }
+            }
+        }
+        for ((a, b), locations) in links {
+            if locations.len() < 2 { continue };
+            let places = locations.iter().map(|(location, _, _)| *location).collect::<Vec<_>>().join(", ");
+            if locations.iter().all(|(_, a_to_b, b_to_a)| (*a_to_b, *b_to_a) == (locations[0].1, locations[0].2)) {
+                problems.push(format!("{} and {} are linked more than once: {}", a, b, places));
+            } else {
+                problems.push(format!("{} and {} are linked with conflicting directions: {}", a, b, places));
+            }
         }
+        problems
+    }
+
+    fn groups(&self) -> Vec<(String, ChannelGroup)> {
+        let bindings = self.text_channel_bindings.iter().enumerate().map(|(i, binding)| (format!("text_channel_bindings[{}]", i), binding.as_group()));
+        let groups = self.text_channel_groups.iter().enumerate().map(|(i, group)| (format!("text_channel_groups[{}]", i), group.clone()));
+        bindings.chain(groups).collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelRef {
+    Discord(String),
+    Guilded(String),
+    /// A room id like `!abc:example.org`
+    Matrix(String),
+    /// A channel name like `#abc` on the one configured server
+    Irc(String),
+}
+
+impl ChannelRef {
+    /// The name of the `Platform` the channel is on
+    pub fn platform(&self) -> &'static str {
+        match self {
+            ChannelRef::Discord(_) => crate::discord::DISCORD,
+            ChannelRef::Guilded(_) => crate::guilded::GUILDED,
+            ChannelRef::Matrix(_) => crate::matrix::MATRIX,
+            ChannelRef::Irc(_) => crate::irc::IRC,
+        }
+    }
+    pub fn id(&self) -> &str {
+        match self {
+            ChannelRef::Discord(id) | ChannelRef::Guilded(id) | ChannelRef::Matrix(id) | ChannelRef::Irc(id) => id,
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.platform(), self.id())
     }
 }
 
@@ -34,4 +160,179 @@ impl Config {
 pub struct ChannelBinding {
     guilded: String,
     discord: String,
+    /// A matrix room and an irc channel to join into the conversation. They follow `direction` for `both` and
+    /// `read_only`, and only receive for the one way directions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    matrix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    irc: Option<String>,
+    #[serde(default)]
+    direction: BindingDirection,
+    /// Kept in the config but nothing is relayed through it, see `admin::set_paused`
+    #[serde(default, skip_serializing_if = "is_false")]
+    paused: bool,
+}
+fn is_false(value: &bool) -> bool { !value }
+impl ChannelBinding {
+    fn as_group(&self) -> ChannelGroup {
+        let (discord, guilded) = match self.direction {
+            BindingDirection::Both => (MemberDirection::Both, MemberDirection::Both),
+            BindingDirection::DiscordToGuilded => (MemberDirection::SendOnly, MemberDirection::ReceiveOnly),
+            BindingDirection::GuildedToDiscord => (MemberDirection::ReceiveOnly, MemberDirection::SendOnly),
+            BindingDirection::ReadOnly => (MemberDirection::ReadOnly, MemberDirection::ReadOnly),
+        };
+        let mut members = vec![
+            GroupMember { channel: ChannelRef::Discord(self.discord.to_owned()), direction: discord },
+            GroupMember { channel: ChannelRef::Guilded(self.guilded.to_owned()), direction: guilded },
+        ];
+        let direction = match self.direction {
+            BindingDirection::Both => MemberDirection::Both,
+            BindingDirection::ReadOnly => MemberDirection::ReadOnly,
+            BindingDirection::DiscordToGuilded | BindingDirection::GuildedToDiscord => MemberDirection::ReceiveOnly,
+        };
+        if let Some(matrix) = &self.matrix { members.push(GroupMember { channel: ChannelRef::Matrix(matrix.to_owned()), direction }) };
+        if let Some(irc) = &self.irc { members.push(GroupMember { channel: ChannelRef::Irc(irc.to_owned()), direction }) };
+        ChannelGroup { members }
+    }
+}
+
+/// Which way messages flow through a binding. Defaults to `both`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BindingDirection {
+    #[default]
+    Both,
+    DiscordToGuilded,
+    GuildedToDiscord,
+    /// Bound, but nothing is relayed either way.
+    ReadOnly,
+}
+
+/// Any number of channels that all relay into each other.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelGroup {
+    members: Vec<GroupMember>,
+}
+
+/// `{ "discord": "<channel id>", "direction": "send_only" }` or the same with `guilded`, `matrix` or `irc`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GroupMember {
+    #[serde(flatten)]
+    channel: ChannelRef,
+    #[serde(default)]
+    direction: MemberDirection,
+}
+
+/// Whether a group member sends its messages to the rest of the group, receives theirs, or both. Defaults to `both`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberDirection {
+    #[default]
+    Both,
+    SendOnly,
+    ReceiveOnly,
+    ReadOnly,
+}
+impl MemberDirection {
+    pub fn sends(self) -> bool { matches!(self, MemberDirection::Both | MemberDirection::SendOnly) }
+    pub fn receives(self) -> bool { matches!(self, MemberDirection::Both | MemberDirection::ReceiveOnly) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn routes(config: serde_json::Value) -> Vec<String> {
+        let raw = serde_json::from_value::<RawConfig>(config).unwrap();
+        Config::from_raw(&raw).route_pairs().into_iter().map(|(from, to)| format!("{} -> {}", from, to)).collect()
+    }
+
+    #[test]
+    fn bindings_follow_their_direction() {
+        assert_eq!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a" }] })), ["discord:1 -> guilded:a", "guilded:a -> discord:1"]);
+        assert_eq!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "direction": "discord_to_guilded" }] })), ["discord:1 -> guilded:a"]);
+        assert_eq!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "direction": "guilded_to_discord", "irc": "#a" }] })),
+            ["guilded:a -> discord:1", "guilded:a -> irc:#a"]);
+        assert!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "direction": "read_only" }] })).is_empty());
+        assert!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "paused": true }] })).is_empty());
+        //Bindings have always been free to carry notes of their own
+        assert_eq!(routes(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "comment": "announcements" }] })).len(), 2);
+    }
+
+    #[test]
+    fn groups_fan_out_to_every_other_member() {
+        let members = json!([{ "discord": "1" }, { "guilded": "a" }, { "matrix": "!a:example.org" }]);
+        assert_eq!(routes(json!({ "text_channel_groups": [{ "members": members }] })), [
+            "discord:1 -> guilded:a", "discord:1 -> matrix:!a:example.org",
+            "guilded:a -> discord:1", "guilded:a -> matrix:!a:example.org",
+            "matrix:!a:example.org -> discord:1", "matrix:!a:example.org -> guilded:a",
+        ]);
+    }
+
+    #[test]
+    fn group_members_follow_their_own_direction() {
+        let members = json!([
+            { "discord": "1", "direction": "send_only" },
+            { "guilded": "a", "direction": "receive_only" },
+            { "guilded": "b", "direction": "read_only" },
+            { "irc": "#a" },
+        ]);
+        assert_eq!(routes(json!({ "text_channel_groups": [{ "members": members }] })), ["discord:1 -> guilded:a", "discord:1 -> irc:#a", "irc:#a -> guilded:a"]);
+    }
+
+    #[test]
+    fn relays_within_a_platform_once_per_pair() {
+        //Same platform relays only stay loop free because each platform skips messages its own webhooks posted
+        let group = json!({ "members": [{ "discord": "1" }, { "discord": "2" }] });
+        assert_eq!(routes(json!({ "text_channel_groups": [group.clone(), group] })), ["discord:1 -> discord:2", "discord:2 -> discord:1"]);
+        let config = Config::from_raw(&serde_json::from_value(json!({ "text_channel_groups": [{ "members": [{ "guilded": "a" }, { "guilded": "b" }] }] })).unwrap());
+        assert_eq!(config.routes_from(&ChannelRef::Guilded("a".to_owned())), [ChannelRef::Guilded("b".to_owned())]);
+        assert!(config.routes_from(&ChannelRef::Guilded("c".to_owned())).is_empty());
+    }
+
+    fn problems(config: serde_json::Value) -> Vec<String> {
+        serde_json::from_value::<RawConfig>(config).unwrap().problems()
+    }
+
+    #[test]
+    fn finds_duplicate_links() {
+        let binding = json!({ "discord": "1", "guilded": "a" });
+        assert_eq!(problems(json!({ "text_channel_bindings": [binding.clone(), binding] })),
+            ["discord:1 and guilded:a are linked more than once: text_channel_bindings[0], text_channel_bindings[1]"]);
+        //A group repeating a binding counts too
+        assert_eq!(problems(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a" }], "text_channel_groups": [{ "members": [{ "guilded": "a" }, { "discord": "1" }] }] })),
+            ["discord:1 and guilded:a are linked more than once: text_channel_bindings[0], text_channel_groups[0]"]);
+        assert_eq!(problems(json!({ "text_channel_groups": [{ "members": [{ "discord": "1" }, { "discord": "1" }] }] })),
+            ["text_channel_groups[0]: discord:1 is listed more than once"]);
+    }
+
+    #[test]
+    fn finds_conflicting_directions() {
+        let config = json!({ "text_channel_bindings": [
+            { "discord": "1", "guilded": "a", "direction": "discord_to_guilded" },
+            { "discord": "1", "guilded": "a", "direction": "guilded_to_discord" },
+        ] });
+        assert_eq!(problems(config), ["discord:1 and guilded:a are linked with conflicting directions: text_channel_bindings[0], text_channel_bindings[1]"]);
+    }
+
+    #[test]
+    fn finds_malformed_ids() {
+        let config = json!({
+            "text_channel_bindings": [{ "discord": "general", "guilded": "" }],
+            "text_channel_groups": [
+                { "members": [{ "matrix": "#room:example.org" }, { "irc": "no spaces" }] },
+                { "members": [{ "discord": "2" }] },
+            ],
+        });
+        assert_eq!(problems(config), [
+            "text_channel_bindings[0]: discord:general is not a discord channel id",
+            "text_channel_bindings[0]: empty guilded channel id",
+            "text_channel_groups[0]: matrix:#room:example.org is not a matrix room id like !abc:example.org",
+            "text_channel_groups[0]: irc:no spaces is not an irc channel like #abc",
+            "text_channel_groups[1] needs at least two members",
+        ]);
+        assert!(problems(json!({ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "matrix": "!a:example.org", "irc": "#a" }] })).is_empty());
+    }
 }
@@ -0,0 +1,724 @@
+use crate::*;
+use async_std::task::JoinHandle;
+use async_tungstenite::tungstenite::Message;
+use futures::FutureExt;
+use serde::{Serialize, Deserialize};
+use serde_json::Value as JsValue;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{trace, warn};
+
+pub const DISCORD: &str = "discord";
+/// The default `discord_api`
+pub const DISCORD_API: &str = "https://discord.com/api/v8";
+/// The default `discord_cdn`
+pub const DISCORD_CDN: &str = "https://cdn.discordapp.com";
+pub const DISCORD_HEARTBEAT_OP: u8 = 1;
+/// How long Hello and the answer to identifying can take
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Permission bits that make someone a moderator for `/bridge`, whatever their roles
+const ADMINISTRATOR: u64 = 1 << 3;
+const MANAGE_GUILD: u64 = 1 << 5;
+/// Message flag for answers only whoever asked can see
+const EPHEMERAL: u64 = 1 << 6;
+
+lazy_static::lazy_static! {
+    pub static ref ALLOWED_MENTIONS_NONE: serde_json::Value = {
+        serde_json::json!( {"parse": []} )
+    };
+}
+
+/// A Discord account on the gateway, relaying through webhooks it makes as it goes.
+pub struct Discord {
+    env: Arc<Environment>,
+    auth: Secret,
+    /// Every session's gateway events go out through here, so subscribers carry on across reconnects
+    gateway_out: Sender<GatewayEvent>,
+    gateway: Subscriber<GatewayEvent>,
+    health: SessionHealth,
+    /// `/bridge` only needs registering once per run, not every session
+    commands_registered: AtomicBool,
+}
+
+/// A gateway frame, parsed once as it comes off the socket and shared with everyone listening
+#[derive(Debug)]
+pub enum GatewayEvent {
+    /// op 0
+    Dispatch(Dispatch),
+    /// op 1, the gateway wants a heartbeat right away
+    HeartbeatRequest,
+    /// op 7
+    Reconnect,
+    /// op 9
+    InvalidSession,
+    /// op 10
+    Hello { heartbeat_interval: u64 },
+    /// op 11
+    HeartbeatAck,
+    /// Any other op, or a frame that didn't parse
+    Other,
+}
+
+/// The events the bridge looks into, the rest are only named
+#[derive(Debug)]
+pub enum Dispatch {
+    /// The application id is only there for bot accounts
+    Ready { application_id: Option<String> },
+    MessageCreate(DiscordMessage),
+    MessageUpdate(DiscordMessage),
+    MessageDelete { id: String, channel_id: String },
+    MessageReactionAdd(DiscordReaction),
+    /// Boxed, it's much bigger than everything else
+    InteractionCreate(Box<DiscordInteraction>),
+    Other(String),
+}
+
+impl GatewayEvent {
+    /// Parses a frame, noting the sequence number down on the way for heartbeats
+    fn parse(msg: Message, sequence_number: &std::sync::Mutex<Option<i64>>) -> Option<GatewayEvent> {
+        #[derive(Deserialize)]
+        struct Frame {
+            op: u8,
+            #[serde(default)]
+            d: JsValue,
+            s: Option<i64>,
+            t: Option<String>,
+        }
+        let frame = match msg {
+            Message::Text(msg) => {
+                trace!(frame = %msg, "Gateway frame");
+                match serde_json::from_str::<Frame>(&msg) { Ok(frame) => frame, Err(_) => return Some(GatewayEvent::Other) }
+            },
+            _ => return None,
+        };
+        if let Some(s) = frame.s { *sequence_number.lock().unwrap() = Some(s) };
+        Some(match frame.op {
+            0 => GatewayEvent::Dispatch(Dispatch::parse(frame.t.unwrap_or_default(), frame.d)),
+            DISCORD_HEARTBEAT_OP => GatewayEvent::HeartbeatRequest,
+            7 => GatewayEvent::Reconnect,
+            9 => GatewayEvent::InvalidSession,
+            10 => match frame.d["heartbeat_interval"].as_u64() { Some(heartbeat_interval) => GatewayEvent::Hello { heartbeat_interval }, None => GatewayEvent::Other },
+            11 => GatewayEvent::HeartbeatAck,
+            _ => GatewayEvent::Other,
+        })
+    }
+}
+
+impl Dispatch {
+    fn parse(event_type: String, data: JsValue) -> Dispatch {
+        fn typed<T: serde::de::DeserializeOwned>(data: JsValue, event_type: String, wrap: impl FnOnce(T) -> Dispatch) -> Dispatch {
+            T::deserialize(data).map(wrap).unwrap_or_else(|err| {
+                warn!(event = %event_type, error = %err, "Failed to deserialize");
+                Dispatch::Other(event_type)
+            })
+        }
+        match event_type.as_str() {
+            "READY" => Dispatch::Ready { application_id: data["application"]["id"].as_str().map(str::to_owned) },
+            "MESSAGE_CREATE" => typed(data, event_type, Dispatch::MessageCreate),
+            //Updates without an author are discord filling in embeds, not people editing, and end up as `Other`
+            "MESSAGE_UPDATE" => typed(data, event_type, Dispatch::MessageUpdate),
+            "MESSAGE_DELETE" => {
+                #[derive(Deserialize)]
+                struct Deleted { id: String, channel_id: String }
+                typed(data, event_type, |deleted: Deleted| Dispatch::MessageDelete { id: deleted.id, channel_id: deleted.channel_id })
+            },
+            "MESSAGE_REACTION_ADD" => typed(data, event_type, Dispatch::MessageReactionAdd),
+            "INTERACTION_CREATE" => typed(data, event_type, |interaction| Dispatch::InteractionCreate(Box::new(interaction))),
+            _ => Dispatch::Other(event_type),
+        }
+    }
+
+    /// The `BridgeEvent` this is, if it's one the bridge cares about. Avatar urls point at `cdn`.
+    fn to_bridge(&self, cdn: &str) -> Option<BridgeEvent> {
+        match self {
+            Dispatch::MessageCreate(msg) => msg.to_bridge(cdn).map(BridgeEvent::MessageCreated),
+            Dispatch::MessageUpdate(msg) => msg.to_bridge(cdn).map(BridgeEvent::MessageEdited),
+            Dispatch::MessageDelete { id, channel_id } => Some(BridgeEvent::MessageDeleted { channel: ChannelRef::Discord(channel_id.clone()), id: id.clone() }),
+            Dispatch::MessageReactionAdd(reaction) => Some(BridgeEvent::ReactionAdded {
+                channel: ChannelRef::Discord(reaction.channel_id.clone()),
+                message_id: reaction.message_id.clone(),
+                user: reaction.member.as_ref()?.user.to_bridge(cdn),
+                emoji: reaction.emoji.name.clone()?,
+            }),
+            //Interactions need answering through Discord, so `run` hands them over itself
+            Dispatch::Ready { .. } | Dispatch::InteractionCreate(_) | Dispatch::Other(_) => None,
+        }
+    }
+}
+
+impl Discord {
+    /// Connecting to the gateway happens in `run`
+    pub fn new(env: Arc<Environment>, auth: Secret) -> Arc<Discord> {
+        let (gateway_out, gateway) = MultiRecv::new();
+        Arc::new(Discord { env, auth, gateway_out, gateway: gateway.subscriber(), health: SessionHealth::default(), commands_registered: AtomicBool::new(false) })
+    }
+
+    /// Every gateway event from now on, for anything else that wants to listen in. A listener that falls
+    /// `DEFAULT_CAPACITY` events behind loses the oldest ones, rather than holding up relaying.
+    pub fn subscribe(&self) -> MultiRecv<GatewayEvent> {
+        self.gateway.subscribe_with(DEFAULT_CAPACITY, Overflow::DropOldest)
+    }
+
+    async fn get_webhook(&self, author: &BridgeUser, discord_channel: &str) -> Result<String, BridgeError> {
+        //Get from database
+        let table = WebhookTable::new(&author.platform, DISCORD);
+        if let Some(webhook) = self.env.storage.get_webhook(&table, discord_channel, &author.id).await? { return Ok(webhook) };
+
+        let avatar = if let Some(avatar_url) = &author.avatar_url {
+            let avatar_response = surf::get(avatar_url).send().await?;
+            let mut avatar_response = check_status(avatar_response).await.with_context(|| format!("Discord Make Webhook: Failed to get avatar for {} user {}", author.platform, author.id))?;
+            let content_type = avatar_response.header("Content-Type").map(|header| header[0].to_string()).unwrap_or_else(|| "image/png".to_owned());
+            Some(format!("data:{};base64,{}", content_type, base64::encode(avatar_response.body_bytes().await?)))
+        } else { None };
+
+        #[derive(Serialize)]
+        struct CreateWebhook {
+            name: String,
+            avatar: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct WebhookResponse {
+            id: String,
+            token: String,
+        }
+        let body = CreateWebhook {
+            name: author.display_name(),
+            avatar,
+        };
+        let response = surf::post(format!("{}/channels/{}/webhooks", self.env.settings.discord_api, discord_channel))
+            .header("Authorization", self.auth.expose())
+            .body(surf::Body::from_json(&body)?).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Discord Make Webhook: Webhook creation for {} user {}", author.platform, author.id))?;
+        let created_webhook = response.body_json::<WebhookResponse>().await?;
+
+        let webhook = format!("{}/webhooks/{}/{}", self.env.settings.discord_api, created_webhook.id, created_webhook.token);
+        self.env.storage.put_webhook(&table, discord_channel, &author.id, &webhook).await?;
+        metrics::WEBHOOKS_CREATED.inc(&[("platform", DISCORD)]);
+        Ok(webhook)
+    }
+
+    /// Only finds webhooks, editing or deleting a copy never needs a new one
+    async fn existing_webhook(&self, author: &BridgeUser, discord_channel: &str) -> Result<String, BridgeError> {
+        let table = WebhookTable::new(&author.platform, DISCORD);
+        self.env.storage.get_webhook(&table, discord_channel, &author.id).await?
+            .ok_or_else(|| BridgeError::storage(format!("no webhook for {} user {} in {}", author.platform, author.id, discord_channel)))
+    }
+
+    /// Goes through recorded frames the way a session goes through live ones, then ends like a gateway with nothing
+    /// more to say
+    async fn replay(&self, frames: Vec<String>, events: &Sender<BridgeEvent>) -> Result<(), BridgeError> {
+        let _connected = self.health.connected();
+        let sequence_number = std::sync::Mutex::new(None);
+        for frame in frames {
+            if let Some(GatewayEvent::Dispatch(dispatch)) = GatewayEvent::parse(Message::Text(frame), &sequence_number) {
+                if let Some(event) = dispatch.to_bridge(&self.env.settings.discord_cdn) { let _ = events.send(event).await; }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates or updates `/bridge` among the application's commands, in the background, leaving any others alone.
+    /// Only bot accounts have commands.
+    fn register_commands(&self, application_id: String) {
+        if !self.auth.expose().starts_with("Bot ") || self.commands_registered.swap(true, Ordering::Relaxed) { return };
+        let (auth, api) = (self.auth.clone(), self.env.settings.discord_api.clone());
+        async_std::task::spawn(async move {
+            let registered = async {
+                let response = surf::post(format!("{}/applications/{}/commands", api, application_id))
+                    .header("Authorization", auth.expose())
+                    .body(surf::Body::from_json(&bridge_command())?).await?;
+                check_status(response).await.context("Discord Register commands")
+            };
+            if let Err(err) = registered.await { warn!(error = %err, "Failed to register /bridge") };
+        });
+    }
+
+    /// Runs a `/bridge` command through the router, answering only whoever used it
+    async fn interact(self: Arc<Self>, interaction: DiscordInteraction, events: Sender<BridgeEvent>) {
+        if let Err(err) = self.answer_interaction(&interaction, &events).await {
+            warn!(interaction = %interaction.id, error = %err, "Failed to answer interaction");
+        }
+    }
+
+    async fn answer_interaction(&self, interaction: &DiscordInteraction, events: &Sender<BridgeEvent>) -> Result<(), BridgeError> {
+        //Deferred, since Discord only waits 3 seconds for the answer and editing bindings can take longer
+        let response = surf::post(format!("{}/interactions/{}/{}/callback", self.env.settings.discord_api, interaction.id, interaction.token))
+            .body(surf::Body::from_json(&serde_json::json!({ "type": 5, "data": { "flags": EPHEMERAL } }))?).await?;
+        check_status(response).await.context("Discord Interaction callback")?;
+
+        let answer = match (&interaction.channel_id, &interaction.member) {
+            (Some(channel_id), Some(member)) => {
+                let channel = ChannelRef::Discord(channel_id.clone());
+                match interaction.command(&channel) {
+                    Some(command) => {
+                        let (answer, answered) = async_std::channel::bounded(1);
+                        let moderator = interaction.is_moderator(&self.env.settings.discord_admin_roles);
+                        let event = BridgeEvent::Command { channel, user: member.user.to_bridge(&self.env.settings.discord_cdn), command, moderator, answer };
+                        let answered = if events.send(event).await.is_ok() { answered.recv().await.ok() } else { None };
+                        answered.unwrap_or_else(|| "The bridge is shutting down, try again later".to_owned())
+                    },
+                    None => "I don't know that command".to_owned(),
+                }
+            },
+            _ => "Bridge commands only work in server channels".to_owned(),
+        };
+        let response = surf::patch(format!("{}/webhooks/{}/{}/messages/@original", self.env.settings.discord_api, interaction.application_id, interaction.token))
+            .body(surf::Body::from_json(&serde_json::json!({ "content": answer, "allowed_mentions": *ALLOWED_MENTIONS_NONE }))?).await?;
+        check_status(response).await.context("Discord Interaction answer")?;
+        Ok(())
+    }
+}
+
+/// `/bridge`, with a subcommand for each `commands::Command`. Only people who can manage the server see it unless
+/// the server says otherwise, and `discord_admin_roles` can use it too.
+fn bridge_command() -> JsValue {
+    let subcommand = |name: &str, description: &str, option: Option<(u8, &str, &str)>| {
+        let options = option.map(|(kind, name, description)| serde_json::json!({ "type": kind, "name": name, "description": description, "required": true }));
+        serde_json::json!({ "type": 1, "name": name, "description": description, "options": options.into_iter().collect::<Vec<_>>() })
+    };
+    //Option types: 3 is a string, 6 a user
+    serde_json::json!({
+        "name": "bridge",
+        "description": "Manage what the bridge relays",
+        "type": 1,
+        "default_member_permissions": MANAGE_GUILD.to_string(),
+        "dm_permission": false,
+        "options": [
+            subcommand("link", "Relay this channel to and from a Guilded channel", Some((3, "channel", "The Guilded channel id"))),
+            subcommand("unlink", "Stop relaying this channel", None),
+            subcommand("status", "Show what this channel is linked to and how the bridge is doing", None),
+            subcommand("pause", "Stop relaying this channel for now, keeping its links", None),
+            subcommand("resume", "Start relaying this channel again", None),
+            subcommand("ignore-user", "Stop relaying someone's messages from this channel", Some((6, "user", "Who to ignore"))),
+            subcommand("unignore-user", "Relay someone's messages from this channel again", Some((6, "user", "Who to stop ignoring"))),
+        ],
+    })
+}
+
+#[async_trait::async_trait]
+impl Platform for Discord {
+    fn name(&self) -> &'static str { DISCORD }
+
+    fn status(&self) -> PlatformStatus { self.health.status() }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        if let Some(path) = &self.env.settings.replay { return self.replay(recording::load(path, DISCORD)?, &events).await };
+        //Every session identifies from scratch instead of resuming, so the sequence starts over too
+        let sequence_number = Arc::new(std::sync::Mutex::new(None));
+        let mut gateway = self.gateway.subscribe();
+        let (to_discord, connection, heartbeat_interval, application_id) = discord_websocket(&self.env.settings.discord_api, &self.auth, self.gateway_out.clone(), &mut gateway, sequence_number.clone()).await?;
+        if let Some(application_id) = application_id { self.register_commands(application_id) };
+        let mut connection = connection.fuse();
+        //Identified, the handshake only succeeds once Discord answers that
+        let _connected = self.health.connected();
+        metrics::watch_queue("discord_gateway", gateway.stats());
+
+        let heartbeat_interval = Duration::from_millis((heartbeat_interval as f32 * 0.95).ceil() as u64);
+        self.health.heartbeat_every(heartbeat_interval);
+        //When the heartbeat waiting for an ack went out
+        let heartbeat_sent = std::sync::Mutex::new(None);
+        let send_heartbeat = || {
+            *heartbeat_sent.lock().unwrap() = Some(Instant::now());
+            to_discord.send(make_discord_heartbeat(&sequence_number))
+        };
+        let heartbeat = async {
+            loop {
+                async_std::task::sleep(heartbeat_interval).await;
+                //A connection that stopped acknowledging heartbeats is a zombie, only a new one gets things moving again
+                if self.health.stale() { return BridgeError::transport("Discord: Heartbeats stopped being acknowledged") };
+                if send_heartbeat().await.is_err() { return BridgeError::transport("Discord heartbeat died") };
+            }
+        }.fuse();
+        let relay = async {
+            while let Some(event) = gateway.next().await {
+                match &*event {
+                    GatewayEvent::HeartbeatRequest => { let _ = send_heartbeat().await; },
+                    GatewayEvent::HeartbeatAck => {
+                        self.health.acked();
+                        if let Some(sent) = heartbeat_sent.lock().unwrap().take() { metrics::HEARTBEAT_LATENCY.set(&[("platform", DISCORD)], sent.elapsed().as_secs_f64()) };
+                    },
+                    GatewayEvent::Reconnect | GatewayEvent::InvalidSession => return BridgeError::transport("Discord asked for a new session"),
+                    GatewayEvent::Dispatch(Dispatch::InteractionCreate(interaction)) => { async_std::task::spawn(self.clone().interact((**interaction).clone(), events.clone())); },
+                    GatewayEvent::Dispatch(dispatch) => if let Some(event) = dispatch.to_bridge(&self.env.settings.discord_cdn) { let _ = events.send(event).await; },
+                    _ => (),
+                }
+            }
+            BridgeError::transport("Discord gateway events ended")
+        }.fuse();
+        futures::pin_mut!(heartbeat, relay);
+
+        select! {
+            err = heartbeat => return Err(err),
+            err = relay => return Err(err),
+            result = connection => return Err(result.err().unwrap_or_else(|| BridgeError::transport("Discord gateway closed"))),
+            _ = shutdown.stop.wait().fuse() => (),
+        }
+        //Intake is over, but the heartbeat keeps the session alive until everything queued is delivered
+        select! {
+            err = heartbeat => return Err(err),
+            result = connection => return Err(result.err().unwrap_or_else(|| BridgeError::transport("Discord gateway closed"))),
+            _ = shutdown.close.wait().fuse() => (),
+        }
+        close_ws(&to_discord, connection).await
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        let webhook = self.get_webhook(&message.author, channel).await?;
+
+        #[derive(Serialize)]
+        struct WebhookMessage {
+            content: String,
+            allowed_mentions: JsValue,
+        }
+        #[derive(Deserialize)]
+        struct SentMessage {
+            id: String,
+        }
+        let body = WebhookMessage {
+            content: message.text_with_attachments(),
+            allowed_mentions: ALLOWED_MENTIONS_NONE.clone(),
+        };
+        //wait=true makes discord answer with the message, which has the id edits and deletes need
+        let response = surf::post(format!("{}?wait=true", webhook))
+            .header("Content-Type", "application/json")
+            .body(surf::Body::from_json(&body)?).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Discord Message for {} user {}", message.author.platform, message.author.id))?;
+        Ok(Some(response.body_json::<SentMessage>().await?.id))
+    }
+
+    fn can_edit(&self) -> bool { true }
+
+    async fn edit_message(&self, channel: &str, id: &str, message: &BridgeMessage) -> Result<(), BridgeError> {
+        let webhook = self.existing_webhook(&message.author, channel).await?;
+        #[derive(Serialize)]
+        struct EditMessage {
+            content: String,
+            allowed_mentions: JsValue,
+        }
+        let body = EditMessage {
+            content: message.text_with_attachments(),
+            allowed_mentions: ALLOWED_MENTIONS_NONE.clone(),
+        };
+        let response = surf::patch(format!("{}/messages/{}", webhook, id))
+            .header("Content-Type", "application/json")
+            .body(surf::Body::from_json(&body)?).await?;
+        check_status(response).await.with_context(|| format!("Discord Edit {}", id))?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel: &str, id: &str, author: &BridgeUser) -> Result<(), BridgeError> {
+        let webhook = self.existing_webhook(author, channel).await?;
+        let response = surf::delete(format!("{}/messages/{}", webhook, id)).await?;
+        check_status(response).await.with_context(|| format!("Discord Delete {}", id))?;
+        Ok(())
+    }
+
+    async fn is_moderator(&self, channel: &str, user: &str) -> Result<bool, BridgeError> {
+        #[derive(Deserialize)]
+        struct Channel { guild_id: Option<String> }
+        #[derive(Deserialize)]
+        struct Member { roles: Vec<String> }
+        let response = surf::get(format!("{}/channels/{}", self.env.settings.discord_api, channel))
+            .header("Authorization", self.auth.expose()).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Discord Channel {}", channel))?;
+        //Direct messages have no server, and so no roles
+        let guild_id = match response.body_json::<Channel>().await?.guild_id { Some(guild) => guild, None => return Ok(false) };
+        let response = surf::get(format!("{}/guilds/{}/members/{}", self.env.settings.discord_api, guild_id, user))
+            .header("Authorization", self.auth.expose()).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Discord Member {} of {}", user, guild_id))?;
+        let roles = response.body_json::<Member>().await?.roles;
+        if may_manage(0, &roles, &self.env.settings.discord_admin_roles) { return Ok(true) };
+        let response = surf::get(format!("{}/guilds/{}", self.env.settings.discord_api, guild_id))
+            .header("Authorization", self.auth.expose()).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Discord Guild {}", guild_id))?;
+        let guild = response.body_json::<Guild>().await?;
+        Ok(may_manage(guild.permissions(user, &roles), &roles, &self.env.settings.discord_admin_roles))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiscordMessage {
+    id: String,
+    channel_id: String,
+    author: DiscordUser,
+    webhook_id: Option<String>,
+    content: Option<String>,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>
+}
+#[derive(Deserialize, Clone, Debug)]
+pub struct DiscordUser {
+    id: String,
+    username: String,
+    avatar: Option<String>,
+}
+#[derive(Deserialize, Debug)]
+pub struct DiscordAttachment {
+    proxy_url: String,
+    filename: String,
+}
+
+impl DiscordUser {
+    fn to_bridge(&self, cdn: &str) -> BridgeUser {
+        BridgeUser {
+            platform: DISCORD.to_owned(),
+            id: self.id.clone(),
+            name: self.username.clone(),
+            avatar_url: self.avatar.as_ref().map(|avatar_hash| format!("{}/avatars/{}/{}.png?size=512", cdn, self.id, avatar_hash)),
+        }
+    }
+}
+
+/// `INTERACTION_CREATE`, for slash commands
+#[derive(Deserialize, Clone, Debug)]
+pub struct DiscordInteraction {
+    id: String,
+    application_id: String,
+    token: String,
+    channel_id: Option<String>,
+    /// Only in servers
+    member: Option<InteractionMember>,
+    #[serde(default)]
+    data: JsValue,
+}
+#[derive(Deserialize, Clone, Debug)]
+struct InteractionMember {
+    user: DiscordUser,
+    #[serde(default)]
+    roles: Vec<String>,
+    /// Everything the member is allowed to do in the channel, as a decimal bitset
+    #[serde(default)]
+    permissions: String,
+}
+
+impl DiscordInteraction {
+    /// A `/bridge` subcommand and its options, taken the same way as the words after `!bridge`
+    fn command(&self, channel: &ChannelRef) -> Option<commands::Command> {
+        if self.data["name"] != "bridge" { return None };
+        let subcommand = self.data["options"].get(0)?;
+        let mut words = vec![subcommand["name"].as_str()?];
+        words.extend(subcommand["options"].as_array().into_iter().flatten().filter_map(|option| option["value"].as_str()));
+        Some(commands::Command::from_words(channel, words))
+    }
+
+    fn is_moderator(&self, admin_roles: &[String]) -> bool {
+        let member = match &self.member { Some(member) => member, None => return false };
+        may_manage(member.permissions.parse::<u64>().unwrap_or(0), &member.roles, admin_roles)
+    }
+}
+
+/// Who can manage the bridge from Discord, whether they ask with `!bridge` or `/bridge`: anyone allowed to manage the
+/// server, and anyone with one of `discord_admin_roles`
+fn may_manage(permissions: u64, roles: &[String], admin_roles: &[String]) -> bool {
+    permissions & (ADMINISTRATOR | MANAGE_GUILD) != 0 || roles.iter().any(|role| admin_roles.contains(role))
+}
+
+/// What's needed of a server to work out its members' permissions
+#[derive(Deserialize)]
+struct Guild {
+    id: String,
+    owner_id: String,
+    roles: Vec<GuildRole>,
+}
+#[derive(Deserialize)]
+struct GuildRole {
+    id: String,
+    /// A decimal bitset
+    permissions: String,
+}
+
+impl Guild {
+    /// What `user` may do anywhere in the server, from @everyone and their `roles`. Channel overwrites never take
+    /// away managing the server, so they don't matter here.
+    fn permissions(&self, user: &str, roles: &[String]) -> u64 {
+        if user == self.owner_id { return ADMINISTRATOR };
+        //@everyone's id is the server's
+        self.roles.iter().filter(|role| role.id == self.id || roles.contains(&role.id))
+            .fold(0, |permissions, role| permissions | role.permissions.parse::<u64>().unwrap_or(0))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DiscordReaction {
+    channel_id: String,
+    message_id: String,
+    member: Option<DiscordMember>,
+    emoji: DiscordEmoji,
+}
+#[derive(Deserialize, Debug)]
+struct DiscordMember {
+    user: DiscordUser,
+}
+#[derive(Deserialize, Debug)]
+struct DiscordEmoji {
+    name: Option<String>,
+}
+
+impl DiscordMessage {
+    fn to_bridge(&self, cdn: &str) -> Option<BridgeMessage> {
+        //Anything sent through a webhook is either ours or not a person, relaying it could loop forever
+        if self.webhook_id.is_some() { return None };
+        Some(BridgeMessage {
+            id: self.id.clone(),
+            channel: ChannelRef::Discord(self.channel_id.clone()),
+            author: self.author.to_bridge(cdn),
+            content: self.content.clone()?,
+            attachments: self.attachments.iter().map(|attachment| Attachment { name: attachment.filename.clone(), url: attachment.proxy_url.clone() }).collect(),
+        })
+    }
+}
+
+/// Connects and identifies. The first event after identifying means it worked.
+async fn discord_websocket(api: &str, discord_auth_header: &Secret, gateway_out: Sender<GatewayEvent>, gateway: &mut MultiRecv<GatewayEvent>, discord_sequence_number: Arc<std::sync::Mutex<Option<i64>>>)
+    -> Result<(Sender<Message>, JoinHandle<Result<(), BridgeError>>, u64, Option<String>), BridgeError> {
+    let discord_auth_header = discord_auth_header.expose();
+    let gateway_get_endpoint = if discord_auth_header.len() > 4 && &discord_auth_header[0..4] == "Bot " { format!("{}/gateway/bot", api) } else { format!("{}/gateway", api) };
+    #[derive(Serialize, Deserialize)]
+    struct GatewayResponse { url: String }
+    let get_response = surf::get(gateway_get_endpoint)
+        .header("Authorization", discord_auth_header)
+        .send().await?;
+    let mut get_response = check_status(get_response).await.context("Failed to get a gateway endpoint")?;
+    let get_response = get_response.body_json::<GatewayResponse>().await?;
+
+    let request = http::Request::builder()
+        .uri(get_response.url)
+        .header("Authorization", discord_auth_header)
+        .body(())
+        .unwrap();
+    let (ws, _response) = async_tungstenite::async_std::connect_async(request).await?;
+    let (to_discord, connection) = my_ws_task(ws, gateway_out, move |msg| {
+        recording::frame(DISCORD, &msg);
+        GatewayEvent::parse(msg, &discord_sequence_number)
+    });
+
+    //Only frames that don't parse are the gateway's fault, anything else is worth another try later
+    let unexpected = |expected: &str, event: Option<&GatewayEvent>| match event {
+        Some(GatewayEvent::Other) => BridgeError::protocol(format!("Discord gateway: Expected {}, got a frame that didn't parse", expected)),
+        //Also what identifying too often gets
+        Some(GatewayEvent::InvalidSession) => BridgeError::transport(format!("Discord gateway: Expected {}, got Invalid Session", expected)),
+        Some(event) => BridgeError::transport(format!("Discord gateway: Expected {}, got {:?}", expected, event)),
+        None => BridgeError::transport(format!("Discord gateway: Closed before {}", expected)),
+    };
+    let handshake = async {
+        let heartbeat_interval = match gateway.next().await.as_deref() {
+            Some(GatewayEvent::Hello { heartbeat_interval }) => *heartbeat_interval,
+            event => return Err(unexpected("Hello", event)),
+        };
+        to_discord.send(Message::Text(format!("{{\"op\": 2, \"d\": {{ \"token\": \"{}\", \"intents\": 1536, \"properties\": {{ \"$os\": \"linux\", \"$browser\": \"bridge7573\", \"$device\": \"bridge7573\" }} }} }}", discord_auth_header))).await?;
+        match gateway.next().await.as_deref() {
+            Some(GatewayEvent::Dispatch(dispatch)) => {
+                let application_id = match dispatch { Dispatch::Ready { application_id } => application_id.clone(), _ => None };
+                Ok((heartbeat_interval, application_id))
+            },
+            event => Err(unexpected("Ready", event)),
+        }
+    };
+    let handshake = async_std::future::timeout(HANDSHAKE_TIMEOUT, handshake).await
+        .unwrap_or_else(|_| Err(BridgeError::transport("Discord gateway handshake timed out")));
+    match handshake {
+        Ok((heartbeat_interval, application_id)) => Ok((to_discord, connection, heartbeat_interval, application_id)),
+        Err(err) => {
+            //Nothing's going to use this connection, so it isn't left running
+            connection.cancel().await;
+            Err(err)
+        },
+    }
+}
+
+fn make_discord_heartbeat(sequence_number: &std::sync::Mutex<Option<i64>>) -> Message {
+    if let Some(seq_num) = &*sequence_number.lock().unwrap() {
+        Message::Text(format!("{{ \"op\": 1, \"d\": {} }}", seq_num))
+    } else {
+        Message::Text("{ \"op\": 1, \"d\": null }".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_frames_once_and_tracks_the_sequence() {
+        let sequence_number = std::sync::Mutex::new(None);
+        let frame = |text: &str| GatewayEvent::parse(Message::Text(text.to_owned()), &sequence_number);
+
+        assert!(matches!(frame(r#"{"op": 10, "d": {"heartbeat_interval": 41250}}"#), Some(GatewayEvent::Hello { heartbeat_interval: 41250 })));
+        assert!(matches!(frame(r#"{"op": 1, "d": null}"#), Some(GatewayEvent::HeartbeatRequest)));
+        assert!(matches!(frame("not json"), Some(GatewayEvent::Other)));
+        assert!(GatewayEvent::parse(Message::Binary(vec![]), &sequence_number).is_none());
+        assert_eq!(*sequence_number.lock().unwrap(), None);
+
+        let created = frame(r#"{"op": 0, "s": 42, "t": "MESSAGE_CREATE", "d": {"id": "2", "channel_id": "1", "content": "hi", "attachments": [],
+            "author": {"id": "3", "username": "bob", "avatar": null}}}"#);
+        match created {
+            Some(GatewayEvent::Dispatch(dispatch)) => match dispatch.to_bridge(DISCORD_CDN) {
+                Some(BridgeEvent::MessageCreated(message)) => assert_eq!((message.id.as_str(), message.content.as_str(), message.author.name.as_str()), ("2", "hi", "bob")),
+                other => panic!("expected a message, got {:?}", other),
+            },
+            other => panic!("expected a dispatch, got {:?}", other),
+        }
+        assert_eq!(*sequence_number.lock().unwrap(), Some(42));
+
+        assert!(matches!(frame(r#"{"op": 0, "s": 43, "t": "TYPING_START", "d": {}}"#), Some(GatewayEvent::Dispatch(Dispatch::Other(t))) if t == "TYPING_START"));
+        assert_eq!(*sequence_number.lock().unwrap(), Some(43));
+    }
+
+    #[test]
+    fn takes_slash_commands_apart() {
+        let sequence_number = std::sync::Mutex::new(None);
+        let interaction = |permissions: &str, options: &str| {
+            let frame = format!(r#"{{"op": 0, "s": 1, "t": "INTERACTION_CREATE", "d": {{"id": "9", "application_id": "8", "token": "t", "type": 2, "channel_id": "1",
+                "member": {{"user": {{"id": "3", "username": "bob", "avatar": null}}, "roles": ["50"], "permissions": "{}"}},
+                "data": {{"name": "bridge", "options": [{}]}}}}}}"#, permissions, options);
+            match GatewayEvent::parse(Message::Text(frame), &sequence_number) {
+                Some(GatewayEvent::Dispatch(Dispatch::InteractionCreate(interaction))) => *interaction,
+                other => panic!("expected an interaction, got {:?}", other),
+            }
+        };
+        let channel = ChannelRef::Discord("1".to_owned());
+
+        let link = interaction("0", r#"{"type": 1, "name": "link", "options": [{"type": 3, "name": "channel", "value": "abc"}]}"#);
+        assert_eq!(link.command(&channel), Some(commands::Command::Link(ChannelRef::Guilded("abc".to_owned()))));
+        assert!(!link.is_moderator(&[]));
+        assert!(link.is_moderator(&["50".to_owned()]));
+
+        let ignore = interaction(&MANAGE_GUILD.to_string(), r#"{"type": 1, "name": "ignore-user", "options": [{"type": 6, "name": "user", "value": "4"}]}"#);
+        assert_eq!(ignore.command(&channel), Some(commands::Command::IgnoreUser("4".to_owned())));
+        assert!(ignore.is_moderator(&[]));
+        assert_eq!(interaction("0", r#"{"type": 1, "name": "status"}"#).command(&channel), Some(commands::Command::Status));
+    }
+
+    #[async_std::test]
+    async fn listeners_that_fall_behind_never_hold_up_the_gateway() {
+        let env = Arc::new(Environment {
+            settings: Settings::load(&["--config".to_owned(), "/nonexistent/config.json".to_owned()]).unwrap(),
+            storage: Box::new(SqliteStorage::open(":memory:").unwrap()),
+            config: RwLock::new(Arc::new(Config { routes: Default::default() })),
+            config_error: Default::default(),
+        });
+        let discord = Discord::new(env, Secret::new("Bot discord-token".to_owned()));
+        let listener = discord.subscribe();
+        let mut session = discord.gateway.subscribe();
+        let relayed = async {
+            for _ in 0..DEFAULT_CAPACITY * 3 {
+                discord.gateway_out.send(GatewayEvent::HeartbeatAck).await.unwrap();
+                session.next().await.unwrap();
+            }
+        };
+        async_std::future::timeout(Duration::from_secs(5), relayed).await.expect("held up by a listener that never reads");
+        assert_eq!(listener.queue_depth(), DEFAULT_CAPACITY);
+        assert!(listener.dropped() >= DEFAULT_CAPACITY as u64);
+    }
+
+    #[test]
+    fn works_out_server_permissions_like_interactions_get_them() {
+        let guild = serde_json::from_str::<Guild>(&format!(r#"{{"id": "1", "owner_id": "9", "roles": [
+            {{"id": "1", "permissions": "1024"}}, {{"id": "50", "permissions": "{}"}}, {{"id": "60", "permissions": "2048"}}]}}"#, MANAGE_GUILD)).unwrap();
+        assert_eq!(guild.permissions("3", &[]), 1024);
+        assert!(!may_manage(guild.permissions("3", &["60".to_owned()]), &["60".to_owned()], &[]));
+        assert!(may_manage(guild.permissions("3", &["50".to_owned()]), &["50".to_owned()], &[]));
+        assert!(may_manage(guild.permissions("9", &[]), &[], &[]));
+        assert!(may_manage(0, &["60".to_owned()], &["60".to_owned()]));
+    }
+}
@@ -0,0 +1,186 @@
+use serde_json::Value as JsValue;
+use std::time::Duration;
+
+/// The error body a platform sent back with a failed request, as far as it could be made out
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApiError {
+    /// Discord's and Guilded's `code`, Matrix's `errcode`
+    pub code: Option<String>,
+    /// `message`, or Matrix's `error`. The raw body when it isn't json.
+    pub message: Option<String>,
+}
+impl ApiError {
+    pub fn parse(body: &str) -> ApiError {
+        let json = match serde_json::from_str::<JsValue>(body) {
+            Ok(json) => json,
+            Err(_) => return ApiError { code: None, message: Some(body.trim().to_owned()).filter(|body| !body.is_empty()) },
+        };
+        let field = |names: &[&str]| names.iter().find_map(|name| match &json[name] {
+            JsValue::String(value) => Some(value.clone()),
+            JsValue::Number(value) => Some(value.to_string()),
+            _ => None,
+        });
+        ApiError { code: field(&["code", "errcode"]), message: field(&["message", "error"]) }
+    }
+}
+
+/// Everything that can go wrong, sorted by what went wrong so callers can tell whether trying again could help
+#[derive(Debug)]
+pub enum BridgeError {
+    /// A platform answered, with a status other than success
+    Http { context: String, status: u16, body: ApiError, retry_after: Option<Duration> },
+    /// Nothing usable came back: DNS, TCP, TLS, a connection dropping
+    Transport { context: String, detail: String },
+    /// Something came back that isn't what the protocol says it should be
+    Protocol { context: String, detail: String },
+    /// The settings or config don't allow it
+    Config { context: String, detail: String },
+    /// The database or data files failed
+    Storage { context: String, detail: String },
+}
+
+impl BridgeError {
+    pub fn transport(detail: impl Into<String>) -> BridgeError { BridgeError::Transport { context: String::new(), detail: detail.into() } }
+    pub fn protocol(detail: impl Into<String>) -> BridgeError { BridgeError::Protocol { context: String::new(), detail: detail.into() } }
+    pub fn config(detail: impl Into<String>) -> BridgeError { BridgeError::Config { context: String::new(), detail: detail.into() } }
+    pub fn storage(detail: impl Into<String>) -> BridgeError { BridgeError::Storage { context: String::new(), detail: detail.into() } }
+
+    /// A failed response, with its body read and parsed
+    pub async fn http(mut response: surf::Response) -> BridgeError {
+        let retry_after = response.header("Retry-After").and_then(|header| header.as_str().parse::<f64>().ok());
+        let body = response.body_string().await.unwrap_or_default();
+        //Discord says how long in the body too, with more precision than the header
+        let retry_after = serde_json::from_str::<JsValue>(&body).ok().and_then(|json| json["retry_after"].as_f64()).or(retry_after);
+        BridgeError::Http {
+            context: String::new(),
+            status: response.status().into(),
+            body: ApiError::parse(&body),
+            retry_after: retry_after.filter(|seconds| seconds.is_finite() && *seconds >= 0.0).map(Duration::from_secs_f64),
+        }
+    }
+
+    /// Puts `context` in front of whatever context there already is
+    pub fn context(mut self, context: impl std::fmt::Display) -> BridgeError {
+        let (BridgeError::Http { context: old, .. } | BridgeError::Transport { context: old, .. } | BridgeError::Protocol { context: old, .. }
+            | BridgeError::Config { context: old, .. } | BridgeError::Storage { context: old, .. }) = &mut self;
+        *old = if old.is_empty() { context.to_string() } else { format!("{}: {}", context, old) };
+        self
+    }
+
+    /// Whether the same thing could work if tried again later. Everything else needs someone to change something first.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            BridgeError::Http { status, .. } => *status == 408 || *status == 429 || *status >= 500,
+            BridgeError::Transport { .. } => true,
+            BridgeError::Protocol { .. } | BridgeError::Config { .. } | BridgeError::Storage { .. } => false,
+        }
+    }
+
+    /// How long the platform asked to wait before trying again
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self { BridgeError::Http { retry_after, .. } => *retry_after, _ => None }
+    }
+
+    /// The HTTP status the platform answered with
+    pub fn status(&self) -> Option<u16> {
+        match self { BridgeError::Http { status, .. } => Some(*status), _ => None }
+    }
+}
+
+impl std::fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (context, detail) = match self {
+            BridgeError::Http { context, status, body, .. } => {
+                let mut detail = status.to_string();
+                for part in body.code.iter().chain(&body.message) { detail = format!("{} {}", detail, part) };
+                (context, detail)
+            },
+            BridgeError::Transport { context, detail } | BridgeError::Protocol { context, detail }
+                | BridgeError::Config { context, detail } | BridgeError::Storage { context, detail } => (context, detail.clone()),
+        };
+        if context.is_empty() { write!(f, "{}", detail) } else { write!(f, "{}: {}", context, detail) }
+    }
+}
+impl std::error::Error for BridgeError {}
+
+impl From<surf::Error> for BridgeError {
+    fn from(err: surf::Error) -> BridgeError {
+        //surf gives reading a body that doesn't parse this status, everything else never got an answer
+        if err.status() == surf::StatusCode::UnprocessableEntity { BridgeError::protocol(err.to_string()) } else { BridgeError::transport(err.to_string()) }
+    }
+}
+impl From<async_tungstenite::tungstenite::Error> for BridgeError {
+    fn from(err: async_tungstenite::tungstenite::Error) -> BridgeError { BridgeError::transport(err.to_string()) }
+}
+impl From<std::io::Error> for BridgeError {
+    //Sockets fail with the network kinds, which are worth another try. Files that are missing, not ours or on a full
+    //or read-only disk stay that way until someone steps in.
+    fn from(err: std::io::Error) -> BridgeError {
+        use std::io::ErrorKind::*;
+        match err.kind() {
+            NotFound | PermissionDenied | AlreadyExists | StorageFull | ReadOnlyFilesystem | IsADirectory | NotADirectory
+                | DirectoryNotEmpty | QuotaExceeded | FileTooLarge | CrossesDevices | InvalidFilename => BridgeError::storage(err.to_string()),
+            _ => BridgeError::transport(err.to_string()),
+        }
+    }
+}
+impl From<serde_json::Error> for BridgeError {
+    fn from(err: serde_json::Error) -> BridgeError { BridgeError::protocol(err.to_string()) }
+}
+impl From<rusqlite::Error> for BridgeError {
+    fn from(err: rusqlite::Error) -> BridgeError { BridgeError::storage(err.to_string()) }
+}
+impl<T> From<async_std::channel::SendError<T>> for BridgeError {
+    fn from(_: async_std::channel::SendError<T>) -> BridgeError { BridgeError::transport("connection task is gone") }
+}
+
+/// `.context(..)` straight on a `Result`
+pub trait Context<T> {
+    fn context(self, context: impl std::fmt::Display) -> Result<T, BridgeError>;
+    fn with_context<C: std::fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T, BridgeError>;
+}
+impl<T, E: Into<BridgeError>> Context<T> for Result<T, E> {
+    fn context(self, context: impl std::fmt::Display) -> Result<T, BridgeError> {
+        self.map_err(|err| err.into().context(context))
+    }
+    fn with_context<C: std::fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T, BridgeError> {
+        self.map_err(|err| err.into().context(context()))
+    }
+}
+
+/// The response when it's a success, its `BridgeError::Http` when it isn't
+pub async fn check_status(response: surf::Response) -> Result<surf::Response, BridgeError> {
+    if response.status().is_success() { Ok(response) } else { Err(BridgeError::http(response).await) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_platforms_error_body() {
+        assert_eq!(ApiError::parse(r#"{"code": 50013, "message": "Missing Permissions"}"#), ApiError { code: Some("50013".to_owned()), message: Some("Missing Permissions".to_owned()) });
+        assert_eq!(ApiError::parse(r#"{"errcode": "M_FORBIDDEN", "error": "You are not invited"}"#), ApiError { code: Some("M_FORBIDDEN".to_owned()), message: Some("You are not invited".to_owned()) });
+        assert_eq!(ApiError::parse("Bad Gateway\n"), ApiError { code: None, message: Some("Bad Gateway".to_owned()) });
+        assert_eq!(ApiError::parse(""), ApiError::default());
+    }
+
+    #[test]
+    fn sorts_retryable_from_permanent() {
+        let http = |status| BridgeError::Http { context: String::new(), status, body: ApiError::default(), retry_after: None };
+        assert!(http(429).is_retryable() && http(502).is_retryable());
+        assert!(!http(404).is_retryable() && !http(403).is_retryable());
+        assert!(BridgeError::transport("reset").is_retryable());
+        assert!(!BridgeError::config("no key").is_retryable());
+        assert!(BridgeError::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)).is_retryable());
+        assert!(matches!(BridgeError::from(std::io::Error::from(std::io::ErrorKind::PermissionDenied)), BridgeError::Storage { .. }));
+    }
+
+    #[test]
+    fn stacks_context() {
+        let err = Err::<(), _>(BridgeError::protocol("no Hello")).context("gateway").context("Discord").unwrap_err();
+        assert_eq!(err.to_string(), "Discord: gateway: no Hello");
+        let err = BridgeError::Http { context: "Send".to_owned(), status: 403, body: ApiError { code: Some("50013".to_owned()), message: Some("Missing Permissions".to_owned()) }, retry_after: None };
+        assert_eq!(err.to_string(), "Send: 403 50013 Missing Permissions");
+    }
+}
@@ -0,0 +1,71 @@
+use crate::commands::Command;
+use crate::config::ChannelRef;
+use async_std::channel::Sender;
+use serde::{Serialize, Deserialize};
+
+/// Something that happened on a platform, translated into the shape every platform shares.
+#[derive(Clone, Debug)]
+pub enum BridgeEvent {
+    MessageCreated(BridgeMessage),
+    /// The whole message as it is after the edit
+    MessageEdited(BridgeMessage),
+    MessageDeleted { channel: ChannelRef, id: String },
+    ReactionAdded { channel: ChannelRef, message_id: String, user: BridgeUser, emoji: String },
+    /// A command the platform took apart and checked the permissions for itself, like a Discord slash command.
+    /// What to say back goes to `answer`.
+    Command { channel: ChannelRef, user: BridgeUser, command: Command, moderator: bool, answer: Sender<String> },
+}
+impl BridgeEvent {
+    /// Where it happened
+    pub fn channel(&self) -> &ChannelRef {
+        match self {
+            BridgeEvent::MessageCreated(message) | BridgeEvent::MessageEdited(message) => &message.channel,
+            BridgeEvent::MessageDeleted { channel, .. } | BridgeEvent::ReactionAdded { channel, .. } | BridgeEvent::Command { channel, .. } => channel,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BridgeMessage {
+    /// The id the message has on its own platform
+    pub id: String,
+    pub channel: ChannelRef,
+    pub author: BridgeUser,
+    pub content: String,
+    pub attachments: Vec<Attachment>,
+}
+impl BridgeMessage {
+    /// The content with a `name: url` line for every attachment, for platforms that can't re-upload them
+    pub fn text_with_attachments(&self) -> String {
+        let mut text = self.content.clone();
+        text.extend(self.attachments.iter().map(|attachment| format!("\n{}: {}", attachment.name, attachment.url)));
+        text
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BridgeUser {
+    /// The name of the `Platform` the user is on
+    pub platform: String,
+    pub id: String,
+    pub name: String,
+    pub avatar_url: Option<String>,
+}
+impl BridgeUser {
+    /// The name relayed messages are posted under, marked with where the user really is
+    pub fn display_name(&self) -> String {
+        match self.platform.as_str() {
+            crate::discord::DISCORD => format!("💬 {}", self.name),
+            crate::guilded::GUILDED => format!("📀 {}", self.name),
+            crate::matrix::MATRIX => format!("Ⓜ️ {}", self.name),
+            crate::irc::IRC => format!("⌨️ {}", self.name),
+            _ => self.name.clone(),
+        }
+    }
+}
@@ -0,0 +1,468 @@
+use crate::*;
+use async_std::task::JoinHandle;
+use async_tungstenite::tungstenite::Message;
+use futures::FutureExt;
+use http_types::headers::HeaderValues;
+use serde::{Serialize, Deserialize};
+use serde_json::Value as JsValue;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use surf::Body;
+use tracing::{debug, trace, warn};
+
+pub const GUILDED: &str = "guilded";
+/// The default `guilded_api`
+pub const GUILDED_API: &str = "https://www.guilded.gg/api";
+/// The default `guilded_socket`
+pub const GUILDED_SOCKET: &str = "wss://api.guilded.gg/socket.io/?jwt=undefined&EIO=3&transport=websocket";
+/// The default `guilded_media`
+pub const GUILDED_MEDIA: &str = "https://media.guilded.gg";
+/// How often engine.io expects a `2` ping
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(24);
+/// How long a user's name and avatar are used before they're looked up again
+const USER_TTL: Duration = Duration::from_secs(60 * 60);
+/// The most users remembered at once, whoever was looked up longest ago is forgotten first
+const MAX_USERS: usize = 10_000;
+
+/// A Guilded user account, relaying through webhooks it makes as it goes.
+pub struct Guilded {
+    env: Arc<Environment>,
+    email: String,
+    password: Secret,
+    /// The session from the last login, `run` logs in again every time it connects
+    cookies: RwLock<Option<HeaderValues>>,
+    /// Every session's socket events go out through here, so subscribers carry on across reconnects
+    socket_out: Sender<GuildedEvent>,
+    socket: Subscriber<GuildedEvent>,
+    health: SessionHealth,
+    /// Guilded only sends user ids with messages, so everyone seen lately is remembered, with when they were looked up
+    users: Mutex<BTreeMap<String, (BridgeUser, Instant)>>,
+    /// Which team each channel messages came from is in, for looking up roles
+    teams: Mutex<BTreeMap<String, String>>,
+}
+
+/// A socket.io frame, parsed once as it comes off the socket and shared with everyone listening
+#[derive(Debug)]
+pub enum GuildedEvent {
+    ChatMessageCreated(ChatMessage),
+    ChatMessageUpdated(ChatMessage),
+    ChatMessageDeleted { channel_id: String, id: String },
+    /// Engine.io's answer to our `2` heartbeat
+    Pong,
+    /// Any other event, by name
+    Other(String),
+}
+
+impl GuildedEvent {
+    /// Socket.io frames are a packet type followed by `["EventName", data]`, anything without that is `None`
+    fn parse(msg: Message) -> Option<GuildedEvent> {
+        let msg = match msg { Message::Text(msg) => msg, _ => return None };
+        trace!(frame = %msg, "Socket frame");
+        if msg == "3" { return Some(GuildedEvent::Pong) };
+        let (event_type, data) = match serde_json::from_str::<JsValue>(&msg[msg.find('[')?..]).ok()? {
+            JsValue::Array(mut contents) if contents.len() >= 2 => match contents.swap_remove(0) {
+                JsValue::String(event_type) => (event_type, contents.swap_remove(0)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let parsed = match event_type.as_str() {
+            "ChatMessageCreated" => ChatMessage::deserialize(data).map(GuildedEvent::ChatMessageCreated),
+            "ChatMessageUpdated" => ChatMessage::deserialize(data).map(GuildedEvent::ChatMessageUpdated),
+            "ChatMessageDeleted" => {
+                #[derive(Deserialize)]
+                struct Deleted { #[serde(rename = "channelId")] channel_id: String, message: DeletedMessage }
+                #[derive(Deserialize)]
+                struct DeletedMessage { id: String }
+                Deleted::deserialize(data).map(|deleted| GuildedEvent::ChatMessageDeleted { channel_id: deleted.channel_id, id: deleted.message.id })
+            },
+            _ => return Some(GuildedEvent::Other(event_type)),
+        };
+        Some(parsed.unwrap_or_else(|err| {
+            warn!(event = %event_type, error = %err, "Failed to deserialize");
+            GuildedEvent::Other(event_type)
+        }))
+    }
+}
+
+pub async fn authenticate(guilded_api: &str, guilded_email: &str, guilded_password: &Secret) -> Result<HeaderValues, BridgeError> {
+    #[derive(Serialize)]
+    struct LoginBody { email: String, password: String, }
+    let uri = guilded_api.to_owned() + "/login";
+    let body = LoginBody { email: guilded_email.to_owned(), password: guilded_password.expose().to_owned() };
+    let res = surf::post(uri).body(surf::Body::from_json(&body)?).await?;
+    let res = check_status(res).await.context("authenticate_guilded")?;
+    let cookies = res.header("Set-Cookie").cloned().ok_or_else(|| BridgeError::protocol("authenticate_guilded no set-cookie"))?;
+    //The session cookie is as good as the password
+    for cookie in cookies.iter() {
+        let value = cookie.as_str().split(';').next().unwrap_or("");
+        logging::redact(value.split_once('=').map(|(_, value)| value).unwrap_or(value));
+    }
+    Ok(cookies)
+}
+
+impl Guilded {
+    /// Logging in and connecting to the socket happen in `run`
+    pub fn new(env: Arc<Environment>, email: String, password: Secret) -> Arc<Guilded> {
+        let (socket_out, socket) = MultiRecv::new();
+        Arc::new(Guilded {
+            env, email, password, cookies: RwLock::new(None), socket_out, socket: socket.subscriber(), health: SessionHealth::default(),
+            users: Mutex::new(BTreeMap::new()), teams: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// The cookies of the current login
+    async fn cookies(&self) -> Result<HeaderValues, BridgeError> {
+        self.cookies.read().await.clone().ok_or_else(|| BridgeError::transport("Guilded: Not logged in yet"))
+    }
+
+    /// Every socket event from now on, for anything else that wants to listen in. A listener that falls
+    /// `DEFAULT_CAPACITY` events behind loses the oldest ones, rather than holding up relaying.
+    pub fn subscribe(&self) -> MultiRecv<GuildedEvent> {
+        self.socket.subscribe_with(DEFAULT_CAPACITY, Overflow::DropOldest)
+    }
+
+    async fn get_user(&self, guilded_user: &str) -> Result<BridgeUser, BridgeError> {
+        if let Some((user, _)) = self.users.lock().await.get(guilded_user).filter(|(_, fetched)| fetched.elapsed() < USER_TTL) { return Ok(user.clone()) };
+
+        #[derive(Deserialize)]
+        struct UserData {
+            name: String,
+            #[serde(rename = "profilePictureSm")]
+            avatar: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct UserResponse {
+            user: UserData,
+        }
+        let user_response = surf::get(format!("{}/users/{}", self.env.settings.guilded_api, guilded_user))
+            .header("Cookie", &self.cookies().await?)
+            .send().await?;
+        let mut user_response = check_status(user_response).await.with_context(|| format!("Guilded: Failed to fetch user {}", guilded_user))?;
+        let user = user_response.body_json::<UserResponse>().await?.user;
+
+        let user = BridgeUser { platform: GUILDED.to_owned(), id: guilded_user.to_owned(), name: user.name, avatar_url: user.avatar };
+        let mut users = self.users.lock().await;
+        users.insert(guilded_user.to_owned(), (user.clone(), Instant::now()));
+        if users.len() > MAX_USERS {
+            let oldest = users.iter().min_by_key(|(_, (_, fetched))| *fetched).map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest { users.remove(&oldest); }
+        }
+        Ok(user)
+    }
+
+    async fn chat_message(&self, msg: &ChatMessage, edited: bool) -> Result<Option<BridgeEvent>, BridgeError> {
+        //Anything sent through a webhook is either ours or not a person, relaying it could loop forever
+        if msg.message.webhook_id.is_some() { return Ok(None) };
+        let channel = ChannelRef::Guilded(msg.channel_id.clone());
+        if let Some(team) = &msg.team_id { self.teams.lock().await.insert(msg.channel_id.clone(), team.clone()); };
+        let mut content = String::new();
+        extract_text_from_node(&msg.message.content.document, &mut content);
+        //Not worth looking the author up for a message that goes nowhere, unless it's a command. Edits might belong
+        //to a message that did.
+        let goes_nowhere = self.env.config.read().await.routes_from(&channel).is_empty();
+        if !edited && goes_nowhere && commands::Command::parse(&channel, &content).is_none() { return Ok(None) };
+
+        let author = self.get_user(&msg.author).await?;
+        let message = BridgeMessage { id: msg.message.id.clone(), channel, author, content, attachments: vec![] };
+        Ok(Some(if edited { BridgeEvent::MessageEdited(message) } else { BridgeEvent::MessageCreated(message) }))
+    }
+
+    /// Goes through recorded frames the way a session goes through live ones, then ends like a socket with nothing
+    /// more to say
+    async fn replay(&self, frames: Vec<String>, events: &Sender<BridgeEvent>) -> Result<(), BridgeError> {
+        let _connected = self.health.connected();
+        for frame in frames {
+            let event = match GuildedEvent::parse(Message::Text(frame)) { Some(event) => event, None => continue };
+            if let Some(event) = self.translate_event(&event).await { let _ = events.send(event).await; }
+        }
+        Ok(())
+    }
+
+    async fn translate_event(&self, event: &GuildedEvent) -> Option<BridgeEvent> {
+        let translated = match event {
+            GuildedEvent::ChatMessageCreated(msg) => self.chat_message(msg, false).await,
+            GuildedEvent::ChatMessageUpdated(msg) => self.chat_message(msg, true).await,
+            GuildedEvent::ChatMessageDeleted { channel_id, id } => return Some(BridgeEvent::MessageDeleted { channel: ChannelRef::Guilded(channel_id.clone()), id: id.clone() }),
+            GuildedEvent::Pong | GuildedEvent::Other(_) => return None,
+        };
+        match translated {
+            Ok(event) => event,
+            Err(err) => { warn!(?event, error = %err, "Failed to translate"); None }
+        }
+    }
+
+    async fn get_webhook(&self, author: &BridgeUser, guilded_channel: &str) -> Result<String, BridgeError> {
+        //Get from database
+        let table = WebhookTable::new(&author.platform, GUILDED);
+        if let Some(webhook) = self.env.storage.get_webhook(&table, guilded_channel, &author.id).await? { return Ok(webhook) };
+
+        #[derive(Serialize, Clone)]
+        struct CreateWebhookBody {
+            #[serde(rename="channelId")]
+            channel: String,
+            name: String,
+            #[serde(rename="iconUrl")]
+            avatar_url: Option<String>,
+        }
+        #[derive(Deserialize)]
+        struct CreateWebhookResponse {
+            id: String,
+            token: String,
+        }
+        //Guilded avatars are already hosted by guilded, so they can be used as the icon directly
+        let hosted_avatar = if author.platform == GUILDED { author.avatar_url.clone() } else { None };
+        let mut body = CreateWebhookBody {
+            channel: guilded_channel.to_owned(),
+            name: author.display_name(),
+            avatar_url: hosted_avatar,
+        };
+        let response = surf::post(format!("{}/webhooks", self.env.settings.guilded_api))
+            .header("Content-Type", "application/json")
+            .header("Cookie", &self.cookies().await?)
+            .body(Body::from_json(&body)?).await?;
+        let mut response = check_status(response).await
+            .with_context(|| format!("Guilded Make Webhook: Failed to make webhook for {} user {} in channel {}", author.platform, author.id, guilded_channel))?;
+        let created_webhook = response.body_json::<CreateWebhookResponse>().await?;
+
+        let webhook = format!("{}/webhooks/{}/{}", self.env.settings.guilded_media, created_webhook.id, created_webhook.token);
+        self.env.storage.put_webhook(&table, guilded_channel, &author.id, &webhook).await?;
+        metrics::WEBHOOKS_CREATED.inc(&[("platform", GUILDED)]);
+
+        //Everyone else's avatar has to be uploaded to guilded first, which is slow enough to not hold the message up for
+        let upload_avatar_from = if body.avatar_url.is_none() { author.avatar_url.clone() } else { None };
+        if let Some(avatar_url) = upload_avatar_from {
+            let (cookies, api, media) = (self.cookies().await?, self.env.settings.guilded_api.clone(), self.env.settings.guilded_media.clone());
+            let author = author.clone();
+            let webhook_id = created_webhook.id;
+            async_std::task::spawn(async move {
+                let avatar = match surf::get(&avatar_url).send().await {
+                    Ok(mut response) => {
+                        if !response.status().is_success() { warn!(platform = %author.platform, user = %author.id, status = %response.status(), "Failed to get avatar"); None }
+                        else {
+                            match response.body_bytes().await {
+                                Ok(bytes) => {
+                                    match upload_avatar(&media, &cookies, format!("avatar_{}.png", author.id), &bytes).await {
+                                        Ok(url) => Some(url),
+                                        Err(err) => { warn!(platform = %author.platform, user = %author.id, error = %err, "Failed to upload avatar"); None }
+                                    }
+                                },
+                                Err(err) => { warn!(platform = %author.platform, user = %author.id, error = %err, "Failed to get avatar"); None }
+                            }
+                        }
+                    },
+                    Err(err) => { warn!(platform = %author.platform, user = %author.id, error = %err, "Failed to get avatar"); None }
+                };
+
+                if avatar.is_some() {
+                    body.avatar_url = avatar;
+                    let response = surf::put(format!("{}/webhooks/{}", api, webhook_id))
+                        .header("Content-Type", "application/json")
+                        .header("Cookie", &cookies)
+                        .body(Body::from_json(&body).expect("How did we get here?")).await;
+                    if let Err(err) = response { warn!(platform = %author.platform, user = %author.id, error = %err, "Failed to set webhook avatar") };
+                }
+            });
+        }
+
+        Ok(webhook)
+    }
+}
+
+#[async_trait::async_trait]
+impl Platform for Guilded {
+    fn name(&self) -> &'static str { GUILDED }
+
+    fn status(&self) -> PlatformStatus { self.health.status() }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        //Every connection logs in afresh, so an expired session is replaced by reconnecting
+        let cookies = authenticate(&self.env.settings.guilded_api, &self.email, &self.password).await?;
+        *self.cookies.write().await = Some(cookies.clone());
+        //Replayed frames still look users up and make webhooks through the API
+        if let Some(path) = &self.env.settings.replay { return self.replay(recording::load(path, GUILDED)?, &events).await };
+        let mut socket = self.socket.subscribe();
+        let (to_guilded, connection) = guilded_websocket(&self.env.settings.guilded_socket, cookies, self.socket_out.clone()).await?;
+        let mut connection = connection.fuse();
+        let _connected = self.health.connected();
+        metrics::watch_queue("guilded_socket", socket.stats());
+
+        //When the heartbeat waiting for a pong went out
+        let heartbeat_sent = std::sync::Mutex::new(None);
+        self.health.heartbeat_every(HEARTBEAT_INTERVAL);
+        let heartbeat = async {
+            loop {
+                *heartbeat_sent.lock().unwrap() = Some(Instant::now());
+                if to_guilded.send(Message::Text("2".to_owned())).await.is_err() { break };
+                async_std::task::sleep(HEARTBEAT_INTERVAL).await;
+                if self.health.stale() { return BridgeError::transport("Guilded: Heartbeats stopped being answered") };
+            };
+            BridgeError::transport("Guilded heartbeat died")
+        }.fuse();
+        let relay = async {
+            while let Some(event) = socket.next().await {
+                if let GuildedEvent::Pong = &*event {
+                    self.health.acked();
+                    if let Some(sent) = heartbeat_sent.lock().unwrap().take() { metrics::HEARTBEAT_LATENCY.set(&[("platform", GUILDED)], sent.elapsed().as_secs_f64()) };
+                }
+                if let Some(event) = self.translate_event(&event).await {
+                    let _ = events.send(event).await;
+                }
+            }
+            BridgeError::transport("Guilded socket events ended")
+        }.fuse();
+        futures::pin_mut!(heartbeat, relay);
+
+        select! {
+            err = heartbeat => return Err(err),
+            err = relay => return Err(err),
+            result = connection => return Err(result.err().unwrap_or_else(|| BridgeError::transport("Guilded socket closed"))),
+            _ = shutdown.stop.wait().fuse() => (),
+        }
+        //Intake is over, but the heartbeat keeps the socket open until everything queued is delivered
+        select! {
+            err = heartbeat => return Err(err),
+            result = connection => return Err(result.err().unwrap_or_else(|| BridgeError::transport("Guilded socket closed"))),
+            _ = shutdown.close.wait().fuse() => (),
+        }
+        close_ws(&to_guilded, connection).await
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        let webhook = self.get_webhook(&message.author, channel).await?;
+
+        #[derive(Serialize)]
+        struct ToWebhook {
+            content: String,
+        }
+        #[derive(Deserialize)]
+        struct SentMessage {
+            id: String,
+        }
+        let response = surf::post(webhook)
+            .header("Content-Type", "application/json")
+            .body(Body::from_json(&ToWebhook { content: message.text_with_attachments() })?).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Guilded Message for {} user {}", message.author.platform, message.author.id))?;
+        //Not every answer has the message in it, a copy without an id just can't be edited or deleted later
+        Ok(response.body_json::<SentMessage>().await.ok().map(|sent| sent.id))
+    }
+
+    async fn delete_message(&self, channel: &str, id: &str, _author: &BridgeUser) -> Result<(), BridgeError> {
+        let response = surf::delete(format!("{}/channels/{}/messages/{}", self.env.settings.guilded_api, channel, id))
+            .header("Cookie", &self.cookies().await?).await?;
+        check_status(response).await.with_context(|| format!("Guilded Delete {}", id))?;
+        Ok(())
+    }
+
+    async fn is_moderator(&self, channel: &str, user: &str) -> Result<bool, BridgeError> {
+        let roles = &self.env.settings.guilded_admin_roles;
+        if roles.is_empty() { return Ok(false) };
+        let team = match self.teams.lock().await.get(channel).cloned() { Some(team) => team, None => return Ok(false) };
+        #[derive(Deserialize)]
+        struct Members { members: Vec<Member> }
+        #[derive(Deserialize)]
+        struct Member {
+            id: String,
+            #[serde(rename = "roleIds", default)]
+            role_ids: Vec<JsValue>,
+        }
+        let response = surf::get(format!("{}/teams/{}/members", self.env.settings.guilded_api, team))
+            .header("Cookie", &self.cookies().await?).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Guilded Members of {}", team))?;
+        let members = response.body_json::<Members>().await?.members;
+        //Role ids are numbers on Guilded, but settings are strings
+        let role_ids = members.into_iter().find(|member| member.id == user).map(|member| member.role_ids).unwrap_or_default();
+        let mut role_ids = role_ids.iter().map(|role| role.as_str().map(str::to_owned).unwrap_or_else(|| role.to_string()));
+        Ok(role_ids.any(|role| roles.contains(&role)))
+    }
+}
+
+/// `ChatMessageCreated` and `ChatMessageUpdated` both look like this
+#[derive(Deserialize, Debug)]
+pub struct ChatMessage {
+    #[serde(rename = "channelId")]
+    channel_id: String,
+    message: GuildedMessage,
+    #[serde(rename = "createdBy")]
+    author: String,
+    #[serde(rename = "teamId")]
+    team_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GuildedMessage {
+    id: String,
+    content: GuildedMessageContent,
+    #[serde(rename = "webhookId")]
+    webhook_id: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct GuildedMessageContent {
+    document: JsValue
+}
+
+pub fn extract_text_from_node(node: &JsValue, out: &mut String) {
+    if let JsValue::Object(contents) = node {
+        if let Some(JsValue::String(object)) = contents.get("object") {
+            match object.as_str() {
+                "document" | "inline" => {
+                    if let Some(JsValue::Array(nodes)) = contents.get("nodes") {
+                        for node in nodes { extract_text_from_node(node, out) };
+                    }
+                },
+                "block" => {
+                    if !out.is_empty() { *out += "\n" };
+                    if let Some(JsValue::Array(nodes)) = contents.get("nodes") {
+                        for node in nodes { extract_text_from_node(node, out) };
+                    }
+                },
+                "text"  => {
+                    if let Some(JsValue::Array(leaves)) = contents.get("leaves") {
+                        for leaf in leaves { extract_text_from_node(leaf, out); }
+                    }
+                },
+                "leaf" => {
+                    if let Some(JsValue::String(text)) = contents.get("text") {
+                        *out += text;
+                    }
+                },
+                _ => debug!(node = %object, "Unexpected node type")
+            }
+        }
+    }
+}
+
+async fn upload_avatar(media: &str, cookies: &HeaderValues, png_name: String, png_bytes: &[u8]) -> Result<String, BridgeError> {
+    const BOUNDARY: &str = "----WebKitFormBoundaryPfRexPAQMB4xRmqq";
+    let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: image/png\r\n\r\n", BOUNDARY, png_name).as_bytes().to_vec();
+    body.extend_from_slice(png_bytes);
+    body.extend_from_slice(format!("\r\n--{}--", BOUNDARY).as_bytes());
+
+    let response = surf::post(format!("{}/media/upload?dynamicMediaTypeId=UserAvatar", media))
+        .header("Cookie", cookies)
+        .header("Content-Type", format!("multipart/form-data; boundary={}", BOUNDARY))
+        .body(surf::Body::from_bytes(body)).await?;
+    let mut response = check_status(response).await.context("DG: Failed to upload media")?;
+
+    #[derive(Deserialize)]
+    struct Response {
+        url: String
+    }
+    let response = response.body_json::<Response>().await?;
+    debug!(url = %response.url, "Uploaded avatar");
+    Ok(response.url)
+}
+
+async fn guilded_websocket(socket: &str, guilded_cookies: HeaderValues, socket_out: Sender<GuildedEvent>) -> Result<(Sender<Message>, JoinHandle<Result<(), BridgeError>>), BridgeError> {
+    let request = guilded_cookies.iter().fold(
+        http::Request::builder()
+            .uri(socket),
+        |request, value| request.header("Cookie", value.as_str().to_owned())
+    ).body(()).unwrap();
+    let (ws, _response) = async_tungstenite::async_std::connect_async(request).await?;
+    Ok(my_ws_task(ws, socket_out, |msg| {
+        recording::frame(GUILDED, &msg);
+        GuildedEvent::parse(msg)
+    }))
+}
@@ -0,0 +1,418 @@
+use crate::*;
+use async_std::channel::{unbounded, Receiver};
+use async_std::net::TcpStream;
+use futures::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::io::BufReader;
+use futures::FutureExt;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::warn;
+
+pub const IRC: &str = "irc";
+/// Servers cut lines at 512 bytes, counting the CRLF
+const MAX_LINE: usize = 510;
+/// Room for the `:nick!user@host ` other clients see in front of our lines. We don't know our own host, so assume the worst.
+const MAX_HOSTMASK: usize = 1 + 10 + 1 + 63 + 1;
+/// The least room a relayed line gets, when a long nick and channel name leave less than this of `MAX_LINE`
+const MIN_ROOM: usize = 64;
+/// Servers kick clients that send too fast
+const LINE_INTERVAL: Duration = Duration::from_millis(300);
+/// How often channels added to the config by a reload are joined
+const JOIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// One client connection to the configured IRC server. Relayed messages are posted by the bridge's own nick as
+/// `<name> message`, since IRC has nothing like webhooks.
+pub struct Irc {
+    env: Arc<Environment>,
+    settings: IrcSettings,
+    /// Only while `run` is connected
+    session: RwLock<Option<IrcSession>>,
+    joined: Mutex<BTreeSet<String>>,
+    health: SessionHealth,
+    /// IRC messages have no ids, these only have to tell apart the ones seen since the bridge started
+    next_id: AtomicU64,
+}
+
+struct IrcSession {
+    nick: String,
+    to_irc: Sender<String>,
+}
+
+/// A parsed line, without its tags
+#[derive(Debug, PartialEq, Eq)]
+pub struct IrcLine {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcLine {
+    pub fn parse(line: &str) -> Option<IrcLine> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        if rest.starts_with('@') { rest = rest.split_once(' ')?.1 };
+        let prefix = if let Some(prefixed) = rest.strip_prefix(':') {
+            let (prefix, after) = prefixed.split_once(' ')?;
+            rest = after;
+            Some(prefix.to_owned())
+        } else { None };
+        let (middle, trailing) = match rest.split_once(" :") {
+            Some((middle, trailing)) => (middle, Some(trailing)),
+            None => (rest, None),
+        };
+        let mut params = middle.split(' ').filter(|param| !param.is_empty()).map(|param| param.to_owned()).collect::<Vec<_>>();
+        if params.is_empty() { return None };
+        let command = params.remove(0).to_ascii_uppercase();
+        params.extend(trailing.map(|trailing| trailing.to_owned()));
+        Some(IrcLine { prefix, command, params })
+    }
+
+    /// The nick part of a `nick!user@host` prefix
+    pub fn nick(&self) -> Option<&str> {
+        self.prefix.as_ref().map(|prefix| prefix.split('!').next().unwrap_or(prefix))
+    }
+}
+
+type BoxedRead = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWrite = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Every line that comes in, without its CRLF. Plenty of clients still send Latin-1, so lines that aren't UTF-8 are
+/// decoded lossily instead of ending the session.
+fn read_lines<R: AsyncRead + Unpin>(reader: BufReader<R>) -> impl futures::Stream<Item = std::io::Result<String>> + Unpin {
+    Box::pin(futures::stream::unfold(reader, |mut reader| async move {
+        let mut line = vec![];
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) => None,
+            Ok(_) => {
+                while line.last().is_some_and(|byte| *byte == b'\n' || *byte == b'\r') { line.pop(); }
+                Some((Ok(String::from_utf8_lossy(&line).into_owned()), reader))
+            },
+            Err(err) => Some((Err(err), reader)),
+        }
+    }))
+}
+
+impl Irc {
+    /// Connecting, logging in and joining happen in `run`
+    pub fn new(env: Arc<Environment>, settings: IrcSettings) -> Arc<Irc> {
+        Arc::new(Irc { env, settings, session: RwLock::new(None), joined: Mutex::new(BTreeSet::new()), health: SessionHealth::default(), next_id: AtomicU64::new(0) })
+    }
+
+    /// Connects, logs in with SASL if there's a password, joins every irc channel in the config and relays until
+    /// `close` fires or the connection dies
+    async fn session(&self, events: &Sender<BridgeEvent>, shutdown: &Shutdown) -> Result<(), BridgeError> {
+        let tcp = TcpStream::connect(&self.settings.server).await.with_context(|| format!("IRC: Failed to connect to {}", self.settings.server))?;
+        let (reader, writer): (BoxedRead, BoxedWrite) = if self.settings.tls {
+            let host = self.settings.server.rsplit_once(':').map(|(host, _)| host).unwrap_or(&self.settings.server);
+            let tls = async_tls::TlsConnector::default().connect(host, tcp).await.with_context(|| format!("IRC: TLS with {} failed", self.settings.server))?;
+            let (reader, writer) = tls.split();
+            (Box::new(reader), Box::new(writer))
+        } else {
+            let (reader, writer) = tcp.split();
+            (Box::new(reader), Box::new(writer))
+        };
+
+        let (to_irc, lines_to_send) = unbounded::<String>();
+        let mut writer = async_std::task::spawn(write_lines(writer, lines_to_send)).fuse();
+        let mut lines = read_lines(BufReader::new(reader));
+        let nick = select! {
+            nick = register(&self.settings, &to_irc, &mut lines).fuse() => nick?,
+            result = writer => return Err(result.err().unwrap_or_else(|| BridgeError::transport("IRC: Writer stopped while registering"))),
+        };
+
+        let _connected = self.health.connected();
+        self.joined.lock().await.clear();
+        *self.session.write().await = Some(IrcSession { nick: nick.clone(), to_irc: to_irc.clone() });
+        self.join_configured(&to_irc).await?;
+
+        let stop = shutdown.stop.wait().fuse();
+        let close = shutdown.close.wait().fuse();
+        futures::pin_mut!(stop, close);
+        let mut join_timer = Box::pin(async_std::task::sleep(JOIN_INTERVAL).fuse());
+        loop {
+            let line = select! {
+                line = lines.next().fuse() => line,
+                result = writer => return Err(result.err().unwrap_or_else(|| BridgeError::transport("IRC: Writer stopped"))),
+                _ = join_timer => {
+                    self.join_configured(&to_irc).await?;
+                    join_timer = Box::pin(async_std::task::sleep(JOIN_INTERVAL).fuse());
+                    continue;
+                },
+                //Lines keep being read after stopping, for PINGs, they're just not relayed
+                _ = stop => continue,
+                _ = close => break,
+            };
+            let line = match line {
+                Some(line) => line.context("IRC: Died while reading")?,
+                None => return Err(BridgeError::transport("IRC: Connection died")),
+            };
+            let line = match IrcLine::parse(&line) { Some(line) => line, None => continue };
+            match line.command.as_str() {
+                "PING" => {
+                    self.health.acked();
+                    to_irc.send(format!("PONG :{}", line.params.last().map(|token| &**token).unwrap_or(""))).await?;
+                },
+                "ERROR" => return Err(BridgeError::transport(format!("IRC: Server closed the connection: {}", line.params.join(" ")))),
+                "KICK" if line.params.get(1).is_some_and(|kicked| kicked.eq_ignore_ascii_case(&nick)) => {
+                    warn!(channel = %line.params[0], reason = line.params.get(2).map(|reason| &**reason).unwrap_or(""), "Kicked");
+                    self.joined.lock().await.remove(&line.params[0].to_ascii_lowercase());
+                    //Straight back in, if it's still bridged. A ban makes the JOIN fail instead of looping.
+                    if self.configured_channels().await.iter().any(|channel| channel.eq_ignore_ascii_case(&line.params[0])) {
+                        self.join(&to_irc, &line.params[0]).await?;
+                    }
+                },
+                _ if shutdown.stop.has_fired() => (),
+                _ => if let Some(event) = self.translate_line(&nick, line).await { let _ = events.send(event).await; },
+            }
+        }
+
+        to_irc.send("QUIT :Bridge shutting down".to_owned()).await?;
+        //The writer finishes once every sender is gone, after sending what's left
+        self.session.write().await.take();
+        drop(to_irc);
+        async_std::future::timeout(Duration::from_secs(5), writer).await
+            .unwrap_or_else(|_| Err(BridgeError::transport("IRC: Timed out sending QUIT")))
+    }
+
+    /// Every irc channel in the config, in either direction
+    async fn configured_channels(&self) -> BTreeSet<String> {
+        self.env.config.read().await.routes.iter()
+            .flat_map(|(from, to)| std::iter::once(from).chain(to))
+            .filter_map(|channel| match channel { ChannelRef::Irc(name) => Some(name.clone()), _ => None })
+            .collect()
+    }
+
+    /// Joins whichever configured channels aren't joined yet, like ones a reload just added
+    async fn join_configured(&self, to_irc: &Sender<String>) -> Result<(), BridgeError> {
+        for channel in self.configured_channels().await { self.join(to_irc, &channel).await? };
+        Ok(())
+    }
+
+    async fn join(&self, to_irc: &Sender<String>, channel: &str) -> Result<(), BridgeError> {
+        let mut joined = self.joined.lock().await;
+        if joined.contains(&channel.to_ascii_lowercase()) { return Ok(()) };
+        to_irc.send(format!("JOIN {}", channel)).await?;
+        joined.insert(channel.to_ascii_lowercase());
+        Ok(())
+    }
+
+    /// Channel names aren't case sensitive, so a message to `#Abc` belongs to whichever spelling the config uses
+    async fn configured_channel(&self, target: &str) -> ChannelRef {
+        let config = self.env.config.read().await;
+        config.routes.keys()
+            .find(|channel| matches!(channel, ChannelRef::Irc(name) if name.eq_ignore_ascii_case(target)))
+            .cloned()
+            .unwrap_or_else(|| ChannelRef::Irc(target.to_owned()))
+    }
+
+    async fn translate_line(&self, our_nick: &str, line: IrcLine) -> Option<BridgeEvent> {
+        if line.command != "PRIVMSG" || line.params.len() < 2 { return None };
+        let nick = line.nick()?;
+        //Our own lines don't come back on IRC, but other bridges' and bouncers' might look like ours
+        if nick.eq_ignore_ascii_case(our_nick) { return None };
+        let target = &line.params[0];
+        if !target.starts_with(['#', '&']) { return None };
+
+        let text = &line.params[1];
+        let content = match text.strip_prefix("\u{1}ACTION ") {
+            Some(action) => format!("*{}*", strip_formatting(action.trim_end_matches('\u{1}'))),
+            //Any other CTCP is a client talking to clients
+            None if text.starts_with('\u{1}') => return None,
+            None => strip_formatting(text),
+        };
+        Some(BridgeEvent::MessageCreated(BridgeMessage {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed).to_string(),
+            channel: self.configured_channel(target).await,
+            author: BridgeUser { platform: IRC.to_owned(), id: nick.to_owned(), name: nick.to_owned(), avatar_url: None },
+            content,
+            attachments: vec![],
+        }))
+    }
+}
+
+#[async_trait::async_trait]
+impl Platform for Irc {
+    fn name(&self) -> &'static str { IRC }
+
+    fn status(&self) -> PlatformStatus { self.health.status() }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        let result = self.session(&events, &shutdown).await;
+        self.session.write().await.take();
+        result
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        let (nick, to_irc) = match &*self.session.read().await {
+            Some(session) => (session.nick.clone(), session.to_irc.clone()),
+            None => return Err(BridgeError::transport("IRC: Not connected")),
+        };
+        self.join(&to_irc, channel).await?;
+        let prefix = format!("<{}> ", no_highlight(&message.author.display_name()));
+        let max = line_room(&nick, channel);
+        for line in message.text_with_attachments().lines().filter(|line| !line.trim().is_empty()) {
+            for chunk in split_line(&prefix, line, max) {
+                to_irc.send(format!("PRIVMSG {} :{}", channel, chunk)).await?;
+            }
+        }
+        //Nothing sent to IRC can be edited or deleted, so there's no id worth remembering
+        Ok(None)
+    }
+}
+
+/// Sends lines until every sender is gone, then closes the connection
+async fn write_lines(mut writer: BoxedWrite, lines: Receiver<String>) -> Result<(), BridgeError> {
+    while let Ok(line) = lines.recv().await {
+        writer.write_all(format!("{}\r\n", line).as_bytes()).await.and(writer.flush().await).context("IRC: Died while writing")?;
+        async_std::task::sleep(LINE_INTERVAL).await;
+    }
+    writer.close().await.context("IRC: Failed to close the connection")
+}
+
+/// NICK, USER and SASL PLAIN if there's a password, until the server welcomes us. Returns the nick we ended up with.
+async fn register<L>(settings: &IrcSettings, to_irc: &Sender<String>, lines: &mut L) -> Result<String, BridgeError>
+where L: futures::Stream<Item = std::io::Result<String>> + Unpin {
+    let mut nick = settings.nick.clone();
+    if settings.sasl.is_some() { to_irc.send("CAP REQ :sasl".to_owned()).await? };
+    to_irc.send(format!("NICK {}", nick)).await?;
+    to_irc.send(format!("USER {} 0 * :bridge7573", nick)).await?;
+
+    while let Some(line) = lines.next().await {
+        let line = match IrcLine::parse(&line.context("IRC: Died while registering")?) { Some(line) => line, None => continue };
+        let param = |i: usize| line.params.get(i).map(|param| &**param).unwrap_or("");
+        match line.command.as_str() {
+            "PING" => to_irc.send(format!("PONG :{}", line.params.last().map(|token| &**token).unwrap_or(""))).await?,
+            "CAP" if param(1) == "ACK" => to_irc.send("AUTHENTICATE PLAIN".to_owned()).await?,
+            "CAP" if param(1) == "NAK" => return Err(BridgeError::config(format!("IRC: {} doesn't do SASL", settings.server))),
+            "AUTHENTICATE" if param(0) == "+" => {
+                let (user, password) = settings.sasl.as_ref().ok_or_else(|| BridgeError::protocol("IRC: Server asked for SASL we didn't start"))?;
+                let plain = base64::encode(format!("{}\0{}\0{}", user, user, password.expose()));
+                //Longer answers go in 400 byte pieces, with a lone + if the last one was exactly 400
+                for piece in plain.as_bytes().chunks(400) { to_irc.send(format!("AUTHENTICATE {}", std::str::from_utf8(piece).unwrap())).await? };
+                if plain.len() % 400 == 0 { to_irc.send("AUTHENTICATE +".to_owned()).await? };
+            },
+            "903" => to_irc.send("CAP END".to_owned()).await?,
+            "902" | "904" | "905" | "906" => return Err(BridgeError::config(format!("IRC: SASL login failed: {}", line.params.join(" ")))),
+            "433" => {
+                nick += "_";
+                to_irc.send(format!("NICK {}", nick)).await?;
+            },
+            "001" => return Ok(line.params.first().cloned().unwrap_or(nick)),
+            //Mostly throttling, like "Closing Link: ... (Throttled: Reconnecting too fast)", which a later try gets past
+            "ERROR" => return Err(BridgeError::transport(format!("IRC: Server refused us: {}", line.params.join(" ")))),
+            _ => (),
+        }
+    }
+    Err(BridgeError::transport("IRC: Connection closed while registering"))
+}
+
+/// How much of a PRIVMSG to `channel` is left for the text, once the server puts our hostmask in front of it
+pub fn line_room(nick: &str, channel: &str) -> usize {
+    MAX_LINE.saturating_sub(MAX_HOSTMASK + nick.len() + format!(" PRIVMSG {} :", channel).len()).max(MIN_ROOM)
+}
+
+/// Splits `text` so that `prefix` plus each piece fits in `max` bytes, at spaces where possible
+pub fn split_line(prefix: &str, text: &str, max: usize) -> Vec<String> {
+    let room = max.saturating_sub(prefix.len()).max(1);
+    let mut pieces = vec![];
+    let mut rest = text.trim_end();
+    while !rest.is_empty() {
+        if rest.len() <= room { pieces.push(format!("{}{}", prefix, rest)); break; }
+        let mut end = room;
+        while !rest.is_char_boundary(end) { end -= 1 };
+        //A single character wider than the room still has to go somewhere
+        if end == 0 { end = rest.char_indices().nth(1).map(|(i, _)| i).unwrap_or(rest.len()) };
+        let cut = match rest[..end].rfind(' ') { Some(space) if space > 0 => space, _ => end };
+        pieces.push(format!("{}{}", prefix, rest[..cut].trim_end()));
+        rest = rest[cut..].trim_start();
+    }
+    pieces
+}
+
+/// Bold, colours and the rest of mIRC's formatting codes
+pub fn strip_formatting(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{2}' | '\u{f}' | '\u{11}' | '\u{16}' | '\u{1d}' | '\u{1e}' | '\u{1f}' => (),
+            '\u{3}' => {
+                //Up to two digits of foreground, then optionally a comma and up to two of background
+                for _ in 0..2 { if chars.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) { chars.next(); } }
+                let mut lookahead = chars.clone();
+                if lookahead.next() == Some(',') && lookahead.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) {
+                    chars.next();
+                    for _ in 0..2 { if chars.peek().map(|c| c.is_ascii_digit()).unwrap_or(false) { chars.next(); } }
+                }
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Puts a zero width space after the first letter of the name, so IRC clients don't treat every relayed message as
+/// mentioning whoever has that nick
+fn no_highlight(name: &str) -> String {
+    //Skip the platform icon, it's the name after it that could match a nick
+    let icon_end = name.find(' ').map(|space| space + 1).unwrap_or(0);
+    let mut chars = name[icon_end..].chars();
+    match chars.next() {
+        Some(first) => format!("{}{}\u{200b}{}", &name[..icon_end], first, chars.as_str()),
+        None => name.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lines() {
+        assert_eq!(IrcLine::parse(":alice!a@host PRIVMSG #chan :hello there\r\n"), Some(IrcLine {
+            prefix: Some("alice!a@host".to_owned()), command: "PRIVMSG".to_owned(), params: vec!["#chan".to_owned(), "hello there".to_owned()],
+        }));
+        let tagged = IrcLine::parse("@time=2021-01-01T00:00:00Z :server 001 bridge7573 :Welcome").unwrap();
+        assert_eq!((tagged.command.as_str(), tagged.params[0].as_str()), ("001", "bridge7573"));
+        assert_eq!(IrcLine::parse("PING :token").unwrap().params, ["token"]);
+        assert_eq!(IrcLine::parse(":alice!a@host PRIVMSG #chan :hi").unwrap().nick(), Some("alice"));
+    }
+
+    #[async_std::test]
+    async fn reads_lines_that_are_not_utf8() {
+        let lines = read_lines(BufReader::new(&b"PING :a\r\n:bob!b@host PRIVMSG #chan :caf\xe9\r\nPING :b"[..]));
+        let lines = lines.map(Result::unwrap).collect::<Vec<_>>().await;
+        assert_eq!(lines, ["PING :a", ":bob!b@host PRIVMSG #chan :caf\u{fffd}", "PING :b"]);
+    }
+
+    #[test]
+    fn splits_long_lines_at_spaces() {
+        let pieces = split_line("<bob> ", "aaaa bbbb cccc dddd", 16);
+        assert_eq!(pieces, ["<bob> aaaa bbbb", "<bob> cccc dddd"]);
+        assert!(pieces.iter().all(|piece| piece.len() <= 16));
+    }
+
+    #[test]
+    fn splits_long_words_on_char_boundaries() {
+        let pieces = split_line("<b> ", "ééééé", 9);
+        assert_eq!(pieces, ["<b> éé", "<b> éé", "<b> é"]);
+        assert_eq!(split_line("<b> ", "short", 100), ["<b> short"]);
+    }
+
+    #[test]
+    fn leaves_room_for_long_nicks_and_channels() {
+        assert_eq!(line_room("bridge7573", "#general"), 510 - 76 - 10 - " PRIVMSG #general :".len());
+        assert_eq!(line_room(&"n".repeat(300), &format!("#{}", "c".repeat(200))), MIN_ROOM);
+    }
+
+    #[test]
+    fn strips_formatting() {
+        assert_eq!(strip_formatting("\u{2}bold\u{2} \u{3}04,12red on blue\u{3} \u{3}4,x"), "bold red on blue ,x");
+    }
+
+    #[test]
+    fn breaks_highlights_after_the_icon() {
+        assert_eq!(no_highlight("💬 bob"), "💬 b\u{200b}ob");
+        assert_eq!(no_highlight("bob"), "b\u{200b}ob");
+    }
+}
@@ -0,0 +1,101 @@
+//! The bridge as a library: a `Router` relaying `BridgeEvent`s between `Platform`s, with the Discord and Guilded
+//! clients, the Matrix application service and the IRC client as the platforms it ships with.
+//! The bridge7573 binary is one way to put these together.
+#[macro_use] extern crate futures;
+pub use async_std::sync::{Mutex, RwLock};
+pub use async_std::channel::Sender;
+pub use futures::StreamExt;
+pub use std::sync::Arc;
+use async_tungstenite::tungstenite::Message;
+use async_tungstenite::WebSocketStream;
+use async_std::channel::unbounded;
+use async_std::task::JoinHandle;
+use futures::{SinkExt, FutureExt};
+
+pub mod multi_recv;
+pub mod error;
+pub mod config;
+pub mod reload;
+pub mod check_config;
+pub mod admin;
+pub mod commands;
+pub mod settings;
+pub mod logging;
+pub mod metrics;
+pub mod recording;
+pub mod persist;
+pub mod storage;
+pub mod event;
+pub mod platform;
+pub mod router;
+pub mod supervisor;
+pub mod server;
+pub mod discord;
+pub mod guilded;
+pub mod matrix;
+pub mod irc;
+pub use storage::*;
+pub use settings::*;
+pub use multi_recv::*;
+pub use error::*;
+pub use config::*;
+pub use event::*;
+pub use platform::*;
+pub use router::*;
+pub use supervisor::*;
+pub use discord::{Discord, DISCORD};
+pub use guilded::{Guilded, GUILDED};
+pub use matrix::{Matrix, MATRIX};
+pub use irc::{Irc, IRC};
+
+/// What every platform and the router share
+pub struct Environment {
+    pub settings: Settings,
+    pub storage: Box<dyn Storage>,
+    pub config: RwLock<Arc<Config>>,
+    /// Why the config last failed to load, until a reload works
+    pub config_error: std::sync::Mutex<Option<String>>,
+}
+
+/// Runs a websocket on its own task, with a channel to send on. Each message received is parsed once with `parse` and
+/// sent to `msg_out`, messages it returns `None` for are dropped.
+/// The task ends with the connection: `Ok` when it was closed from our end, by sending a `Message::Close` or dropping
+/// every sender, an error when it wasn't.
+pub fn my_ws_task<S, T, P>(ws: WebSocketStream<S>, msg_out: Sender<T>, mut parse: P) -> (Sender<Message>, JoinHandle<Result<(), BridgeError>>)
+where S: futures::AsyncRead + futures::AsyncWrite + Unpin + Send + 'static, T: Send + 'static, P: FnMut(Message) -> Option<T> + Send + 'static {
+    let (send_msgs, msgs_to_send) = unbounded::<Message>();
+    let task = async_std::task::spawn(async move {
+        let mut msgs_to_send = msgs_to_send.fuse();
+        let mut ws = ws;
+        let mut closing = false;
+        loop {
+            select_biased! {
+                incoming_msg = ws.next().fuse() => match incoming_msg {
+                    Some(Ok(msg)) => {
+                        let msg = match parse(msg) { Some(msg) => msg, None => continue };
+                        //Nobody listening is fine, the platform might be between sessions
+                        let _ = msg_out.send(msg).await;
+                    },
+                    Some(Err(_)) | None if closing => return Ok(()),
+                    Some(Err(err)) => return Err(BridgeError::from(err).context("websocket")),
+                    None => return Err(BridgeError::transport("websocket closed by the other end")),
+                },
+                send_msg = msgs_to_send.next() => {
+                    //Whatever comes after closing can't be sent, the other end only has to answer the close now
+                    if closing { continue };
+                    let msg = send_msg.unwrap_or(Message::Close(None));
+                    closing = matches!(msg, Message::Close(_));
+                    ws.send(msg).await.context("websocket")?;
+                },
+            }
+        }
+    });
+    (send_msgs, task)
+}
+
+/// Closes a websocket from `my_ws_task` from our end, waiting a little for the other end to answer
+pub async fn close_ws(to_ws: &Sender<Message>, connection: impl std::future::Future<Output = Result<(), BridgeError>>) -> Result<(), BridgeError> {
+    let _ = to_ws.send(Message::Close(None)).await;
+    async_std::future::timeout(std::time::Duration::from_secs(5), connection).await
+        .unwrap_or_else(|_| Err(BridgeError::transport("websocket didn't answer the close")))
+}
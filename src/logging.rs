@@ -0,0 +1,132 @@
+use crate::settings::{LogFormat, Settings};
+use std::borrow::Cow;
+use std::io::Write;
+use tracing_subscriber::EnvFilter;
+
+lazy_static::lazy_static! {
+    /// Everything `redact` was given, longest first so a secret containing another is replaced whole
+    static ref SECRETS: std::sync::RwLock<Vec<String>> = std::sync::RwLock::new(Vec::new());
+}
+
+/// Never let `secret` show up in the logs. Every `Secret` does this when it's made.
+pub fn redact(secret: &str) {
+    if secret.is_empty() { return };
+    let mut secrets = SECRETS.write().unwrap();
+    if secrets.iter().any(|known| known == secret) { return };
+    secrets.push(secret.to_owned());
+    secrets.sort_by_key(|known| std::cmp::Reverse(known.len()));
+}
+
+/// Urls that are `<path><id>/<token>`, where the token is all it takes to use them: posting as a webhook, or
+/// answering a Discord interaction
+const TOKEN_PATHS: &[&str] = &["/webhooks/", "/interactions/"];
+
+/// `text` with every secret, and the token part of any webhook or interaction url or `"token"` json field, replaced
+/// with `<redacted>`
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    let mut text = Cow::Borrowed(text);
+    for secret in SECRETS.read().unwrap().iter() {
+        if text.contains(&**secret) { text = Cow::Owned(text.replace(&**secret, "<redacted>")) };
+    }
+    for path in TOKEN_PATHS {
+        if text.contains(path) { text = Cow::Owned(scrub_url_tokens(&text, path)) };
+    }
+    //Interactions come with their token in the payload as well
+    if text.contains("\"token\"") { text = Cow::Owned(scrub_token_fields(&text)) };
+    text
+}
+
+fn scrub_url_tokens(text: &str, path: &str) -> String {
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(path) {
+        let after = &rest[start + path.len()..];
+        let id_end = after.find(|c: char| !c.is_ascii_alphanumeric() && c != '-').unwrap_or(after.len());
+        let token = after[id_end..].strip_prefix('/').map(|token| token.find(|c: char| !c.is_ascii_alphanumeric() && c != '-' && c != '_').unwrap_or(token.len()));
+        match token {
+            Some(token_len) if token_len > 0 => {
+                scrubbed.push_str(&rest[..start + path.len() + id_end + 1]);
+                scrubbed.push_str("<redacted>");
+                rest = &after[id_end + 1 + token_len..];
+            },
+            _ => {
+                scrubbed.push_str(&rest[..start + path.len()]);
+                rest = after;
+            },
+        }
+    }
+    scrubbed.push_str(rest);
+    scrubbed
+}
+
+fn scrub_token_fields(text: &str) -> String {
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("\"token\"") {
+        let after = &rest[start + "\"token\"".len()..];
+        let value = after.trim_start().strip_prefix(':').map(str::trim_start).and_then(|value| value.strip_prefix('"'));
+        match value.and_then(|value| value.find('"').map(|len| (value, len))) {
+            Some((value, len)) if len > 0 => {
+                scrubbed.push_str(&rest[..rest.len() - value.len()]);
+                scrubbed.push_str("<redacted>");
+                rest = &value[len..];
+            },
+            _ => {
+                scrubbed.push_str(&rest[..start + "\"token\"".len()]);
+                rest = after;
+            },
+        }
+    }
+    scrubbed.push_str(rest);
+    scrubbed
+}
+
+/// Stderr, through `scrub`. The formatter hands over one whole event per write.
+struct Redacting;
+impl Write for Redacting {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        std::io::stderr().write_all(scrub(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> { std::io::stderr().flush() }
+}
+
+/// Sends every log event at or above `log_filter` to stderr, as text or one json object per line
+pub fn init(settings: &Settings) -> Result<(), String> {
+    let mut directives = settings.log_filter.clone();
+    if settings.print_all_msg { directives += ",bridge7573::discord=trace,bridge7573::guilded=trace" };
+    let filter = EnvFilter::try_new(&directives).map_err(|err| format!("Invalid log_filter {}: {}", settings.log_filter, err))?;
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_writer(|| Redacting);
+    match settings.log_format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).with_current_span(false).try_init(),
+    }.map_err(|err| format!("Failed to start logging: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_secrets() {
+        redact("correct horse battery staple");
+        redact("horse");
+        assert_eq!(scrub("password was correct horse battery staple!"), "password was <redacted>!");
+        //However short, a secret is still a secret
+        assert_eq!(scrub("a horse, of course"), "a <redacted>, of course");
+    }
+
+    #[test]
+    fn scrubs_webhook_tokens() {
+        assert_eq!(scrub("POST https://discord.com/api/webhooks/123/abc_DEF-456 failed"), "POST https://discord.com/api/webhooks/123/<redacted> failed");
+        assert_eq!(scrub("https://media.guilded.gg/webhooks/1a-2b/tok?wait=true"), "https://media.guilded.gg/webhooks/1a-2b/<redacted>?wait=true");
+        assert_eq!(scrub("GET /webhooks/ without a token"), "GET /webhooks/ without a token");
+    }
+
+    #[test]
+    fn scrubs_interaction_tokens() {
+        assert_eq!(scrub("POST https://discord.com/api/v10/interactions/99/aW50ZXJhY3Rpb24_x-y/callback failed"), "POST https://discord.com/api/v10/interactions/99/<redacted>/callback failed");
+        assert_eq!(scrub(r#"{"t":"INTERACTION_CREATE","d":{"id":"99","token": "aW50ZXJhY3Rpb24","type":2}}"#), r#"{"t":"INTERACTION_CREATE","d":{"id":"99","token": "<redacted>","type":2}}"#);
+        assert_eq!(scrub(r#"{"token":"","a":"token"}"#), r#"{"token":"","a":"token"}"#);
+    }
+}
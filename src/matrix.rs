@@ -0,0 +1,334 @@
+use crate::*;
+use futures::FutureExt;
+use serde::Deserialize;
+use serde_json::{json, Value as JsValue};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tide::listener::Listener;
+use tracing::warn;
+
+pub const MATRIX: &str = "matrix";
+/// The application service's own user, `sender_localpart` in the registration
+const BOT_LOCALPART: &str = "bridge7573";
+/// Every puppet's localpart starts with this, the registration claims the whole namespace
+const PUPPET_PREFIX: &str = "_bridge7573_";
+
+/// A Matrix application service. The homeserver pushes room events to it, and everyone from the other platforms
+/// gets a puppet user that joins the rooms they're relayed into, the way webhooks stand in for them elsewhere.
+pub struct Matrix {
+    env: Arc<Environment>,
+    settings: MatrixSettings,
+    /// Everyone seen talking, profiles only change when puppets get set up anyway
+    users: Mutex<BTreeMap<String, BridgeUser>>,
+    /// The last few transaction ids. The homeserver sends a transaction again if it didn't hear back.
+    seen_transactions: Mutex<VecDeque<String>>,
+    started_at: u128,
+    next_txn: AtomicU64,
+    health: SessionHealth,
+}
+
+/// The registration file to hand to the homeserver, as YAML
+pub fn registration(settings: &MatrixSettings) -> String {
+    format!(
+"id: bridge7573
+url: http://{listen}
+as_token: \"{as_token}\"
+hs_token: \"{hs_token}\"
+sender_localpart: {bot}
+rate_limited: false
+namespaces:
+  users:
+    - exclusive: true
+      regex: \"@{prefix}.*:{server_name}\"
+  aliases: []
+  rooms: []
+",
+        listen = settings.listen, as_token = settings.as_token.expose(), hs_token = settings.hs_token.expose(),
+        bot = BOT_LOCALPART, prefix = PUPPET_PREFIX, server_name = regex_escape(&settings.server_name))
+}
+
+impl Matrix {
+    pub fn new(env: Arc<Environment>, settings: MatrixSettings) -> Arc<Matrix> {
+        let started_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|since| since.as_millis()).unwrap_or(0);
+        Arc::new(Matrix { env, settings, users: Mutex::new(BTreeMap::new()), seen_transactions: Mutex::new(VecDeque::new()), started_at, next_txn: AtomicU64::new(0), health: SessionHealth::default() })
+    }
+
+    fn bot(&self) -> String { format!("@{}:{}", BOT_LOCALPART, self.settings.server_name) }
+
+    /// Our own bot and puppets, relaying them would loop forever
+    fn is_ours(&self, user: &str) -> bool {
+        user == self.bot() || (user.starts_with(&format!("@{}", PUPPET_PREFIX)) && user.ends_with(&format!(":{}", self.settings.server_name)))
+    }
+
+    /// Transaction ids only have to be unique for as long as the as_token is, so across restarts too
+    fn txn_id(&self) -> String {
+        format!("bridge7573.{}.{}", self.started_at, self.next_txn.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn client_url(&self, path: &str, user: Option<&str>) -> String {
+        let url = format!("{}/_matrix/client/v3/{}", self.settings.homeserver, path);
+        match user { Some(user) => format!("{}?user_id={}", url, encode(user)), None => url }
+    }
+
+    fn media_url(&self, mxc: &str) -> Option<String> {
+        mxc.strip_prefix("mxc://").map(|media| format!("{}/_matrix/media/v3/download/{}", self.settings.homeserver, media))
+    }
+
+    /// Sends a request with the as_token and returns the JSON body, which is `null` if there isn't one
+    async fn request(&self, what: &str, request: surf::RequestBuilder) -> Result<JsValue, BridgeError> {
+        let response = request.header("Authorization", format!("Bearer {}", self.settings.as_token.expose())).await?;
+        let mut response = check_status(response).await.with_context(|| format!("Matrix {}", what))?;
+        Ok(response.body_json::<JsValue>().await.unwrap_or(JsValue::Null))
+    }
+
+    async fn get_user(&self, user: &str) -> BridgeUser {
+        if let Some(known) = self.users.lock().await.get(user) { return known.clone() };
+        let profile = self.request("profile", surf::get(self.client_url(&format!("profile/{}", encode(user)), None))).await
+            .unwrap_or_else(|err| { warn!(user, error = %err, "Failed to fetch profile"); JsValue::Null });
+        let localpart = user.trim_start_matches('@').split(':').next().unwrap_or(user);
+        let known = BridgeUser {
+            platform: MATRIX.to_owned(),
+            id: user.to_owned(),
+            name: profile["displayname"].as_str().unwrap_or(localpart).to_owned(),
+            avatar_url: profile["avatar_url"].as_str().and_then(|mxc| self.media_url(mxc)),
+        };
+        self.users.lock().await.insert(user.to_owned(), known.clone());
+        known
+    }
+
+    /// The puppet standing in for `author` in `room`, registered and joined if it's the first time
+    async fn get_puppet(&self, author: &BridgeUser, room: &str) -> Result<String, BridgeError> {
+        //Get from database
+        let table = WebhookTable::new(&author.platform, MATRIX);
+        if let Some(puppet) = self.env.storage.get_webhook(&table, room, &author.id).await? { return Ok(puppet) };
+
+        let localpart = puppet_localpart(&author.platform, &author.id);
+        let puppet = format!("@{}:{}", localpart, self.settings.server_name);
+        let registered = self.request(&format!("register {}", puppet), surf::post(self.client_url("register", None))
+            .body(json!({ "type": "m.login.application_service", "username": localpart }))).await;
+        match registered {
+            Err(BridgeError::Http { body, .. }) if body.code.as_deref() == Some("M_USER_IN_USE") => (),
+            registered => { registered?; },
+        }
+
+        //A puppet without its name or avatar can still talk
+        if let Err(err) = self.set_profile(&puppet, author).await { warn!(%puppet, user = %author.id, error = %err, "Failed to set profile") };
+
+        let join_url = self.client_url(&format!("join/{}", encode(room)), Some(&puppet));
+        let joined = self.request(&format!("join {} to {}", puppet, room), surf::post(&join_url).body(json!({}))).await;
+        match joined {
+            Err(err) if err.status() == Some(403) => {
+                //Invite only room, the bot has to be in it to let puppets in
+                self.request("invite", surf::post(self.client_url(&format!("rooms/{}/invite", encode(room)), None)).body(json!({ "user_id": puppet }))).await?;
+                self.request("join", surf::post(&join_url).body(json!({}))).await?;
+            },
+            joined => { joined?; },
+        }
+
+        self.env.storage.put_webhook(&table, room, &author.id, &puppet).await?;
+        Ok(puppet)
+    }
+
+    async fn set_profile(&self, puppet: &str, author: &BridgeUser) -> Result<(), BridgeError> {
+        self.request("displayname", surf::put(self.client_url(&format!("profile/{}/displayname", encode(puppet)), Some(puppet)))
+            .body(json!({ "displayname": author.display_name() }))).await?;
+
+        let avatar_url = match &author.avatar_url { Some(avatar_url) => avatar_url, None => return Ok(()) };
+        let avatar = surf::get(avatar_url).await?;
+        let mut avatar = check_status(avatar).await.with_context(|| format!("Failed to get avatar for {} user {}", author.platform, author.id))?;
+        let content_type = avatar.header("Content-Type").map(|header| header[0].to_string()).unwrap_or_else(|| "image/png".to_owned());
+        let bytes = avatar.body_bytes().await?;
+        let uploaded = self.request("upload", surf::post(format!("{}/_matrix/media/v3/upload?user_id={}", self.settings.homeserver, encode(puppet)))
+            .header("Content-Type", content_type)
+            .body(surf::Body::from_bytes(bytes))).await?;
+        let content_uri = uploaded["content_uri"].as_str().ok_or_else(|| BridgeError::protocol("Matrix upload: no content_uri"))?;
+        self.request("avatar_url", surf::put(self.client_url(&format!("profile/{}/avatar_url", encode(puppet)), Some(puppet)))
+            .body(json!({ "avatar_url": content_uri }))).await?;
+        Ok(())
+    }
+
+    async fn existing_puppet(&self, author: &BridgeUser, room: &str) -> Result<String, BridgeError> {
+        let table = WebhookTable::new(&author.platform, MATRIX);
+        self.env.storage.get_webhook(&table, room, &author.id).await?
+            .ok_or_else(|| BridgeError::storage(format!("no puppet for {} user {} in {}", author.platform, author.id, room)))
+    }
+
+    /// Whether the homeserver already sent this transaction, remembering it if not
+    async fn seen_transaction(&self, txn: &str) -> bool {
+        let mut seen = self.seen_transactions.lock().await;
+        if seen.iter().any(|seen| seen == txn) { return true };
+        if seen.len() >= 64 { seen.pop_front(); }
+        seen.push_back(txn.to_owned());
+        false
+    }
+
+    /// Turns a room event from a transaction into a `BridgeEvent`, if it's one the bridge cares about
+    async fn translate_event(&self, event: JsValue) -> Option<BridgeEvent> {
+        #[derive(Deserialize)]
+        struct RoomEvent {
+            #[serde(rename = "type")]
+            event_type: String,
+            room_id: String,
+            sender: String,
+            event_id: String,
+            #[serde(default)]
+            content: JsValue,
+            redacts: Option<String>,
+        }
+        let event = RoomEvent::deserialize(event).ok()?;
+        if self.is_ours(&event.sender) { return None };
+        let channel = ChannelRef::Matrix(event.room_id);
+        let relates_to = &event.content["m.relates_to"];
+
+        match event.event_type.as_str() {
+            "m.room.message" if relates_to["rel_type"] == "m.replace" => {
+                let author = self.get_user(&event.sender).await;
+                let message = self.message(relates_to["event_id"].as_str()?, channel, author, &event.content["m.new_content"])?;
+                Some(BridgeEvent::MessageEdited(message))
+            },
+            "m.room.message" => {
+                let author = self.get_user(&event.sender).await;
+                Some(BridgeEvent::MessageCreated(self.message(&event.event_id, channel, author, &event.content)?))
+            },
+            //Newer room versions moved redacts into the content
+            "m.room.redaction" => {
+                let from_content = event.content["redacts"].as_str().map(|id| id.to_owned());
+                let id = event.redacts.or(from_content)?;
+                Some(BridgeEvent::MessageDeleted { channel, id })
+            },
+            "m.reaction" => Some(BridgeEvent::ReactionAdded {
+                message_id: relates_to["event_id"].as_str()?.to_owned(),
+                emoji: relates_to["key"].as_str()?.to_owned(),
+                user: self.get_user(&event.sender).await,
+                channel,
+            }),
+            _ => None,
+        }
+    }
+
+    fn message(&self, id: &str, channel: ChannelRef, author: BridgeUser, content: &JsValue) -> Option<BridgeMessage> {
+        let body = content["body"].as_str()?.to_owned();
+        let (content, attachments) = match content["msgtype"].as_str()? {
+            "m.text" | "m.notice" => (body, vec![]),
+            "m.emote" => (format!("*{}*", body), vec![]),
+            "m.image" | "m.file" | "m.video" | "m.audio" => (String::new(), vec![Attachment { url: self.media_url(content["url"].as_str()?)?, name: body }]),
+            _ => return None,
+        };
+        Some(BridgeMessage { id: id.to_owned(), channel, author, content, attachments })
+    }
+}
+
+type AppState = (Arc<Matrix>, Sender<BridgeEvent>, Signal);
+
+/// `PUT /_matrix/app/v1/transactions/:txn`, every room event the homeserver thinks the bridge should see
+async fn transaction(mut req: tide::Request<AppState>) -> tide::Result {
+    let (matrix, events, stop) = req.state().clone();
+    let from_header = req.header("Authorization").and_then(|header| header.as_str().strip_prefix("Bearer ").map(|token| token.to_owned()));
+    let from_query = req.url().query_pairs().find(|(key, _)| key == "access_token").map(|(_, token)| token.into_owned());
+    if from_header.or(from_query).as_deref() != Some(matrix.settings.hs_token.expose()) {
+        return Ok(tide::Response::builder(403).body(json!({ "errcode": "M_FORBIDDEN" })).build());
+    }
+    //The homeserver keeps the transaction and sends it again, to whichever bridge comes up next
+    if stop.has_fired() {
+        return Ok(tide::Response::builder(503).body(json!({ "errcode": "M_UNAVAILABLE", "error": "Shutting down" })).build());
+    }
+
+    #[derive(Deserialize)]
+    struct Transaction { events: Vec<JsValue> }
+    let transaction = req.body_json::<Transaction>().await?;
+    if !matrix.seen_transaction(req.param("txn")?).await {
+        for event in transaction.events {
+            if let Some(event) = matrix.translate_event(event).await { let _ = events.send(event).await; }
+        }
+    }
+    Ok(json!({}).into())
+}
+
+#[async_trait::async_trait]
+impl Platform for Matrix {
+    fn name(&self) -> &'static str { MATRIX }
+
+    fn status(&self) -> PlatformStatus { self.health.status() }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        let listen = self.settings.listen.clone();
+        let mut app = tide::with_state((self.clone(), events, shutdown.stop.clone()));
+        app.at("/_matrix/app/v1/transactions/:txn").put(transaction);
+        //Homeservers from before the v1 prefix
+        app.at("/transactions/:txn").put(transaction);
+        let mut listener = app.bind(listen).await.context("Matrix application service couldn't listen")?;
+        let _connected = self.health.connected();
+        select! {
+            result = listener.accept().fuse() => result.context("Matrix application service died"),
+            _ = shutdown.close.wait().fuse() => Ok(()),
+        }
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        let puppet = self.get_puppet(&message.author, channel).await?;
+        let sent = self.request("send", surf::put(self.client_url(&format!("rooms/{}/send/m.room.message/{}", encode(channel), self.txn_id()), Some(&puppet)))
+            .body(json!({ "msgtype": "m.text", "body": message.text_with_attachments() }))).await?;
+        Ok(sent["event_id"].as_str().map(|id| id.to_owned()))
+    }
+
+    fn can_edit(&self) -> bool { true }
+
+    async fn edit_message(&self, channel: &str, id: &str, message: &BridgeMessage) -> Result<(), BridgeError> {
+        let puppet = self.existing_puppet(&message.author, channel).await?;
+        let text = message.text_with_attachments();
+        self.request("edit", surf::put(self.client_url(&format!("rooms/{}/send/m.room.message/{}", encode(channel), self.txn_id()), Some(&puppet)))
+            .body(json!({
+                "msgtype": "m.text",
+                "body": format!("* {}", text),
+                "m.new_content": { "msgtype": "m.text", "body": text },
+                "m.relates_to": { "rel_type": "m.replace", "event_id": id },
+            }))).await?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, channel: &str, id: &str, author: &BridgeUser) -> Result<(), BridgeError> {
+        let puppet = self.existing_puppet(author, channel).await?;
+        self.request("redact", surf::put(self.client_url(&format!("rooms/{}/redact/{}/{}", encode(channel), encode(id), self.txn_id()), Some(&puppet)))
+            .body(json!({}))).await?;
+        Ok(())
+    }
+}
+
+/// Matrix localparts are lowercase, so anything else is escaped the way the spec suggests for mapping other ids:
+/// `A` becomes `_a`, `_` becomes `__` and everything else outside `a-z0-9.-` is `=` and its hex bytes
+pub fn puppet_localpart(platform: &str, id: &str) -> String {
+    let mut localpart = format!("{}{}_", PUPPET_PREFIX, platform);
+    for c in id.chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '.' | '-' => localpart.push(c),
+            'A'..='Z' => { localpart.push('_'); localpart.push(c.to_ascii_lowercase()); },
+            '_' => localpart.push_str("__"),
+            c => for byte in c.to_string().bytes() { localpart += &format!("={:02x}", byte) },
+        }
+    }
+    localpart
+}
+
+/// Percent encodes a path segment or query value
+fn encode(segment: &str) -> String {
+    segment.bytes().map(|byte| match byte {
+        b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+        byte => format!("%{:02X}", byte),
+    }).collect()
+}
+
+fn regex_escape(text: &str) -> String {
+    text.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_string() } else { format!("\\\\{}", c) }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_puppet_localparts() {
+        assert_eq!(puppet_localpart("discord", "1234"), "_bridge7573_discord_1234");
+        assert_eq!(puppet_localpart("guilded", "4WNb_q"), "_bridge7573_guilded_4_w_nb__q");
+        assert_eq!(puppet_localpart("irc", "a=b é"), "_bridge7573_irc_a=3db=20=c3=a9");
+    }
+}
@@ -0,0 +1,144 @@
+use crate::error::BridgeError;
+use crate::multi_recv::QueueStats;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind { Counter, Gauge }
+
+/// One metric family. Every value it has is kept per set of labels.
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+}
+
+pub const MESSAGES_BRIDGED: Metric = Metric { name: "bridge_messages_bridged_total", help: "Messages relayed, by direction and binding", kind: Kind::Counter };
+pub const DELIVERY_FAILURES: Metric = Metric { name: "bridge_delivery_failures_total", help: "Messages, edits and deletes that couldn't be relayed, by platform and reason", kind: Kind::Counter };
+pub const WEBHOOKS_CREATED: Metric = Metric { name: "bridge_webhooks_created_total", help: "Webhooks made to post as someone, by platform", kind: Kind::Counter };
+pub const RECONNECTS: Metric = Metric { name: "bridge_reconnects_total", help: "Platform sessions started again after failing, by platform", kind: Kind::Counter };
+pub const RATE_LIMITED: Metric = Metric { name: "bridge_rate_limited_total", help: "Deliveries and sessions refused with 429 Too Many Requests, by platform", kind: Kind::Counter };
+pub const HEARTBEAT_LATENCY: Metric = Metric { name: "bridge_heartbeat_latency_seconds", help: "How long the last heartbeat took to be acknowledged, by platform", kind: Kind::Gauge };
+pub const QUEUE_DEPTH: Metric = Metric { name: "bridge_queue_depth", help: "Events waiting for a gateway subscriber to read them, by queue", kind: Kind::Gauge };
+pub const QUEUE_DROPPED: Metric = Metric { name: "bridge_queue_dropped_total", help: "Events a gateway subscriber never got because it fell behind, by queue", kind: Kind::Counter };
+
+/// In the order `/metrics` shows them
+const ALL: [&Metric; 8] = [&MESSAGES_BRIDGED, &DELIVERY_FAILURES, &WEBHOOKS_CREATED, &RECONNECTS, &RATE_LIMITED, &HEARTBEAT_LATENCY, &QUEUE_DEPTH, &QUEUE_DROPPED];
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Default)]
+struct Registry {
+    values: BTreeMap<&'static str, BTreeMap<Labels, f64>>,
+    /// Read when rendering, since a queue's depth changes without anyone telling us
+    queues: BTreeMap<String, QueueStats>,
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRY: Mutex<Registry> = Mutex::new(Registry::default());
+}
+
+fn labels(labels: &[(&'static str, &str)]) -> Labels {
+    labels.iter().map(|(name, value)| (*name, (*value).to_owned())).collect()
+}
+
+impl Metric {
+    pub fn inc(&self, labels: &[(&'static str, &str)]) {
+        self.add(labels, 1.0);
+    }
+
+    pub fn add(&self, with_labels: &[(&'static str, &str)], amount: f64) {
+        debug_assert_eq!(self.kind, Kind::Counter, "{} is a gauge", self.name);
+        *REGISTRY.lock().unwrap().values.entry(self.name).or_default().entry(labels(with_labels)).or_default() += amount;
+    }
+
+    pub fn set(&self, with_labels: &[(&'static str, &str)], value: f64) {
+        debug_assert_eq!(self.kind, Kind::Gauge, "{} is a counter", self.name);
+        REGISTRY.lock().unwrap().values.entry(self.name).or_default().insert(labels(with_labels), value);
+    }
+}
+
+/// Reports a subscriber's queue as `queue` until it's gone, replacing whichever subscriber had the name before
+pub fn watch_queue(queue: &str, stats: QueueStats) {
+    REGISTRY.lock().unwrap().queues.insert(queue.to_owned(), stats);
+}
+
+/// A short, fixed set of reasons, so failures can be told apart without every error message becoming its own label
+pub fn failure_reason(err: &BridgeError) -> String {
+    match err {
+        BridgeError::Http { status: 429, .. } => "rate_limited".to_owned(),
+        BridgeError::Http { status, .. } => format!("http_{}", status),
+        BridgeError::Transport { .. } => "transport".to_owned(),
+        BridgeError::Protocol { .. } => "protocol".to_owned(),
+        BridgeError::Config { .. } => "config".to_owned(),
+        BridgeError::Storage { .. } => "storage".to_owned(),
+    }
+}
+
+/// Counts a failed delivery to `platform`, and the rate limit if that's what it was
+pub fn delivery_failed(platform: &str, err: &BridgeError) {
+    DELIVERY_FAILURES.inc(&[("platform", platform), ("reason", &failure_reason(err))]);
+    if err.status() == Some(429) { RATE_LIMITED.inc(&[("platform", platform)]) };
+}
+
+/// Everything, in the Prometheus text format
+pub fn render() -> String {
+    let mut registry = REGISTRY.lock().unwrap();
+    let mut depths = BTreeMap::new();
+    let mut dropped = BTreeMap::new();
+    registry.queues.retain(|queue, stats| match stats.depth() {
+        Some(depth) => {
+            depths.insert(vec![("queue", queue.clone())], depth as f64);
+            dropped.insert(vec![("queue", queue.clone())], stats.dropped() as f64);
+            true
+        },
+        None => false,
+    });
+    registry.values.insert(QUEUE_DEPTH.name, depths);
+    registry.values.insert(QUEUE_DROPPED.name, dropped);
+
+    let mut out = String::new();
+    for metric in ALL {
+        let kind = match metric.kind { Kind::Counter => "counter", Kind::Gauge => "gauge" };
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", metric.name, metric.help, metric.name, kind);
+        for (labels, value) in registry.values.get(metric.name).into_iter().flatten() {
+            let labels = labels.iter().map(|(name, value)| format!("{}=\"{}\"", name, escape(value))).collect::<Vec<_>>();
+            if labels.is_empty() { let _ = writeln!(out, "{} {}", metric.name, value); }
+            else { let _ = writeln!(out, "{}{{{}}} {}", metric.name, labels.join(","), value); }
+        }
+    }
+    out
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multi_recv::MultiRecv;
+
+    #[test]
+    fn renders_labelled_values() {
+        MESSAGES_BRIDGED.inc(&[("direction", "discord_to_guilded"), ("binding", "discord:1 -> guilded:\"a\"")]);
+        MESSAGES_BRIDGED.inc(&[("direction", "discord_to_guilded"), ("binding", "discord:1 -> guilded:\"a\"")]);
+        HEARTBEAT_LATENCY.set(&[("platform", "test")], 0.25);
+        let rendered = render();
+        assert!(rendered.contains("# TYPE bridge_messages_bridged_total counter\n"), "{}", rendered);
+        assert!(rendered.contains("bridge_messages_bridged_total{direction=\"discord_to_guilded\",binding=\"discord:1 -> guilded:\\\"a\\\"\"} 2\n"), "{}", rendered);
+        assert!(rendered.contains("bridge_heartbeat_latency_seconds{platform=\"test\"} 0.25\n"), "{}", rendered);
+    }
+
+    #[async_std::test]
+    async fn reports_queues_while_their_subscriber_is_around() {
+        let (sender, receiver) = MultiRecv::<u32>::new();
+        watch_queue("metrics_test", receiver.stats());
+        sender.send(1).await.unwrap();
+        async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(render().contains("bridge_queue_depth{queue=\"metrics_test\"} 1\n"));
+        drop(receiver);
+        assert!(!render().contains("queue=\"metrics_test\""));
+    }
+}
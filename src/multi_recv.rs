@@ -1,18 +1,122 @@
-use async_std::channel::{Sender, Receiver, unbounded};
+use async_std::channel::{Sender, Receiver, TrySendError, bounded, unbounded};
 use async_std::task::spawn;
 use futures::FutureExt;
 use futures::StreamExt;
 use futures::Stream;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// How many items a subscriber can fall behind by before its `Overflow` policy kicks in
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// What happens when a subscriber's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for the subscriber to catch up, which holds up every other subscriber and then the sender too
+    Block,
+    /// Throw the oldest queued item away to make room
+    DropOldest,
+    /// Stop sending to the subscriber, it gets `RecvError::Lagged` once it has read what's queued
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// The sender is gone and everything sent has been read
+    Closed,
+    /// The subscriber fell too far behind and was disconnected
+    Lagged,
+}
+
+#[derive(Default)]
+struct Counters {
+    dropped: AtomicU64,
+    lagged: AtomicBool,
+}
+
+/// The fan out task's end of a subscriber
+struct Subscription<T> {
+    to_them: Sender<Arc<T>>,
+    /// `DropOldest` takes items back out of the queue through this
+    their_queue: Option<Receiver<Arc<T>>>,
+    overflow: Overflow,
+    counters: Arc<Counters>,
+}
+impl<T> Subscription<T> {
+    fn is_alive(&self) -> bool {
+        //Our own `their_queue` counts as a receiver
+        self.to_them.receiver_count() > self.their_queue.iter().count()
+    }
+
+    /// Whether the subscriber is still there afterwards
+    async fn send(&self, msg: &Arc<T>) -> bool {
+        if !self.is_alive() { return false };
+        match self.overflow {
+            Overflow::Block => self.to_them.send(msg.clone()).await.is_ok(),
+            Overflow::DropOldest => loop {
+                match self.to_them.try_send(msg.clone()) {
+                    Ok(()) => return true,
+                    Err(TrySendError::Full(_)) => if self.their_queue.as_ref().is_some_and(|queue| queue.try_recv().is_ok()) { self.counters.dropped.fetch_add(1, Ordering::Relaxed); },
+                    Err(TrySendError::Closed(_)) => return false,
+                }
+            },
+            Overflow::Disconnect => match self.to_them.try_send(msg.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    self.counters.lagged.store(true, Ordering::Relaxed);
+                    false
+                },
+                Err(TrySendError::Closed(_)) => false,
+            },
+        }
+    }
+}
+
+/// What `QueueStats` needs from a subscription, without its item type
+trait Depth: Send + Sync {
+    fn depth(&self) -> Option<usize>;
+}
+impl<T: Send + Sync> Depth for Subscription<T> {
+    fn depth(&self) -> Option<usize> {
+        self.is_alive().then(|| self.to_them.len())
+    }
+}
+
+/// A look at one subscriber's queue that doesn't keep the subscriber around, for metrics
+#[derive(Clone)]
+pub struct QueueStats {
+    subscription: Weak<dyn Depth>,
+    counters: Arc<Counters>,
+}
+impl QueueStats {
+    /// How many items are waiting to be read, `None` once the subscriber is gone
+    pub fn depth(&self) -> Option<usize> {
+        self.subscription.upgrade().and_then(|subscription| subscription.depth())
+    }
+
+    /// How many items the subscriber never got because of its `Overflow` policy
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+}
 
 pub struct MultiRecv<T> {
     to_me: Receiver<Arc<T>>,
-    new_receivers: Sender<Sender<Arc<T>>>
+    counters: Arc<Counters>,
+    subscription: Weak<dyn Depth>,
+    new_receivers: Sender<Arc<Subscription<T>>>
 }
 impl<T> MultiRecv<T> where T: Sync + std::marker::Send + 'static {
+    /// A sender and its first subscriber, with `DEFAULT_CAPACITY` and `Overflow::Block`
     pub fn new() -> (Sender<T>, MultiRecv<T>) {
-        let (sender, mut origional_receiver) = unbounded::<T>();
-        let (new_receivers, mut receive_new_receivers) = unbounded::<Sender<Arc<T>>>();
+        Self::with_capacity(DEFAULT_CAPACITY, Overflow::Block)
+    }
+
+    /// A sender that holds at most `capacity` items, and its first subscriber
+    pub fn with_capacity(capacity: usize, overflow: Overflow) -> (Sender<T>, MultiRecv<T>) {
+        let (sender, mut origional_receiver) = bounded::<T>(capacity);
+        let (new_receivers, mut receive_new_receivers) = unbounded::<Arc<Subscription<T>>>();
         spawn(async move {
             let mut receivers = Vec::new();
             loop {
@@ -20,6 +124,9 @@ impl<T> MultiRecv<T> where T: Sync + std::marker::Send + 'static {
                     new_receiver = receive_new_receivers.next().fuse() => {
                         if let Some(new_receiver) = new_receiver {
                             receivers.push(new_receiver);
+                        } else {
+                            //Every subscriber and `Subscriber` is gone, nobody could receive anything again
+                            return;
                         }
                     },
                     new_msg = origional_receiver.next().fuse() => {
@@ -27,7 +134,7 @@ impl<T> MultiRecv<T> where T: Sync + std::marker::Send + 'static {
                             let new_msg = Arc::new(new_msg);
                             let mut i = 0;
                             while i < receivers.len() {
-                                if let Err(_) = receivers[i].send(new_msg.clone()).await {
+                                if !receivers[i].send(&new_msg).await {
                                     receivers.remove(i);
                                 } else {
                                     i += 1;
@@ -40,21 +147,138 @@ impl<T> MultiRecv<T> where T: Sync + std::marker::Send + 'static {
                 }
             }
         });
-        let (to_receiver, receiver) = unbounded::<Arc<T>>();
-        new_receivers.try_send(to_receiver).expect("How did we get here");
-        (sender, MultiRecv { to_me: receiver, new_receivers })
+        let receiver = Subscriber { new_receivers }.subscribe_with(capacity, overflow);
+        (sender, receiver)
+    }
+}
+impl<T> MultiRecv<T> where T: Sync + std::marker::Send + 'static {
+    /// A handle that makes new receivers later without receiving, and piling up, anything itself
+    pub fn subscriber(&self) -> Subscriber<T> {
+        Subscriber { new_receivers: self.new_receivers.clone() }
+    }
+
+    /// The next item, or why there won't be one
+    pub async fn recv(&mut self) -> Result<Arc<T>, RecvError> {
+        match self.to_me.recv().await {
+            Ok(msg) => Ok(msg),
+            Err(_) if self.counters.lagged.load(Ordering::Relaxed) => Err(RecvError::Lagged),
+            Err(_) => Err(RecvError::Closed),
+        }
+    }
+
+    /// How many items are waiting to be read
+    pub fn queue_depth(&self) -> usize {
+        self.to_me.len()
+    }
+
+    /// How many items this subscriber never got because of its `Overflow` policy
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats { subscription: self.subscription.clone(), counters: self.counters.clone() }
     }
 }
 impl<T> Clone for MultiRecv<T> where T: Sync + std::marker::Send + 'static {
+    /// Another subscriber, with `DEFAULT_CAPACITY` and `Overflow::Block`
     fn clone(&self) -> Self {
-        let (send_to_me, to_me) = unbounded::<Arc<T>>();
-        self.new_receivers.try_send(send_to_me).expect("Failed to clone MultiRecv");
-        MultiRecv { to_me, new_receivers: self.new_receivers.clone() }
+        self.subscriber().subscribe()
     }
 }
-impl<T> Stream for MultiRecv<T> where T: Clone + Sync + std::marker::Send + 'static {
+impl<T> Stream for MultiRecv<T> where T: Sync + std::marker::Send + 'static {
     type Item = Arc<T>;
     fn poll_next(mut self: std::pin::Pin<&mut Self>, ctx: &mut std::task::Context) -> std::task::Poll<Option<Arc<T>>> {
         self.to_me.poll_next_unpin(ctx)
     }
 }
+
+pub struct Subscriber<T> {
+    new_receivers: Sender<Arc<Subscription<T>>>
+}
+impl<T> Subscriber<T> where T: Sync + std::marker::Send + 'static {
+    /// Everything sent from now on, with `DEFAULT_CAPACITY` and `Overflow::Block`
+    pub fn subscribe(&self) -> MultiRecv<T> {
+        self.subscribe_with(DEFAULT_CAPACITY, Overflow::Block)
+    }
+
+    /// Everything sent from now on, queueing at most `capacity` items
+    pub fn subscribe_with(&self, capacity: usize, overflow: Overflow) -> MultiRecv<T> {
+        let (to_them, to_me) = bounded::<Arc<T>>(capacity);
+        let counters = Arc::new(Counters::default());
+        let subscription = Arc::new(Subscription { to_them, their_queue: (overflow == Overflow::DropOldest).then(|| to_me.clone()), overflow, counters: counters.clone() });
+        let weak = Arc::downgrade(&subscription);
+        //Not `try_send(..).expect(..)`, `Subscription` isn't `Debug`
+        if self.new_receivers.try_send(subscription).is_err() { panic!("Failed to subscribe to MultiRecv") };
+        MultiRecv { to_me, counters, subscription: weak, new_receivers: self.new_receivers.clone() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn settle() {
+        async_std::task::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[async_std::test]
+    async fn block_waits_for_the_slowest_subscriber() {
+        let (sender, mut slow) = MultiRecv::<u32>::with_capacity(2, Overflow::Block);
+        let mut fast = slow.subscriber().subscribe_with(16, Overflow::Block);
+        settle().await;
+        for i in 0..5 { sender.send(i).await.unwrap() };
+        settle().await;
+        //Two queued for the slow one, one stuck in the fan out and two in the sender's own queue
+        assert!(sender.try_send(5).is_err());
+        assert_eq!((slow.queue_depth(), fast.queue_depth()), (2, 2));
+        for i in 0..5 { assert_eq!(*slow.recv().await.unwrap(), i) };
+        for i in 0..5 { assert_eq!(*fast.recv().await.unwrap(), i) };
+        assert_eq!((fast.dropped(), slow.dropped()), (0, 0));
+    }
+
+    #[async_std::test]
+    async fn drop_oldest_keeps_the_newest() {
+        let (sender, mut fast) = MultiRecv::<u32>::with_capacity(16, Overflow::Block);
+        let mut slow = fast.subscriber().subscribe_with(2, Overflow::DropOldest);
+        settle().await;
+        for i in 0..5 { sender.send(i).await.unwrap() };
+        settle().await;
+        assert_eq!((slow.queue_depth(), slow.dropped()), (2, 3));
+        let stats = slow.stats();
+        assert_eq!((stats.depth(), stats.dropped()), (Some(2), 3));
+        assert_eq!(*slow.recv().await.unwrap(), 3);
+        assert_eq!(*slow.recv().await.unwrap(), 4);
+        for i in 0..5 { assert_eq!(*fast.recv().await.unwrap(), i) };
+        drop(sender);
+        assert_eq!(slow.recv().await, Err(RecvError::Closed));
+    }
+
+    #[async_std::test]
+    async fn disconnect_ends_with_a_lag_error() {
+        let (sender, mut fast) = MultiRecv::<u32>::with_capacity(16, Overflow::Block);
+        let mut slow = fast.subscriber().subscribe_with(2, Overflow::Disconnect);
+        settle().await;
+        for i in 0..4 { sender.send(i).await.unwrap() };
+        settle().await;
+        assert_eq!(*slow.recv().await.unwrap(), 0);
+        assert_eq!(*slow.recv().await.unwrap(), 1);
+        assert_eq!(slow.recv().await, Err(RecvError::Lagged));
+        assert_eq!(slow.dropped(), 1);
+        //Everyone else carries on
+        for i in 0..4 { assert_eq!(*fast.recv().await.unwrap(), i) };
+    }
+
+    #[async_std::test]
+    async fn forgets_subscribers_that_are_dropped() {
+        let (sender, mut kept) = MultiRecv::<u32>::with_capacity(1, Overflow::Block);
+        let stats = [Overflow::Block, Overflow::DropOldest].map(|overflow| kept.subscriber().subscribe_with(1, overflow).stats());
+        for i in 0..3 {
+            sender.send(i).await.unwrap();
+            assert_eq!(*kept.recv().await.unwrap(), i);
+        }
+        assert!(stats.iter().all(|stats| stats.depth().is_none()));
+        assert_eq!(kept.stats().depth(), Some(0));
+    }
+}
@@ -0,0 +1,105 @@
+use async_std::fs;
+use async_std::path::Path;
+use futures::AsyncWriteExt;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// Replaces `path` with `contents` so that a crash at any point leaves either the old or the new file, never half of one.
+/// The previous generation is kept as `<path>.bak`.
+pub async fn save_atomic(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    if Path::new(path).exists().await {
+        //The backup goes through a temporary file of its own, so a crash never leaves a torn .bak either
+        let bak_tmp = format!("{}.bak.tmp", path);
+        fs::copy(path, &bak_tmp).await?;
+        fs::File::open(&bak_tmp).await?.sync_all().await?;
+        fs::rename(&bak_tmp, format!("{}.bak", path)).await?;
+    }
+    fs::rename(&tmp, path).await?;
+    //The rename itself only survives a crash once the directory is synced
+    let dir = Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+    fs::File::open(dir).await?.sync_all().await?;
+    Ok(())
+}
+
+pub async fn save_json<T: Serialize>(path: &str, value: &T) -> std::io::Result<()> {
+    let contents = serde_json::to_vec(value)?;
+    save_atomic(path, &contents).await
+}
+
+/// Starts fresh when the file doesn't exist yet, but refuses to when it exists and can't be read.
+/// Quietly starting over would recreate every webhook and orphan the old ones.
+///
+/// `upgrade` gets the raw json first, to bring files written by older versions up to date.
+pub async fn load_json<T: DeserializeOwned + Default>(path: &str, upgrade: impl FnOnce(serde_json::Value) -> Result<serde_json::Value, String>) -> Result<T, String> {
+    let contents = match fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(err) => return Err(format!("Failed to read {}: {}", path, err)),
+    };
+    let parsed = serde_json::from_str(&contents).map_err(|err| err.to_string())
+        .and_then(upgrade)
+        .and_then(|upgraded| serde_json::from_value(upgraded).map_err(|err| err.to_string()));
+    parsed.map_err(|err| {
+        let backup = format!("{}.bak", path);
+        if std::path::Path::new(&backup).exists() {
+            format!("{} is corrupt ({}). The previous version is in {}, check it and copy it over {} to recover", path, err, backup, path)
+        } else {
+            format!("{} is corrupt ({}). Fix or remove it to start over", path, err)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("bridge7573_persist_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[async_std::test]
+    async fn saves_atomically_and_keeps_the_previous_generation() {
+        let dir = temp_dir("save");
+        let path = dir.join("data.json").to_str().unwrap().to_owned();
+        save_atomic(&path, b"first").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"first");
+        assert!(!std::path::Path::new(&format!("{}.bak", path)).exists());
+
+        save_atomic(&path, b"second").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read(format!("{}.bak", path)).unwrap(), b"first");
+        let mut left = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(left, ["data.json", "data.json.bak"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[async_std::test]
+    async fn refuses_to_load_a_corrupt_file() {
+        let dir = temp_dir("load");
+        let path = dir.join("data.json").to_str().unwrap().to_owned();
+        let loaded: BTreeMap<String, String> = load_json(&path, Ok).await.unwrap();
+        assert!(loaded.is_empty());
+
+        std::fs::write(&path, "{ not json").unwrap();
+        let err = load_json::<BTreeMap<String, String>>(&path, Ok).await.unwrap_err();
+        assert!(err.contains("is corrupt") && err.contains("Fix or remove it"), "{}", err);
+
+        std::fs::write(format!("{}.bak", path), "{}").unwrap();
+        let err = load_json::<BTreeMap<String, String>>(&path, Ok).await.unwrap_err();
+        assert!(err.contains(&format!("The previous version is in {}.bak", path)), "{}", err);
+
+        let err = load_json::<BTreeMap<String, String>>(&format!("{}.bak", path), |_| Err("too new".to_owned())).await.unwrap_err();
+        assert!(err.contains("too new"), "{}", err);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
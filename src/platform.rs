@@ -0,0 +1,132 @@
+use crate::event::*;
+use crate::error::*;
+use crate::supervisor::Shutdown;
+use async_std::channel::Sender;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// A chat service the bridge can relay to and from. Channel ids are the platform's own, without the `platform:` prefix.
+#[async_trait::async_trait]
+pub trait Platform: Send + Sync {
+    /// Matches `ChannelRef::platform` for this platform's channels
+    fn name(&self) -> &'static str;
+
+    /// One session: connects and sends everything that happens on the platform to `events` until `shutdown.stop` fires,
+    /// keeps the connection up for whatever is still being delivered until `shutdown.close` fires, then closes it and
+    /// returns `Ok`. An error means the connection couldn't be made or was lost, and the router runs it again if that's
+    /// worth trying. Returning `Ok` before then means there's nothing more to relay from the platform.
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError>;
+
+    /// Posts a copy of `message` into `channel` as its author. Returns the copy's id, if the platform gives one back.
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError>;
+
+    /// Whether `edit_message` works here. Edits aren't relayed to platforms that can't.
+    fn can_edit(&self) -> bool {
+        false
+    }
+
+    /// Replaces the content of a copy made by `send_message` with the edited `message`
+    async fn edit_message(&self, _channel: &str, _id: &str, _message: &BridgeMessage) -> Result<(), BridgeError> {
+        Err(BridgeError::protocol(format!("{} can't edit relayed messages", self.name())))
+    }
+
+    /// Deletes a copy made by `send_message` for `author`
+    async fn delete_message(&self, _channel: &str, _id: &str, _author: &BridgeUser) -> Result<(), BridgeError> {
+        Err(BridgeError::protocol(format!("{} can't delete relayed messages", self.name())))
+    }
+
+    /// Whether `user` may manage the bridge from `channel` with `!bridge` commands. Nobody can, unless the platform has
+    /// roles to go by.
+    async fn is_moderator(&self, _channel: &str, _user: &str) -> Result<bool, BridgeError> {
+        Ok(false)
+    }
+
+    /// How the connection is doing right now. Platforms without a connection of their own are always connected.
+    fn status(&self) -> PlatformStatus {
+        PlatformStatus { connected: true, last_ack_secs: None, stale: false }
+    }
+}
+
+/// For `/healthz` and `/readyz`
+#[derive(Clone, Debug, Serialize)]
+pub struct PlatformStatus {
+    /// Logged in and relaying. False before the first session is up and while reconnecting.
+    pub connected: bool,
+    /// Seconds since the other end last answered a heartbeat or ping, for platforms that have one
+    pub last_ack_secs: Option<f64>,
+    /// Nothing answered the heartbeat for over two of its intervals, the connection is most likely dead
+    pub stale: bool,
+}
+
+/// Kept up to date by a platform's `run`, for its `status`
+#[derive(Default)]
+pub struct SessionHealth {
+    connected: AtomicBool,
+    last_ack: std::sync::Mutex<Option<Instant>>,
+    /// The heartbeat interval of the current session, and when it started, for sessions that have a heartbeat
+    heartbeat: std::sync::Mutex<Option<(Duration, Instant)>>,
+}
+impl SessionHealth {
+    /// Connected until the returned guard is dropped, however the session ends
+    pub fn connected(&self) -> ConnectedGuard<'_> {
+        self.connected.store(true, Ordering::Relaxed);
+        ConnectedGuard(self)
+    }
+
+    /// The other end answered a heartbeat or ping
+    pub fn acked(&self) {
+        *self.last_ack.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// The current session sends a heartbeat every `interval`, which the other end should answer
+    pub fn heartbeat_every(&self, interval: Duration) {
+        *self.heartbeat.lock().unwrap() = Some((interval, Instant::now()));
+    }
+
+    /// No answer to the heartbeat, or no heartbeat answered since the session started, for over two intervals
+    pub fn stale(&self) -> bool {
+        let (interval, started) = match *self.heartbeat.lock().unwrap() { Some(heartbeat) => heartbeat, None => return false };
+        let heard = self.last_ack.lock().unwrap().map_or(started, |acked| acked.max(started));
+        heard.elapsed() > interval * 2
+    }
+
+    pub fn status(&self) -> PlatformStatus {
+        let stale = self.stale();
+        PlatformStatus {
+            connected: self.connected.load(Ordering::Relaxed),
+            last_ack_secs: self.last_ack.lock().unwrap().map(|acked| acked.elapsed().as_secs_f64()),
+            stale,
+        }
+    }
+}
+
+pub struct ConnectedGuard<'a>(&'a SessionHealth);
+impl Drop for ConnectedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.connected.store(false, Ordering::Relaxed);
+        self.0.heartbeat.lock().unwrap().take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goes_stale_two_heartbeats_after_the_last_ack() {
+        let health = SessionHealth::default();
+        let connected = health.connected();
+        assert!(!health.stale());
+        health.heartbeat_every(Duration::from_millis(10));
+        assert!(!health.status().stale);
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(health.status().stale);
+        health.acked();
+        assert!(!health.stale());
+        drop(connected);
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(!health.stale());
+    }
+}
@@ -0,0 +1,100 @@
+//! Raw Discord and Guilded frames, written down as they come in so a session can be played back later without the
+//! real services (`--record` and `--replay`). One JSON object per line, like `{"platform":"discord","frame":"..."}`.
+use crate::*;
+use async_tungstenite::tungstenite::Message;
+use serde::{Serialize, Deserialize};
+use serde_json::Value as JsValue;
+use std::io::Write;
+
+lazy_static::lazy_static! {
+    static ref RECORDING: std::sync::Mutex<Option<std::fs::File>> = std::sync::Mutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Frame {
+    pub platform: String,
+    pub frame: String,
+}
+
+/// Appends every frame from now on to `path`
+pub fn start(path: &str) -> Result<(), BridgeError> {
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)
+        .map_err(|err| BridgeError::storage(format!("Failed to open {} for recording: {}", path, err)))?;
+    *RECORDING.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+/// Writes `msg` down, if recording. Whatever the logs hide is hidden here too, along with session and interaction tokens.
+pub fn frame(platform: &str, msg: &Message) {
+    let mut recording = RECORDING.lock().unwrap();
+    let file = match &mut *recording { Some(file) => file, None => return };
+    let text = match msg { Message::Text(text) => text, _ => return };
+    let line = serde_json::to_string(&Frame { platform: platform.to_owned(), frame: redact(text) }).expect("How did we get here?");
+    if let Err(err) = writeln!(file, "{}", line) {
+        tracing::warn!(error = %err, "Failed to record a frame, not recording anymore");
+        *recording = None;
+    }
+}
+
+/// The frames recorded from `platform`, in the order they came
+pub fn load(path: &str, platform: &str) -> Result<Vec<String>, BridgeError> {
+    let text = std::fs::read_to_string(path).map_err(|err| BridgeError::storage(format!("Failed to read {}: {}", path, err)))?;
+    let mut frames = Vec::new();
+    for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let frame = serde_json::from_str::<Frame>(line).map_err(|err| BridgeError::storage(format!("{}:{}: {}", path, i + 1, err)))?;
+        if frame.platform == platform { frames.push(frame.frame) };
+    }
+    Ok(frames)
+}
+
+fn redact(text: &str) -> String {
+    //Socket.io frames have their packet type in front of the json
+    let (packet_type, json) = text.split_at(text.find(['{', '[']).unwrap_or(text.len()));
+    let text = match serde_json::from_str::<JsValue>(json) {
+        Ok(mut json) => {
+            hide_tokens(&mut json);
+            format!("{}{}", packet_type, json)
+        },
+        Err(_) => text.to_owned(),
+    };
+    logging::scrub(&text).into_owned()
+}
+
+fn hide_tokens(json: &mut JsValue) {
+    match json {
+        JsValue::Object(fields) => for (name, value) in fields {
+            if matches!(name.as_str(), "token" | "session_id") && value.is_string() { *value = JsValue::from("<redacted>") }
+            else { hide_tokens(value) };
+        },
+        JsValue::Array(items) => items.iter_mut().for_each(hide_tokens),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hides_tokens() {
+        logging::redact("recording-secret");
+        assert_eq!(redact(r#"{"op":0,"d":{"token":"abc","session_id":"def","content":"recording-secret"}}"#),
+            r#"{"op":0,"d":{"token":"<redacted>","session_id":"<redacted>","content":"<redacted>"}}"#);
+        assert_eq!(redact(r#"42["ChatMessageCreated",{"url":"https://media.guilded.gg/webhooks/1/xyz"}]"#),
+            r#"42["ChatMessageCreated",{"url":"https://media.guilded.gg/webhooks/1/<redacted>"}]"#);
+        assert_eq!(redact("3"), "3");
+    }
+
+    #[test]
+    fn records_what_it_loads() {
+        let path = std::env::temp_dir().join(format!("bridge7573-recording-{}.jsonl", std::process::id())).to_str().unwrap().to_owned();
+        start(&path).unwrap();
+        frame(DISCORD, &Message::Text(r#"{"op":11}"#.to_owned()));
+        frame(GUILDED, &Message::Text("3".to_owned()));
+        frame(DISCORD, &Message::Binary(vec![1]));
+        *RECORDING.lock().unwrap() = None;
+        assert_eq!(load(&path, DISCORD).unwrap(), [r#"{"op":11}"#]);
+        assert_eq!(load(&path, GUILDED).unwrap(), ["3"]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
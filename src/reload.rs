@@ -0,0 +1,33 @@
+use crate::*;
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+use tracing::{error, info};
+
+/// Re-reads the config file every time the process gets a SIGHUP. A config that fails to load is reported and ignored.
+pub fn reload_config_on_sighup(env: Arc<Environment>) {
+    let mut signals = Signals::new([SIGHUP]).expect("Failed to listen for SIGHUP");
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            async_std::task::block_on(reload_config(&env));
+        }
+    });
+}
+
+pub async fn reload_config(env: &Arc<Environment>) {
+    let new_config = match Config::load(&env.settings.config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            error!(error = %err, "Config reload failed, keeping the old config");
+            *env.config_error.lock().unwrap() = Some(err);
+            return;
+        }
+    };
+    env.config_error.lock().unwrap().take();
+    let mut config = env.config.write().await;
+    let old_routes = config.route_pairs();
+    let new_routes = new_config.route_pairs();
+    for (from, to) in old_routes.difference(&new_routes) { info!(%from, %to, "Route removed") };
+    for (from, to) in new_routes.difference(&old_routes) { info!(%from, %to, "Route added") };
+    *config = Arc::new(new_config);
+    info!(routes = new_routes.len(), "Config reloaded");
+}
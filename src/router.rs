@@ -0,0 +1,293 @@
+use crate::*;
+use async_std::channel::unbounded;
+use futures::FutureExt;
+use futures::stream::FuturesUnordered;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+/// How many relayed messages edits and deletes are followed for, the oldest are forgotten first
+const RELAYED_HISTORY: usize = 10_000;
+/// How long platforms get to disconnect once everything's delivered
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Takes the events every platform produces and relays them to wherever the config routes them.
+pub struct Router {
+    env: Arc<Environment>,
+    platforms: BTreeMap<&'static str, Arc<dyn Platform>>,
+    relayed: Mutex<RelayedHistory>,
+}
+
+/// Where the copies of a relayed message ended up, so edits and deletes can follow it
+#[derive(Clone)]
+struct Relayed {
+    author: BridgeUser,
+    copies: Vec<RelayedCopy>,
+}
+#[derive(Clone)]
+struct RelayedCopy {
+    channel: ChannelRef,
+    id: String,
+}
+
+/// The copies of the last `limit` relayed messages, by the original's channel and id.
+///
+/// Only kept in memory: it changes with every message, and edits and deletes mostly come soon after the original.
+/// Messages relayed before a restart are no longer followed.
+struct RelayedHistory {
+    limit: usize,
+    copies: BTreeMap<(ChannelRef, String), Relayed>,
+    order: VecDeque<(ChannelRef, String)>,
+}
+impl RelayedHistory {
+    fn new(limit: usize) -> RelayedHistory {
+        RelayedHistory { limit, copies: BTreeMap::new(), order: VecDeque::new() }
+    }
+
+    fn insert(&mut self, channel: ChannelRef, id: String, relayed: Relayed) {
+        let key = (channel, id);
+        if self.copies.insert(key.clone(), relayed).is_none() { self.order.push_back(key) };
+        while self.copies.len() > self.limit {
+            let oldest = match self.order.pop_front() { Some(oldest) => oldest, None => break };
+            self.copies.remove(&oldest);
+        }
+    }
+
+    fn get(&self, channel: &ChannelRef, id: &str) -> Option<Relayed> {
+        self.copies.get(&(channel.clone(), id.to_owned())).cloned()
+    }
+
+    fn remove(&mut self, channel: &ChannelRef, id: &str) {
+        let key = (channel.clone(), id.to_owned());
+        if self.copies.remove(&key).is_some() { self.order.retain(|kept| kept != &key) };
+    }
+}
+
+impl Router {
+    pub fn new(env: Arc<Environment>) -> Router {
+        Router { env, platforms: BTreeMap::new(), relayed: Mutex::new(RelayedHistory::new(RELAYED_HISTORY)) }
+    }
+
+    pub fn add_platform(&mut self, platform: Arc<dyn Platform>) {
+        self.platforms.insert(platform.name(), platform);
+    }
+
+    pub fn platforms(&self) -> Vec<Arc<dyn Platform>> {
+        self.platforms.values().cloned().collect()
+    }
+
+    /// Runs every platform, running failed ones again after a while, and handles their events until `shutdown.stop`
+    /// fires or every platform is done. What's already queued is still delivered, then `shutdown.close` has the
+    /// platforms disconnect and storage is flushed. Returns the first error a platform couldn't get past.
+    ///
+    /// Up to `relay_concurrency` channels are handled at once, each channel's events one at a time in the order they came.
+    pub async fn run(self, shutdown: Shutdown) -> Result<(), BridgeError> {
+        let (events, incoming) = unbounded::<BridgeEvent>();
+        let mut sessions = self.platforms.values().cloned().map(|platform| {
+            let (events, shutdown) = (events.clone(), shutdown.clone());
+            async move {
+                let mut first = true;
+                let result = supervise(platform.name(), &shutdown.stop, Backoff::default(), || {
+                    if !first { metrics::RECONNECTS.inc(&[("platform", platform.name())]) };
+                    first = false;
+                    let session = platform.clone().run(events.clone(), shutdown.clone());
+                    async {
+                        let result = session.await;
+                        if result.as_ref().err().and_then(BridgeError::status) == Some(429) { metrics::RATE_LIMITED.inc(&[("platform", platform.name())]) };
+                        result
+                    }
+                }).await;
+                (platform.name(), result)
+            }
+        }).collect::<FuturesUnordered<_>>();
+        drop(events);
+        let mut failed = None;
+
+        let limit = self.env.settings.relay_concurrency.max(1);
+        let mut incoming = incoming.fuse();
+        let mut running = FuturesUnordered::new();
+        //Every channel with an event running or waiting, and what's waiting
+        let mut channels = BTreeMap::<ChannelRef, VecDeque<BridgeEvent>>::new();
+        //Channels with events waiting and none running, taking turns as room frees up
+        let mut ready = VecDeque::<ChannelRef>::new();
+        let stop = shutdown.stop.wait().fuse();
+        futures::pin_mut!(stop);
+        loop {
+            while running.len() < limit {
+                let channel = match ready.pop_front() { Some(channel) => channel, None => break };
+                let event = channels.get_mut(&channel).and_then(|waiting| waiting.pop_front()).expect("Ready channel without events");
+                let router = &self;
+                running.push(async move {
+                    router.handle(event).await;
+                    channel
+                });
+            }
+            let stopped = shutdown.stop.has_fired() || sessions.is_empty();
+            if stopped && channels.is_empty() && incoming.get_ref().is_empty() { break };
+            select! {
+                event = incoming.next() => if let Some(event) = event {
+                    let channel = event.channel().clone();
+                    channels.entry(channel.clone()).or_insert_with(|| {
+                        ready.push_back(channel);
+                        VecDeque::new()
+                    }).push_back(event);
+                },
+                channel = running.select_next_some() => {
+                    if channels.get(&channel).is_none_or(|waiting| waiting.is_empty()) {
+                        channels.remove(&channel);
+                    } else {
+                        ready.push_back(channel);
+                    }
+                },
+                (name, result) = sessions.select_next_some() => if let Err(err) = result {
+                    error!(platform = name, error = %err, "Giving up");
+                    failed.get_or_insert(err);
+                    shutdown.stop.fire();
+                },
+                _ = stop => info!(queued = incoming.get_ref().len() + channels.values().map(|waiting| waiting.len()).sum::<usize>() + running.len(), "Stopped, delivering what's queued"),
+                complete => break,
+            }
+        }
+
+        shutdown.stop.fire();
+        shutdown.close.fire();
+        let disconnected = async_std::future::timeout(CLOSE_TIMEOUT, async {
+            while let Some((name, result)) = sessions.next().await {
+                if let Err(err) = result { warn!(platform = name, error = %err, "Failed to disconnect cleanly") };
+            }
+        }).await;
+        if disconnected.is_err() { warn!("Gave up waiting for platforms to disconnect") };
+        if let Err(err) = self.env.storage.flush().await {
+            error!(error = %err, "Failed to flush storage");
+            failed.get_or_insert(err);
+        }
+        match failed { Some(err) => Err(err), None => Ok(()) }
+    }
+
+    pub async fn handle(&self, event: BridgeEvent) {
+        match event {
+            BridgeEvent::MessageCreated(message) => self.message_created(message).await,
+            BridgeEvent::MessageEdited(message) => self.message_edited(message).await,
+            BridgeEvent::MessageDeleted { channel, id } => self.message_deleted(channel, id).await,
+            //Relayed messages are posted by webhooks, and webhooks can't react, so there's nothing to relay a reaction as
+            BridgeEvent::ReactionAdded { .. } => (),
+            BridgeEvent::Command { channel, user, command, moderator, answer } => {
+                let _ = answer.send(commands::answer(&self.env, &self.platforms, &channel, &user, command, moderator).await).await;
+            },
+        }
+    }
+
+    async fn message_created(&self, message: BridgeMessage) {
+        if commands::handle(&self.env, &self.platforms, &message).await { return };
+        match admin::is_ignored(&self.env, &message.channel, &message.author).await {
+            Ok(false) => (),
+            Ok(true) => return debug!(channel = %message.channel, message_id = %message.id, user = %message.author.id, "Ignored"),
+            Err(err) => warn!(channel = %message.channel, user = %message.author.id, error = %err, "Failed to check if the author is ignored"),
+        }
+        let targets = self.env.config.read().await.routes_from(&message.channel).to_vec();
+        let mut copies = vec![];
+        for target in targets {
+            let platform = match self.platforms.get(target.platform()) {
+                Some(platform) => platform,
+                None => {
+                    warn!(channel = %message.channel, message_id = %message.id, to = %target, "No {} platform running", target.platform());
+                    metrics::DELIVERY_FAILURES.inc(&[("platform", target.platform()), ("reason", "no_platform")]);
+                    continue;
+                }
+            };
+            let result = platform.send_message(target.id(), &message).await;
+            if result.is_ok() {
+                let direction = format!("{}_to_{}", message.channel.platform(), target.platform());
+                metrics::MESSAGES_BRIDGED.inc(&[("direction", &direction), ("binding", &format!("{} -> {}", message.channel, target))]);
+            }
+            match result {
+                Ok(Some(id)) => {
+                    debug!(channel = %message.channel, message_id = %message.id, to = %target, copy_id = %id, "Relayed");
+                    copies.push(RelayedCopy { channel: target, id });
+                },
+                Ok(None) => debug!(channel = %message.channel, message_id = %message.id, to = %target, "Relayed"),
+                Err(err) => {
+                    warn!(channel = %message.channel, message_id = %message.id, user = %message.author.id, to = %target, error = %err, "Relay failed");
+                    metrics::delivery_failed(target.platform(), &err);
+                },
+            }
+        }
+        if copies.is_empty() { return };
+
+        self.relayed.lock().unwrap().insert(message.channel, message.id, Relayed { author: message.author, copies });
+    }
+
+    async fn message_edited(&self, message: BridgeMessage) {
+        let relayed = match self.relayed.lock().unwrap().get(&message.channel, &message.id) { Some(relayed) => relayed, None => return };
+        //The copies were posted as whoever wrote the original, whoever the platform says did the editing
+        let message = BridgeMessage { author: relayed.author, ..message };
+        for copy in relayed.copies {
+            if let Some(platform) = self.platforms.get(copy.channel.platform()) {
+                //Guilded webhook messages can't be edited, for one, and that's not a failed delivery
+                if !platform.can_edit() {
+                    debug!(channel = %message.channel, message_id = %message.id, to = %copy.channel, "Can't relay edits there");
+                    continue;
+                }
+                if let Err(err) = platform.edit_message(copy.channel.id(), &copy.id, &message).await {
+                    warn!(channel = %message.channel, message_id = %message.id, to = %copy.channel, copy_id = %copy.id, error = %err, "Relaying edit failed");
+                    metrics::delivery_failed(copy.channel.platform(), &err);
+                }
+            }
+        }
+    }
+
+    async fn message_deleted(&self, channel: ChannelRef, id: String) {
+        let relayed = match self.relayed.lock().unwrap().get(&channel, &id) { Some(relayed) => relayed, None => return };
+        for copy in relayed.copies {
+            if let Some(platform) = self.platforms.get(copy.channel.platform()) {
+                if let Err(err) = platform.delete_message(copy.channel.id(), &copy.id, &relayed.author).await {
+                    warn!(%channel, message_id = %id, to = %copy.channel, copy_id = %copy.id, error = %err, "Relaying delete failed");
+                    metrics::delivery_failed(copy.channel.platform(), &err);
+                }
+            }
+        }
+        self.relayed.lock().unwrap().remove(&channel, &id);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relayed(id: &str) -> Relayed {
+        let author = BridgeUser { platform: DISCORD.to_owned(), id: "1".to_owned(), name: "bob".to_owned(), avatar_url: None };
+        Relayed { author, copies: vec![RelayedCopy { channel: ChannelRef::Guilded("g".to_owned()), id: id.to_owned() }] }
+    }
+
+    #[test]
+    fn forgets_the_oldest_relayed_messages_first() {
+        let channel = ChannelRef::Discord("d".to_owned());
+        let mut history = RelayedHistory::new(2);
+        for id in ["1", "2", "3"] { history.insert(channel.clone(), id.to_owned(), relayed(id)) };
+        assert!(history.get(&channel, "1").is_none());
+        assert_eq!(history.get(&channel, "3").unwrap().copies[0].id, "3");
+
+        history.remove(&channel, "2");
+        history.insert(channel.clone(), "4".to_owned(), relayed("4"));
+        assert!(history.get(&channel, "2").is_none());
+        assert!(history.get(&channel, "3").is_some() && history.get(&channel, "4").is_some());
+        assert!(history.copies.len() <= 2);
+    }
+
+    #[test]
+    fn stays_bounded_while_messages_come_and_go() {
+        let channel = ChannelRef::Discord("d".to_owned());
+        let mut history = RelayedHistory::new(3);
+        for id in 0..100 {
+            let id = (id % 5).to_string();
+            history.insert(channel.clone(), id.clone(), relayed(&id));
+            history.remove(&channel, &id);
+            history.insert(channel.clone(), id.clone(), relayed(&id));
+            assert!(history.order.len() <= 3 && history.copies.len() <= 3);
+        }
+        //A deleted and relayed again message is only as old as its latest copy
+        for id in ["2", "3", "4"] { assert!(history.get(&channel, id).is_some(), "{}", id) };
+    }
+}
@@ -0,0 +1,144 @@
+use crate::*;
+use futures::FutureExt;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Clone)]
+struct State {
+    env: Arc<Environment>,
+    platforms: Vec<Arc<dyn Platform>>,
+    shutdown: Shutdown,
+}
+
+/// The bridge's own HTTP server, for monitoring. Stops when `shutdown.close` fires, so it keeps answering while
+/// queued events are delivered.
+///
+/// - `/metrics`: everything in `metrics`
+/// - `/healthz`: fails when storage can't be read, restarting might help with that
+/// - `/readyz`: also fails while any platform is connecting, reconnecting or has stopped answering its heartbeat,
+///   and once the bridge is shutting down
+///
+/// The admin API is served apart from these, see `serve_admin`.
+pub async fn serve(env: Arc<Environment>, platforms: Vec<Arc<dyn Platform>>, listen: String, shutdown: Shutdown) -> Result<(), BridgeError> {
+    let mut app = tide::with_state(State { env, platforms, shutdown: shutdown.clone() });
+    app.at("/metrics").get(|_| async {
+        Ok(tide::Response::builder(200).content_type("text/plain; version=0.0.4").body(metrics::render()).build())
+    });
+    app.at("/healthz").get(|req: tide::Request<State>| async move { Ok(health(req.state(), false).await) });
+    app.at("/readyz").get(|req: tide::Request<State>| async move { Ok(health(req.state(), true).await) });
+    select! {
+        result = app.listen(listen).fuse() => result.context("HTTP server died"),
+        _ = shutdown.close.wait().fuse() => Ok(()),
+    }
+}
+
+/// `/admin`, see `admin_api`, on `admin_listen`. Nothing's served without an `admin_token`.
+pub async fn serve_admin(env: Arc<Environment>, platforms: Vec<Arc<dyn Platform>>, shutdown: Shutdown) -> Result<(), BridgeError> {
+    let token = match env.settings.admin_token.clone() { Some(token) => token, None => return Ok(()) };
+    let listen = env.settings.admin_listen.clone();
+    let state = State { env, platforms, shutdown: shutdown.clone() };
+    let mut app = tide::with_state(state.clone());
+    app.at("/admin").nest(admin_api(state, token));
+    select! {
+        result = app.listen(listen).fuse() => result.context("Admin HTTP server died"),
+        _ = shutdown.close.wait().fuse() => Ok(()),
+    }
+}
+
+#[derive(Serialize)]
+struct Health {
+    ok: bool,
+    shutting_down: bool,
+    platforms: BTreeMap<&'static str, PlatformStatus>,
+    config: Component,
+    storage: Component,
+}
+
+#[derive(Serialize)]
+struct Component {
+    ok: bool,
+    error: Option<String>,
+}
+impl Component {
+    fn from_error(error: Option<String>) -> Component {
+        Component { ok: error.is_none(), error }
+    }
+}
+
+async fn health(state: &State, ready: bool) -> tide::Response {
+    let platforms = state.platforms.iter().map(|platform| (platform.name(), platform.status())).collect::<BTreeMap<_, _>>();
+    //A config that failed to reload leaves the last good one running, so it's reported but doesn't fail anything
+    let config = Component::from_error(state.env.config_error.lock().unwrap().clone());
+    let storage = Component::from_error(state.env.storage.check().await.err().map(|err| err.to_string()));
+    let shutting_down = state.shutdown.stop.has_fired();
+    let ok = storage.ok && (!ready || (!shutting_down && platforms.values().all(|status| status.connected && !status.stale)));
+    let health = Health { ok, shutting_down, platforms, config, storage };
+    tide::Response::builder(if ok { 200 } else { 503 }).body(tide::Body::from_json(&health).expect("How did we get here?")).build()
+}
+
+/// Everything in `admin`, for `Authorization: Bearer <admin_token>` only. Answers with JSON, or `{"error": ...}` and
+/// 400 for a bad request or 500 for anything else.
+///
+/// - `GET /bindings`, `POST /bindings` with a binding like in the config, `DELETE /bindings/:index`
+/// - `POST /bindings/:index/pause` and `POST /bindings/:index/resume`
+/// - `GET /webhooks`, `DELETE /webhooks/:table/:channel` and `DELETE /webhooks/:table/:channel/:user`
+/// - `POST /webhooks/reconcile`
+fn admin_api(state: State, token: Secret) -> tide::Server<State> {
+    let mut api = tide::with_state(state);
+    api.with(RequireToken(token));
+    api.at("/bindings").get(|req: tide::Request<State>| async move { respond(admin::bindings(&req.state().env).await) });
+    api.at("/bindings").post(|mut req: tide::Request<State>| async move {
+        let binding = req.body_json().await?;
+        respond(admin::add_binding(&req.state().env, binding).await.map(|index| serde_json::json!({ "index": index })))
+    });
+    api.at("/bindings/:index").delete(|req: tide::Request<State>| async move {
+        respond(admin::remove_binding(&req.state().env, index(&req)?).await)
+    });
+    api.at("/bindings/:index/pause").post(|req: tide::Request<State>| async move {
+        respond(admin::set_paused(&req.state().env, index(&req)?, true).await)
+    });
+    api.at("/bindings/:index/resume").post(|req: tide::Request<State>| async move {
+        respond(admin::set_paused(&req.state().env, index(&req)?, false).await)
+    });
+    api.at("/webhooks").get(|req: tide::Request<State>| async move { respond(admin::webhooks(&req.state().env).await) });
+    api.at("/webhooks/reconcile").post(|req: tide::Request<State>| async move { respond(admin::reconcile_webhooks(&req.state().env).await) });
+    api.at("/webhooks/:table/:channel").delete(|req: tide::Request<State>| async move {
+        respond(admin::evict_webhooks(&req.state().env, req.param("table")?, req.param("channel")?, None).await)
+    });
+    api.at("/webhooks/:table/:channel/:user").delete(|req: tide::Request<State>| async move {
+        respond(admin::evict_webhooks(&req.state().env, req.param("table")?, req.param("channel")?, Some(req.param("user")?)).await)
+    });
+    api
+}
+
+fn index(req: &tide::Request<State>) -> tide::Result<usize> {
+    req.param("index")?.parse().map_err(|_| tide::Error::from_str(400, "the binding index isn't a number"))
+}
+
+fn respond<T: Serialize>(result: Result<T, BridgeError>) -> tide::Result {
+    let (status, body) = match result {
+        Ok(value) => (200, serde_json::to_value(value)?),
+        Err(err @ BridgeError::Config { .. }) => (400, serde_json::json!({ "error": err.to_string() })),
+        Err(err) => (500, serde_json::json!({ "error": err.to_string() })),
+    };
+    Ok(tide::Response::builder(status).body(tide::Body::from_json(&body)?).build())
+}
+
+struct RequireToken(Secret);
+
+#[async_trait::async_trait]
+impl tide::Middleware<State> for RequireToken {
+    async fn handle(&self, req: tide::Request<State>, next: tide::Next<'_, State>) -> tide::Result {
+        let given = match req.header("Authorization").and_then(|header| header.as_str().strip_prefix("Bearer ")) {
+            Some(given) => given,
+            None => return Ok(tide::Response::new(401)),
+        };
+        if !same(given.as_bytes(), self.0.expose().as_bytes()) { return Ok(tide::Response::new(401)) };
+        Ok(next.run(req).await)
+    }
+}
+
+/// Takes as long whatever part of the token is right, so the token can't be guessed a byte at a time
+fn same(given: &[u8], token: &[u8]) -> bool {
+    given.len() == token.len() && given.iter().zip(token).fold(0, |differs, (a, b)| differs | (a ^ b)) == 0
+}
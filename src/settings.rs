@@ -0,0 +1,419 @@
+use serde::{Serialize, Deserialize};
+use crate::config::{RawConfig, read_config_file};
+
+/// A string that never shows up in Debug or Display output. Use `expose` at the one place it's actually needed.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+impl Secret {
+    pub fn new(secret: String) -> Secret {
+        crate::logging::redact(&secret);
+        Secret(secret)
+    }
+    pub fn expose(&self) -> &str { &self.0 }
+}
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { f.write_str("<redacted>") }
+}
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { f.write_str("<redacted>") }
+}
+
+/// The shortest `admin_token` accepted, anything shorter is too easy to guess
+pub const MIN_ADMIN_TOKEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat { Text, Json }
+
+/// The `settings` section of config.json. Secrets can only be given as files here, so the config itself stays shareable.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct FileSettings {
+    guilded_email: Option<String>,
+    guilded_password_file: Option<String>,
+    discord_auth_file: Option<String>,
+    print_all_msg: Option<bool>,
+    storage: Option<String>,
+    storage_path: Option<String>,
+    json_storage_path: Option<String>,
+    storage_key_file: Option<String>,
+    matrix_homeserver: Option<String>,
+    matrix_server_name: Option<String>,
+    matrix_listen: Option<String>,
+    matrix_as_token_file: Option<String>,
+    matrix_hs_token_file: Option<String>,
+    irc_server: Option<String>,
+    irc_tls: Option<bool>,
+    irc_nick: Option<String>,
+    irc_sasl_user: Option<String>,
+    irc_sasl_password_file: Option<String>,
+    relay_concurrency: Option<usize>,
+    log_filter: Option<String>,
+    log_format: Option<String>,
+    http_listen: Option<String>,
+    admin_token_file: Option<String>,
+    admin_listen: Option<String>,
+    discord_admin_roles: Option<String>,
+    guilded_admin_roles: Option<String>,
+    discord_api: Option<String>,
+    guilded_api: Option<String>,
+    discord_cdn: Option<String>,
+    guilded_socket: Option<String>,
+    guilded_media: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+/// Everything the bridge needs to know before it starts, resolved from (lowest to highest priority)
+/// defaults, the `settings` section of the config file, env variables, `<env variable>_FILE` secret files and CLI flags.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// `--config`, `bridge_config`
+    pub config_path: String,
+    /// `--guilded-email`, `guilded_email`
+    pub guilded_email: Option<String>,
+    /// `--guilded-password-file`, `guilded_password_FILE`, `guilded_password`
+    pub guilded_password: Option<Secret>,
+    /// `--discord-auth-file`, `discord_auth_FILE`, `discord_auth`
+    pub discord_auth: Option<Secret>,
+    /// `--print-all-msg`, `print_all_msg`: log every Discord and Guilded frame, at trace level
+    pub print_all_msg: bool,
+    /// `--storage`, `storage`: `json` (default) or `sqlite`
+    pub storage: String,
+    /// `--storage-path`, `storage_path`: the directory holding the json files (default `.`),
+    /// or the sqlite database (default `bridge7573.sqlite`)
+    pub storage_path: String,
+    /// `--json-storage-path`, `json_storage_path`: the directory `migrate-storage` copies the json files from (default `.`)
+    pub json_storage_path: String,
+    /// `--storage-key-file`, `storage_key_FILE`, `storage_key`: base64 of 32 bytes to encrypt stored webhooks with
+    pub storage_key: Option<Secret>,
+    /// `--matrix-homeserver`, `matrix_homeserver`: the homeserver's client API url. Matrix is only bridged when it's set.
+    pub matrix_homeserver: Option<String>,
+    /// `--matrix-server-name`, `matrix_server_name`: the part after the `:` in the homeserver's user ids
+    pub matrix_server_name: Option<String>,
+    /// `--matrix-listen`, `matrix_listen`: where the application service listens for the homeserver (default `127.0.0.1:9573`)
+    pub matrix_listen: String,
+    /// `--matrix-as-token-file`, `matrix_as_token_FILE`, `matrix_as_token`
+    pub matrix_as_token: Option<Secret>,
+    /// `--matrix-hs-token-file`, `matrix_hs_token_FILE`, `matrix_hs_token`
+    pub matrix_hs_token: Option<Secret>,
+    /// `--irc-server`, `irc_server`: `host:port` of the IRC server. IRC is only bridged when it's set.
+    pub irc_server: Option<String>,
+    /// `--irc-tls` or `--irc-plaintext`, `irc_tls`: whether to connect with TLS (default true)
+    pub irc_tls: bool,
+    /// `--irc-nick`, `irc_nick`: the nick the bridge asks for (default `bridge7573`)
+    pub irc_nick: String,
+    /// `--irc-sasl-user`, `irc_sasl_user`: the account to log into with SASL (default the nick)
+    pub irc_sasl_user: Option<String>,
+    /// `--irc-sasl-password-file`, `irc_sasl_password_FILE`, `irc_sasl_password`: SASL is only used when it's set
+    pub irc_sasl_password: Option<Secret>,
+    /// `--relay-concurrency`, `relay_concurrency`: how many channels are relayed from at once (default 8).
+    /// Events from the same channel are always relayed one at a time, in order.
+    pub relay_concurrency: usize,
+    /// `--log-filter`, `log_filter`: which log events to show, like `info,bridge7573::irc=debug` (default `info`)
+    pub log_filter: String,
+    /// `--log-format`, `log_format`: `text` (default) or `json`, one object per line
+    pub log_format: LogFormat,
+    /// `--http-listen`, `http_listen`: where the bridge serves `/metrics`, `/healthz` and `/readyz`, like `127.0.0.1:9574`. Nothing's served when it's unset.
+    pub http_listen: Option<String>,
+    /// `--admin-token-file`, `admin_token_FILE`, `admin_token`: the bearer token for `/admin`, at least
+    /// `MIN_ADMIN_TOKEN` characters. The admin API is only served when it's set.
+    pub admin_token: Option<Secret>,
+    /// `--admin-listen`, `admin_listen`: where the admin API is served, apart from the monitoring endpoints so it can
+    /// stay private while those are scraped (default `127.0.0.1:9575`)
+    pub admin_listen: String,
+    /// `--discord-admin-roles`, `discord_admin_roles`: comma separated ids of the Discord roles that can use the
+    /// `!bridge` and `/bridge` commands that change things, besides whoever can manage the server.
+    pub discord_admin_roles: Vec<String>,
+    /// `--guilded-admin-roles`, `guilded_admin_roles`: the same for Guilded team roles, which are all there is to go by
+    /// there. Nobody can when it's unset.
+    pub guilded_admin_roles: Vec<String>,
+    /// `--discord-api`, `discord_api`: Discord's REST API (default `DISCORD_API`), for pointing the bridge at a test server
+    pub discord_api: String,
+    /// `--guilded-api`, `guilded_api`: Guilded's REST API (default `GUILDED_API`)
+    pub guilded_api: String,
+    /// `--discord-cdn`, `discord_cdn`: where Discord avatars are downloaded from (default `DISCORD_CDN`)
+    pub discord_cdn: String,
+    /// `--guilded-socket`, `guilded_socket`: Guilded's socket.io endpoint, query included (default `GUILDED_SOCKET`)
+    pub guilded_socket: String,
+    /// `--guilded-media`, `guilded_media`: the host Guilded webhooks and avatar uploads are on (default `GUILDED_MEDIA`)
+    pub guilded_media: String,
+    /// `--record`, `record`: a file to append every Discord and Guilded frame to, see `recording`
+    pub record: Option<String>,
+    /// `--replay`, `replay`: a file from `record` to go through instead of connecting to Discord and Guilded. The
+    /// bridge stops once it's all relayed.
+    pub replay: Option<String>,
+}
+
+/// The settings without which there's no bridge at all
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub guilded_email: String,
+    pub guilded_password: Secret,
+    pub discord_auth: Secret,
+}
+
+/// What the IRC connection needs, once `irc_server` is set
+#[derive(Debug, Clone)]
+pub struct IrcSettings {
+    pub server: String,
+    pub tls: bool,
+    pub nick: String,
+    /// SASL PLAIN account and password
+    pub sasl: Option<(String, Secret)>,
+}
+
+/// What the Matrix application service needs, once `matrix_homeserver` is set
+#[derive(Debug, Clone)]
+pub struct MatrixSettings {
+    pub homeserver: String,
+    pub server_name: String,
+    pub listen: String,
+    /// Sent to the homeserver with every request
+    pub as_token: Secret,
+    /// Expected from the homeserver with every transaction
+    pub hs_token: Secret,
+}
+
+impl Settings {
+    pub fn load(args: &[String]) -> Result<Settings, String> {
+        let config_path = flag_value(args, "--config").map(|s| s.to_owned())
+            .or_else(|| std::env::var("bridge_config").ok())
+            .unwrap_or_else(|| "config.json".to_owned());
+        //A broken config file is reported properly once the config itself is loaded
+        let file = read_config_file(&config_path).ok()
+            .and_then(|text| RawConfig::parse(&text).ok())
+            .map(|raw| raw.settings)
+            .unwrap_or_default();
+
+        let storage = flag_value(args, "--storage").map(|s| s.to_owned())
+            .or_else(|| std::env::var("storage").ok())
+            .or(file.storage)
+            .unwrap_or_else(|| "json".to_owned());
+
+        Ok(Settings {
+            guilded_email: flag_value(args, "--guilded-email").map(|s| s.to_owned())
+                .or_else(|| std::env::var("guilded_email").ok())
+                .or(file.guilded_email),
+            guilded_password: layered_secret(args, "--guilded-password-file", "guilded_password", file.guilded_password_file)?,
+            discord_auth: layered_secret(args, "--discord-auth-file", "discord_auth", file.discord_auth_file)?,
+            print_all_msg: args.iter().any(|arg| arg == "--print-all-msg")
+                || std::env::var("print_all_msg").is_ok()
+                || file.print_all_msg.unwrap_or(false),
+            storage_path: flag_value(args, "--storage-path").map(|s| s.to_owned())
+                .or_else(|| std::env::var("storage_path").ok())
+                .or(file.storage_path)
+                .unwrap_or_else(|| if storage == "sqlite" { "bridge7573.sqlite".to_owned() } else { ".".to_owned() }),
+            json_storage_path: layered_value(args, "--json-storage-path", "json_storage_path", file.json_storage_path)
+                .unwrap_or_else(|| ".".to_owned()),
+            storage,
+            storage_key: layered_secret(args, "--storage-key-file", "storage_key", file.storage_key_file)?,
+            matrix_homeserver: layered_value(args, "--matrix-homeserver", "matrix_homeserver", file.matrix_homeserver),
+            matrix_server_name: layered_value(args, "--matrix-server-name", "matrix_server_name", file.matrix_server_name),
+            matrix_listen: layered_value(args, "--matrix-listen", "matrix_listen", file.matrix_listen).unwrap_or_else(|| "127.0.0.1:9573".to_owned()),
+            matrix_as_token: layered_secret(args, "--matrix-as-token-file", "matrix_as_token", file.matrix_as_token_file)?,
+            matrix_hs_token: layered_secret(args, "--matrix-hs-token-file", "matrix_hs_token", file.matrix_hs_token_file)?,
+            irc_server: layered_value(args, "--irc-server", "irc_server", file.irc_server),
+            irc_tls: layered_switch(args, "--irc-tls", "--irc-plaintext", "irc_tls", file.irc_tls).unwrap_or(true),
+            irc_nick: layered_value(args, "--irc-nick", "irc_nick", file.irc_nick).unwrap_or_else(|| "bridge7573".to_owned()),
+            irc_sasl_user: layered_value(args, "--irc-sasl-user", "irc_sasl_user", file.irc_sasl_user),
+            irc_sasl_password: layered_secret(args, "--irc-sasl-password-file", "irc_sasl_password", file.irc_sasl_password_file)?,
+            relay_concurrency: match layered_value(args, "--relay-concurrency", "relay_concurrency", file.relay_concurrency.map(|limit| limit.to_string())) {
+                Some(limit) => limit.parse().ok().filter(|limit| *limit > 0).ok_or_else(|| format!("relay_concurrency {} isn't a number above 0", limit))?,
+                None => 8,
+            },
+            log_filter: layered_value(args, "--log-filter", "log_filter", file.log_filter).unwrap_or_else(|| "info".to_owned()),
+            log_format: match layered_value(args, "--log-format", "log_format", file.log_format).as_deref() {
+                None | Some("text") => LogFormat::Text,
+                Some("json") => LogFormat::Json,
+                Some(other) => return Err(format!("Unknown log_format {}, expected text or json", other)),
+            },
+            http_listen: layered_value(args, "--http-listen", "http_listen", file.http_listen),
+            admin_token: match layered_secret(args, "--admin-token-file", "admin_token", file.admin_token_file)? {
+                Some(token) if token.expose().trim().len() < MIN_ADMIN_TOKEN => return Err(format!("admin_token has to be at least {} characters", MIN_ADMIN_TOKEN)),
+                token => token,
+            },
+            admin_listen: layered_value(args, "--admin-listen", "admin_listen", file.admin_listen).unwrap_or_else(|| "127.0.0.1:9575".to_owned()),
+            discord_admin_roles: list(layered_value(args, "--discord-admin-roles", "discord_admin_roles", file.discord_admin_roles)),
+            guilded_admin_roles: list(layered_value(args, "--guilded-admin-roles", "guilded_admin_roles", file.guilded_admin_roles)),
+            discord_api: layered_value(args, "--discord-api", "discord_api", file.discord_api).unwrap_or_else(|| crate::discord::DISCORD_API.to_owned()).trim_end_matches('/').to_owned(),
+            guilded_api: layered_value(args, "--guilded-api", "guilded_api", file.guilded_api).unwrap_or_else(|| crate::guilded::GUILDED_API.to_owned()).trim_end_matches('/').to_owned(),
+            discord_cdn: layered_value(args, "--discord-cdn", "discord_cdn", file.discord_cdn).unwrap_or_else(|| crate::discord::DISCORD_CDN.to_owned()).trim_end_matches('/').to_owned(),
+            guilded_socket: layered_value(args, "--guilded-socket", "guilded_socket", file.guilded_socket).unwrap_or_else(|| crate::guilded::GUILDED_SOCKET.to_owned()),
+            guilded_media: layered_value(args, "--guilded-media", "guilded_media", file.guilded_media).unwrap_or_else(|| crate::guilded::GUILDED_MEDIA.to_owned()).trim_end_matches('/').to_owned(),
+            record: layered_value(args, "--record", "record", file.record),
+            replay: layered_value(args, "--replay", "replay", file.replay),
+            config_path,
+        })
+    }
+
+    pub fn credentials(&self) -> Result<Credentials, String> {
+        Ok(Credentials {
+            guilded_email: self.guilded_email.clone().ok_or("No guilded_email setting")?,
+            guilded_password: self.guilded_password.clone().ok_or("No guilded_password setting")?,
+            discord_auth: self.discord_auth.clone().ok_or("No discord_auth setting")?,
+        })
+    }
+
+    /// `None` when IRC isn't set up at all
+    pub fn irc(&self) -> Option<IrcSettings> {
+        Some(IrcSettings {
+            server: self.irc_server.clone()?,
+            tls: self.irc_tls,
+            nick: self.irc_nick.clone(),
+            sasl: self.irc_sasl_password.clone().map(|password| (self.irc_sasl_user.clone().unwrap_or_else(|| self.irc_nick.clone()), password)),
+        })
+    }
+
+    /// `None` when Matrix isn't set up at all, an error when it's only partly set up
+    pub fn matrix(&self) -> Result<Option<MatrixSettings>, String> {
+        let homeserver = match &self.matrix_homeserver { Some(homeserver) => homeserver, None => return Ok(None) };
+        Ok(Some(MatrixSettings {
+            homeserver: homeserver.trim_end_matches('/').to_owned(),
+            server_name: self.matrix_server_name.clone().ok_or("No matrix_server_name setting")?,
+            listen: self.matrix_listen.clone(),
+            as_token: self.matrix_as_token.clone().ok_or("No matrix_as_token setting")?,
+            hs_token: self.matrix_hs_token.clone().ok_or("No matrix_hs_token setting")?,
+        }))
+    }
+}
+
+pub fn flag_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1)).map(|s| &**s)
+}
+
+fn layered_value(args: &[String], flag: &str, env_var: &str, file_setting: Option<String>) -> Option<String> {
+    flag_value(args, flag).map(|s| s.to_owned())
+        .or_else(|| std::env::var(env_var).ok())
+        .or(file_setting)
+}
+
+/// Like `layered_value` for a yes or no setting, with a flag for each. The env variable is off when it's `false` or `0`.
+fn layered_switch(args: &[String], on_flag: &str, off_flag: &str, env_var: &str, file_setting: Option<bool>) -> Option<bool> {
+    args.iter().rev().find_map(|arg| if arg == on_flag { Some(true) } else if arg == off_flag { Some(false) } else { None })
+        .or_else(|| std::env::var(env_var).ok().map(|value| value != "false" && value != "0"))
+        .or(file_setting)
+}
+
+fn list(value: Option<String>) -> Vec<String> {
+    value.iter().flat_map(|value| value.split(',')).map(str::trim).filter(|item| !item.is_empty()).map(str::to_owned).collect()
+}
+
+fn layered_secret(args: &[String], flag: &str, env_var: &str, file_setting: Option<String>) -> Result<Option<Secret>, String> {
+    if let Some(path) = flag_value(args, flag) { return read_secret_file(path).map(Some) };
+    let from_file = std::env::var(format!("{}_FILE", env_var)).ok();
+    let from_env = std::env::var(env_var).ok();
+    match (from_file, from_env) {
+        (Some(_), Some(_)) => Err(format!("Both {} and {}_FILE are set, pick one", env_var, env_var)),
+        (Some(path), None) => read_secret_file(&path).map(Some),
+        (None, Some(secret)) => Ok(Some(Secret::new(secret))),
+        (None, None) => file_setting.map(|path| read_secret_file(&path)).transpose(),
+    }
+}
+
+pub fn read_secret_file(path: &str) -> Result<Secret, String> {
+    let secret = std::fs::read_to_string(path).map_err(|err| format!("Failed to read secret file {}: {}", path, err))?;
+    Ok(Secret::new(secret.trim_end_matches(['\r', '\n']).to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bridge7573_settings_{}_{}", name, std::process::id())).to_str().unwrap().to_owned();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    //Every test gets env variables of its own, the tests run side by side in one process
+    #[test]
+    fn flags_beat_env_beats_config_file() {
+        let file = Some("from file".to_owned());
+        assert_eq!(layered_value(&[], "--layer", "bridge7573_test_layer", file.clone()).as_deref(), Some("from file"));
+        std::env::set_var("bridge7573_test_layer", "from env");
+        assert_eq!(layered_value(&[], "--layer", "bridge7573_test_layer", file.clone()).as_deref(), Some("from env"));
+        assert_eq!(layered_value(&args(&["--layer", "from flag"]), "--layer", "bridge7573_test_layer", file).as_deref(), Some("from flag"));
+        std::env::remove_var("bridge7573_test_layer");
+
+        let config = temp_file("config.json", r#"{ "settings": { "guilded_email": "file@example.org", "relay_concurrency": 3 } }"#);
+        let settings = Settings::load(&args(&["--config", &config])).unwrap();
+        assert_eq!((settings.guilded_email.as_deref(), settings.relay_concurrency), (Some("file@example.org"), 3));
+        let settings = Settings::load(&args(&["--config", &config, "--guilded-email", "flag@example.org"])).unwrap();
+        assert_eq!(settings.guilded_email.as_deref(), Some("flag@example.org"));
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn refuses_relay_concurrency_0_from_every_layer() {
+        let err = Settings::load(&args(&["--config", "/nonexistent/config.json", "--relay-concurrency", "0"])).unwrap_err();
+        assert_eq!(err, "relay_concurrency 0 isn't a number above 0");
+        let config = temp_file("relay_concurrency.json", r#"{ "settings": { "relay_concurrency": 0 } }"#);
+        assert_eq!(Settings::load(&args(&["--config", &config])).unwrap_err(), err);
+        assert_eq!(Settings::load(&args(&["--config", &config, "--relay-concurrency", "2"])).unwrap().relay_concurrency, 2);
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn switches_layer_too() {
+        let switch = |args: &[String]| layered_switch(args, "--on", "--off", "bridge7573_test_switch", Some(false));
+        assert_eq!(switch(&[]), Some(false));
+        std::env::set_var("bridge7573_test_switch", "true");
+        assert_eq!(switch(&[]), Some(true));
+        assert_eq!(switch(&args(&["--off"])), Some(false));
+        std::env::set_var("bridge7573_test_switch", "0");
+        assert_eq!(switch(&args(&["--off", "--on"])), Some(true));
+        std::env::remove_var("bridge7573_test_switch");
+
+        let config = temp_file("irc_tls.json", r#"{ "settings": { "irc_tls": false } }"#);
+        assert!(!Settings::load(&args(&["--config", &config])).unwrap().irc_tls);
+        assert!(Settings::load(&args(&["--config", &config, "--irc-tls"])).unwrap().irc_tls);
+        std::fs::remove_file(config).unwrap();
+    }
+
+    #[test]
+    fn reads_secrets_from_each_layer() {
+        let flag_file = temp_file("flag_secret", "from flag file\n");
+        let env_file = temp_file("env_secret", "from env file\r\n");
+        let config_file = temp_file("config_secret", "from config file");
+        let secret = |args: &[String]| layered_secret(args, "--secret-file", "bridge7573_test_secret", Some(config_file.clone()))
+            .map(|secret| secret.map(|secret| secret.expose().to_owned()));
+
+        assert_eq!(secret(&[]).unwrap().as_deref(), Some("from config file"));
+        std::env::set_var("bridge7573_test_secret_FILE", &env_file);
+        assert_eq!(secret(&[]).unwrap().as_deref(), Some("from env file"));
+        std::env::set_var("bridge7573_test_secret", "from env");
+        assert_eq!(secret(&[]).unwrap_err(), "Both bridge7573_test_secret and bridge7573_test_secret_FILE are set, pick one");
+        assert_eq!(secret(&args(&["--secret-file", &flag_file])).unwrap().as_deref(), Some("from flag file"));
+        std::env::remove_var("bridge7573_test_secret_FILE");
+        assert_eq!(secret(&[]).unwrap().as_deref(), Some("from env"));
+        std::env::remove_var("bridge7573_test_secret");
+        assert!(layered_secret(&[], "--secret-file", "bridge7573_test_secret", Some("/nonexistent/secret".to_owned())).is_err());
+        for path in [flag_file, env_file, config_file] { std::fs::remove_file(path).unwrap() };
+    }
+
+    #[test]
+    fn refuses_short_admin_tokens() {
+        let short = temp_file("short_admin_token", "   \n");
+        let err = Settings::load(&args(&["--config", "/nonexistent/config.json", "--admin-token-file", &short])).unwrap_err();
+        assert_eq!(err, "admin_token has to be at least 16 characters");
+        let long = temp_file("long_admin_token", "a-long-enough-admin-token\n");
+        let settings = Settings::load(&args(&["--config", "/nonexistent/config.json", "--admin-token-file", &long])).unwrap();
+        assert_eq!(settings.admin_token.unwrap().expose(), "a-long-enough-admin-token");
+        assert_eq!(settings.admin_listen, "127.0.0.1:9575");
+        for path in [short, long] { std::fs::remove_file(path).unwrap() };
+    }
+
+    #[test]
+    fn secrets_never_print() {
+        let secret = Secret::new("hunter2-but-longer".to_owned());
+        assert_eq!(format!("{:?}", secret), "<redacted>");
+        assert_eq!(format!("{}", secret), "<redacted>");
+        assert_eq!(secret.expose(), "hunter2-but-longer");
+        let irc = IrcSettings { server: "irc.example.org".to_owned(), tls: true, nick: "bridge7573".to_owned(), sasl: Some(("bridge7573".to_owned(), secret)) };
+        assert!(!format!("{:?}", irc).contains("hunter2"));
+    }
+}
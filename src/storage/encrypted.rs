@@ -0,0 +1,186 @@
+use super::*;
+use crate::settings::{Secret, read_secret_file};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, AeadCore, Nonce};
+use chacha20poly1305::aead::{Aead, OsRng};
+use std::sync::Arc;
+
+const PREFIX: &str = "enc:v1:";
+
+/// Encrypts webhooks on their way into another storage and decrypts them on the way back out.
+///
+/// Stored webhooks are `enc:v1:` followed by base64 of the nonce and ciphertext. Anything without the prefix was
+/// stored before encryption was turned on and is passed through as is, until `rotate-key` rewrites it.
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    cipher: Option<ChaCha20Poly1305>,
+}
+
+impl EncryptedStorage {
+    /// `key` is 32 bytes of base64. Without one webhooks are stored in plain text.
+    pub fn new(inner: Arc<dyn Storage>, key: Option<&Secret>) -> Result<EncryptedStorage, BridgeError> {
+        let cipher = match key {
+            Some(key) => {
+                let key = base64::decode(key.expose().trim()).map_err(|_| BridgeError::config("storage_key isn't base64"))?;
+                Some(ChaCha20Poly1305::new_from_slice(&key).map_err(|_| BridgeError::config("storage_key has to be 32 bytes"))?)
+            },
+            None => None,
+        };
+        Ok(EncryptedStorage { inner, cipher })
+    }
+
+    fn encrypt(&self, webhook: &str) -> Result<String, BridgeError> {
+        let cipher = if let Some(cipher) = &self.cipher { cipher } else { return Ok(webhook.to_owned()) };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(cipher.encrypt(&nonce, webhook.as_bytes()).map_err(|_| BridgeError::storage("Failed to encrypt webhook"))?);
+        Ok(format!("{}{}", PREFIX, base64::encode(sealed)))
+    }
+
+    fn decrypt(&self, stored: &str) -> Result<String, BridgeError> {
+        let sealed = if let Some(sealed) = stored.strip_prefix(PREFIX) { sealed } else { return Ok(stored.to_owned()) };
+        let cipher = self.cipher.as_ref().ok_or_else(|| BridgeError::config("Stored webhooks are encrypted, but there's no storage_key setting"))?;
+        let sealed = base64::decode(sealed).map_err(|_| BridgeError::storage("Encrypted webhook isn't base64"))?;
+        if sealed.len() < 12 { return Err(BridgeError::storage("Encrypted webhook is too short")) };
+        let (nonce, ciphertext) = sealed.split_at(12);
+        let webhook = cipher.decrypt(Nonce::from_slice(nonce), ciphertext).map_err(|_| BridgeError::config("Failed to decrypt webhook, wrong storage_key?"))?;
+        String::from_utf8(webhook).map_err(|_| BridgeError::storage("Decrypted webhook isn't utf8"))
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for EncryptedStorage {
+    async fn get_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<Option<String>, BridgeError> {
+        self.inner.get_webhook(table, channel, user).await?.map(|stored| self.decrypt(&stored)).transpose()
+    }
+    async fn put_webhook(&self, table: &WebhookTable, channel: &str, user: &str, webhook: &str) -> Result<(), BridgeError> {
+        let stored = self.encrypt(webhook)?;
+        self.inner.put_webhook(table, channel, user, &stored).await
+    }
+    async fn remove_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<(), BridgeError> {
+        self.inner.remove_webhook(table, channel, user).await
+    }
+    async fn webhooks(&self, table: &WebhookTable) -> Result<Webhooks, BridgeError> {
+        let mut webhooks = self.inner.webhooks(table).await?;
+        for users in webhooks.values_mut() {
+            for webhook in users.values_mut() { *webhook = self.decrypt(webhook)?; }
+        }
+        Ok(webhooks)
+    }
+    async fn webhook_tables(&self) -> Result<Vec<WebhookTable>, BridgeError> { self.inner.webhook_tables().await }
+
+    async fn get_mapping(&self, kind: &str, key: &str) -> Result<Option<String>, BridgeError> { self.inner.get_mapping(kind, key).await }
+    async fn put_mapping(&self, kind: &str, key: &str, value: &str) -> Result<(), BridgeError> { self.inner.put_mapping(kind, key, value).await }
+    async fn remove_mapping(&self, kind: &str, key: &str) -> Result<(), BridgeError> { self.inner.remove_mapping(kind, key).await }
+    async fn mappings(&self, kind: &str) -> Result<BTreeMap<String, String>, BridgeError> { self.inner.mappings(kind).await }
+    async fn mapping_kinds(&self) -> Result<Vec<String>, BridgeError> { self.inner.mapping_kinds().await }
+    async fn put_all(&self, contents: &Contents) -> Result<(), BridgeError> {
+        let mut sealed = Contents { webhooks: BTreeMap::new(), mappings: contents.mappings.clone() };
+        for (table, webhooks) in &contents.webhooks {
+            let mut stored = webhooks.clone();
+            for users in stored.values_mut() {
+                for webhook in users.values_mut() { *webhook = self.encrypt(webhook)?; }
+            }
+            sealed.webhooks.insert(table.clone(), stored);
+        }
+        self.inner.put_all(&sealed).await
+    }
+    async fn flush(&self) -> Result<(), BridgeError> { self.inner.flush().await }
+}
+
+/// `bridge7573 rotate-key --new-key-file <path>`: re-encrypts every stored webhook with the new key.
+/// Also how encryption gets turned on for data stored without a key.
+pub async fn rotate_key(settings: &Settings, new_key_file: Option<&str>) -> Result<(), BridgeError> {
+    let new_key = read_secret_file(new_key_file.ok_or_else(|| BridgeError::config("rotate-key needs --new-key-file"))?).map_err(BridgeError::config)?;
+    let inner = Arc::<dyn Storage>::from(open_plain_storage(settings).await?);
+    rotate(inner, settings.storage_key.as_ref(), &new_key).await?;
+    println!("Webhooks are now encrypted with the new key, switch the storage_key setting over to it");
+    Ok(())
+}
+
+async fn rotate(inner: Arc<dyn Storage>, old_key: Option<&Secret>, new_key: &Secret) -> Result<(), BridgeError> {
+    let old = EncryptedStorage::new(inner.clone(), old_key)?;
+    let new = EncryptedStorage::new(inner.clone(), Some(new_key))?;
+    //Decrypt everything before writing anything, a wrong old key shouldn't leave a mix behind.
+    //The json files are saved one after the other, so webhooks already under the new key are from a rotation that
+    //got cut off between the two and are taken as they are, running it again finishes the job.
+    let mut rotated = Contents::default();
+    for table in inner.webhook_tables().await? {
+        let mut webhooks = inner.webhooks(&table).await?;
+        for users in webhooks.values_mut() {
+            for webhook in users.values_mut() {
+                *webhook = old.decrypt(webhook).or_else(|err| new.decrypt(webhook).map_err(|_| err))?;
+            }
+        }
+        rotated.webhooks.insert(table, webhooks);
+    }
+    new.put_all(&rotated).await
+}
+
+/// A fresh 32 byte key, in the format `storage_key` expects
+pub fn generate_key() -> String {
+    base64::encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> Arc<dyn Storage> {
+        let path = std::env::temp_dir().join(format!("bridge7573-encrypted-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Arc::new(SqliteStorage::open(path.to_str().unwrap()).unwrap())
+    }
+
+    fn table() -> WebhookTable { WebhookTable::new("guilded", "discord") }
+
+    #[async_std::test]
+    async fn round_trips_without_storing_plain_text() {
+        let inner = temp_db("round-trip");
+        let storage = EncryptedStorage::new(inner.clone(), Some(&Secret::new(generate_key()))).unwrap();
+        storage.put_webhook(&table(), "111", "user", "https://discord.com/api/webhooks/1/token").await.unwrap();
+        assert_eq!(storage.get_webhook(&table(), "111", "user").await.unwrap().as_deref(), Some("https://discord.com/api/webhooks/1/token"));
+        assert_eq!(storage.webhooks(&table()).await.unwrap()["111"]["user"], "https://discord.com/api/webhooks/1/token");
+        let stored = inner.get_webhook(&table(), "111", "user").await.unwrap().unwrap();
+        assert!(stored.starts_with(PREFIX) && !stored.contains("token"), "{}", stored);
+    }
+
+    #[async_std::test]
+    async fn passes_plain_text_webhooks_through() {
+        let inner = temp_db("plain");
+        inner.put_webhook(&table(), "111", "user", "https://discord.com/api/webhooks/1/token").await.unwrap();
+        let storage = EncryptedStorage::new(inner, Some(&Secret::new(generate_key()))).unwrap();
+        assert_eq!(storage.get_webhook(&table(), "111", "user").await.unwrap().as_deref(), Some("https://discord.com/api/webhooks/1/token"));
+    }
+
+    #[async_std::test]
+    async fn refuses_the_wrong_key() {
+        let inner = temp_db("wrong-key");
+        EncryptedStorage::new(inner.clone(), Some(&Secret::new(generate_key()))).unwrap()
+            .put_webhook(&table(), "111", "user", "webhook").await.unwrap();
+        let err = EncryptedStorage::new(inner.clone(), Some(&Secret::new(generate_key()))).unwrap()
+            .get_webhook(&table(), "111", "user").await.unwrap_err();
+        assert!(err.to_string().contains("wrong storage_key"), "{}", err);
+        assert!(EncryptedStorage::new(inner, None).unwrap().get_webhook(&table(), "111", "user").await.is_err());
+    }
+
+    #[async_std::test]
+    async fn rotates_every_webhook_to_the_new_key() {
+        let inner = temp_db("rotate");
+        let (old_key, new_key) = (Secret::new(generate_key()), Secret::new(generate_key()));
+        inner.put_webhook(&table(), "111", "plain", "plain webhook").await.unwrap();
+        EncryptedStorage::new(inner.clone(), Some(&old_key)).unwrap().put_webhook(&table(), "111", "old", "old webhook").await.unwrap();
+        EncryptedStorage::new(inner.clone(), Some(&new_key)).unwrap().put_webhook(&table(), "222", "new", "new webhook").await.unwrap();
+
+        rotate(inner.clone(), Some(&old_key), &new_key).await.unwrap();
+        let rotated = EncryptedStorage::new(inner.clone(), Some(&new_key)).unwrap().webhooks(&table()).await.unwrap();
+        assert_eq!(rotated["111"]["plain"], "plain webhook");
+        assert_eq!(rotated["111"]["old"], "old webhook");
+        assert_eq!(rotated["222"]["new"], "new webhook");
+        assert!(inner.webhooks(&table()).await.unwrap().values().flat_map(|users| users.values()).all(|stored| stored.starts_with(PREFIX)));
+
+        //Nothing is written when any webhook can't be decrypted
+        let before = inner.contents().await.unwrap();
+        assert!(rotate(inner.clone(), Some(&Secret::new(generate_key())), &Secret::new(generate_key())).await.is_err());
+        assert_eq!(inner.contents().await.unwrap(), before);
+    }
+}
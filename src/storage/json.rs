@@ -0,0 +1,238 @@
+use super::*;
+use crate::persist;
+use async_std::sync::Mutex;
+use serde::{Serialize, Deserialize};
+use serde_json::Value as JsValue;
+
+/// The original gd_data.json and dg_data.json, kept entirely in memory and rewritten on every change
+pub struct JsonStorage {
+    gd_path: String,
+    dg_path: String,
+    gd: Mutex<DataFile>,
+    dg: Mutex<DataFile>,
+}
+
+/// Bump this and add a step to `upgrade` whenever the layout of `DataFile` changes
+const CURRENT_VERSION: u64 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct DataFile {
+    version: u64,
+    #[serde(default)]
+    webhooks: Webhooks,
+    #[serde(default, skip_serializing_if = "Webhooks::is_empty")]
+    discord_webhooks: Webhooks,
+    #[serde(default, skip_serializing_if = "Webhooks::is_empty")]
+    guilded_webhooks: Webhooks,
+    /// Webhook tables for any other pair of platforms, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    tables: BTreeMap<String, Webhooks>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    mappings: BTreeMap<String, BTreeMap<String, String>>,
+}
+impl Default for DataFile {
+    fn default() -> Self {
+        DataFile { version: CURRENT_VERSION, webhooks: Webhooks::new(), discord_webhooks: Webhooks::new(), guilded_webhooks: Webhooks::new(), tables: BTreeMap::new(), mappings: BTreeMap::new() }
+    }
+}
+
+/// Brings a data file written by any earlier version up to `CURRENT_VERSION`, one version at a time
+fn upgrade(mut data: JsValue) -> Result<JsValue, String> {
+    let object = data.as_object_mut().ok_or("not a json object")?;
+    let mut version = match object.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or("version isn't a number")?,
+    };
+    if version > CURRENT_VERSION { return Err(format!("version {} was written by a newer bridge7573, this one only knows up to {}", version, CURRENT_VERSION)) };
+    while version < CURRENT_VERSION {
+        match version {
+            //v0 had no version field, and the same-platform tables were added to it without one
+            0 => (),
+            _ => unreachable!(),
+        }
+        version += 1;
+    }
+    object.insert("version".to_owned(), version.into());
+    Ok(data)
+}
+
+impl DataFile {
+    fn table(&mut self, table: &WebhookTable) -> &mut Webhooks {
+        match table.name() {
+            "discord_to_guilded" | "guilded_to_discord" => &mut self.webhooks,
+            "discord_to_discord" => &mut self.discord_webhooks,
+            "guilded_to_guilded" => &mut self.guilded_webhooks,
+            other => self.tables.entry(other.to_owned()).or_default(),
+        }
+    }
+
+    /// Names of the non-empty tables in this file, given the user's platform the file is for
+    fn table_names(&self, user_platform: &str) -> Vec<WebhookTable> {
+        let mut names = vec![];
+        for (channel_platform, webhooks) in [("discord", &self.discord_webhooks), ("guilded", &self.guilded_webhooks)] {
+            let legacy = if channel_platform == user_platform { webhooks } else { &self.webhooks };
+            if !legacy.is_empty() { names.push(WebhookTable::new(user_platform, channel_platform)) }
+        }
+        names.extend(self.tables.keys().cloned().map(WebhookTable::from_name));
+        names
+    }
+}
+
+async fn save(path: &str, file: &DataFile) -> Result<(), BridgeError> {
+    persist::save_json(path, file).await.map_err(|err| BridgeError::storage(format!("Failed to write {}: {}", path, err)))
+}
+
+impl JsonStorage {
+    /// Opens the data files in `dir`
+    pub async fn open(dir: &str) -> Result<JsonStorage, BridgeError> {
+        let gd_path = format!("{}/gd_data.json", dir.trim_end_matches('/'));
+        let dg_path = format!("{}/dg_data.json", dir.trim_end_matches('/'));
+        Ok(JsonStorage {
+            gd: Mutex::new(persist::load_json(&gd_path, upgrade).await.map_err(BridgeError::storage)?),
+            dg: Mutex::new(persist::load_json(&dg_path, upgrade).await.map_err(BridgeError::storage)?),
+            gd_path, dg_path,
+        })
+    }
+
+    /// Webhooks made for discord users live in dg_data.json, everything else in gd_data.json
+    fn file(&self, table: &WebhookTable) -> (&Mutex<DataFile>, &str) {
+        if table.name().starts_with("discord_to_") { (&self.dg, &self.dg_path) } else { (&self.gd, &self.gd_path) }
+    }
+}
+
+#[async_trait::async_trait]
+impl Storage for JsonStorage {
+    async fn get_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<Option<String>, BridgeError> {
+        let (file, _) = self.file(table);
+        Ok(file.lock().await.table(table).get(channel).and_then(|users| users.get(user)).cloned())
+    }
+    async fn put_webhook(&self, table: &WebhookTable, channel: &str, user: &str, webhook: &str) -> Result<(), BridgeError> {
+        let (file, path) = self.file(table);
+        let mut file = file.lock().await;
+        file.table(table).entry(channel.to_owned()).or_default().insert(user.to_owned(), webhook.to_owned());
+        save(path, &file).await?;
+        Ok(())
+    }
+    async fn remove_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<(), BridgeError> {
+        let (file, path) = self.file(table);
+        let mut file = file.lock().await;
+        if let Some(users) = file.table(table).get_mut(channel) { users.remove(user); }
+        save(path, &file).await?;
+        Ok(())
+    }
+    async fn webhooks(&self, table: &WebhookTable) -> Result<Webhooks, BridgeError> {
+        let (file, _) = self.file(table);
+        Ok(file.lock().await.table(table).clone())
+    }
+    async fn webhook_tables(&self) -> Result<Vec<WebhookTable>, BridgeError> {
+        let mut tables = self.dg.lock().await.table_names("discord");
+        tables.extend(self.gd.lock().await.table_names("guilded"));
+        Ok(tables)
+    }
+
+    //Mappings aren't tied to a direction, they all live in gd_data.json
+    async fn get_mapping(&self, kind: &str, key: &str) -> Result<Option<String>, BridgeError> {
+        Ok(self.gd.lock().await.mappings.get(kind).and_then(|values| values.get(key)).cloned())
+    }
+    async fn put_mapping(&self, kind: &str, key: &str, value: &str) -> Result<(), BridgeError> {
+        let mut file = self.gd.lock().await;
+        file.mappings.entry(kind.to_owned()).or_default().insert(key.to_owned(), value.to_owned());
+        save(&self.gd_path, &file).await?;
+        Ok(())
+    }
+    async fn remove_mapping(&self, kind: &str, key: &str) -> Result<(), BridgeError> {
+        let mut file = self.gd.lock().await;
+        if let Some(values) = file.mappings.get_mut(kind) { values.remove(key); }
+        save(&self.gd_path, &file).await?;
+        Ok(())
+    }
+    async fn mappings(&self, kind: &str) -> Result<BTreeMap<String, String>, BridgeError> {
+        Ok(self.gd.lock().await.mappings.get(kind).cloned().unwrap_or_default())
+    }
+    async fn mapping_kinds(&self) -> Result<Vec<String>, BridgeError> {
+        Ok(self.gd.lock().await.mappings.iter().filter(|(_, values)| !values.is_empty()).map(|(kind, _)| kind.clone()).collect())
+    }
+    //Each file is saved once, after everything headed for it is in
+    async fn put_all(&self, contents: &Contents) -> Result<(), BridgeError> {
+        let mut dg = self.dg.lock().await;
+        let mut gd = self.gd.lock().await;
+        let (mut dg_changed, mut gd_changed) = (false, false);
+        for (table, webhooks) in &contents.webhooks {
+            let (file, changed) = if self.file(table).1 == self.dg_path { (&mut *dg, &mut dg_changed) } else { (&mut *gd, &mut gd_changed) };
+            for (channel, users) in webhooks {
+                file.table(table).entry(channel.clone()).or_default().extend(users.iter().map(|(user, webhook)| (user.clone(), webhook.clone())));
+            }
+            *changed = true;
+        }
+        for (kind, mappings) in &contents.mappings {
+            gd.mappings.entry(kind.clone()).or_default().extend(mappings.iter().map(|(key, value)| (key.clone(), value.clone())));
+            gd_changed = true;
+        }
+        if dg_changed { save(&self.dg_path, &dg).await?; }
+        if gd_changed { save(&self.gd_path, &gd).await?; }
+        Ok(())
+    }
+    //Every change is saved as it's made, so this only has to wait out a save that's still going
+    async fn flush(&self) -> Result<(), BridgeError> {
+        let _dg = self.dg.lock().await;
+        let _gd = self.gd.lock().await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_fixture(raw: &str) -> Result<DataFile, String> {
+        upgrade(serde_json::from_str(raw).unwrap()).and_then(|upgraded| serde_json::from_value(upgraded).map_err(|err| err.to_string()))
+    }
+
+    #[test]
+    fn upgrades_v0_gd_data() {
+        let mut data = load_fixture(include_str!("../../tests/fixtures/gd_data.v0.json")).unwrap();
+        assert_eq!(data.version, CURRENT_VERSION);
+        assert_eq!(data.table(&WebhookTable::new("guilded", "discord"))["111"]["guilded_user"], "https://discord.com/api/webhooks/1/token");
+        assert!(data.table(&WebhookTable::new("guilded", "guilded")).is_empty());
+    }
+
+    #[test]
+    fn upgrades_v0_dg_data_with_same_platform_webhooks() {
+        let mut data = load_fixture(include_str!("../../tests/fixtures/dg_data.v0.json")).unwrap();
+        assert_eq!(data.version, CURRENT_VERSION);
+        assert_eq!(data.table(&WebhookTable::new("discord", "guilded"))["guilded_channel"]["222"], "https://media.guilded.gg/webhooks/2/token");
+        assert_eq!(data.table(&WebhookTable::new("discord", "discord"))["333"]["222"], "https://discord.com/api/webhooks/3/token");
+    }
+
+    #[test]
+    fn loads_v1_data() {
+        let mut data = load_fixture(include_str!("../../tests/fixtures/gd_data.v1.json")).unwrap();
+        assert_eq!(data.version, 1);
+        assert_eq!(data.table(&WebhookTable::new("guilded", "discord"))["111"]["guilded_user"], "https://discord.com/api/webhooks/1/token");
+        assert_eq!(data.mappings["message"]["guilded_message"], "discord_message");
+
+        let mut data = load_fixture(include_str!("../../tests/fixtures/dg_data.v1.json")).unwrap();
+        assert_eq!(data.table(&WebhookTable::new("discord", "discord"))["333"]["222"], "https://discord.com/api/webhooks/3/token");
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let err = upgrade(serde_json::json!({ "version": CURRENT_VERSION + 1, "webhooks": {} })).unwrap_err();
+        assert!(err.contains("newer"), "{}", err);
+    }
+
+    #[test]
+    fn keeps_other_platforms_in_their_own_tables() {
+        let mut data = load_fixture(include_str!("../../tests/fixtures/gd_data.v1.json")).unwrap();
+        data.table(&WebhookTable::new("guilded", "matrix")).entry("!room".to_owned()).or_default().insert("guilded_user".to_owned(), "hook".to_owned());
+        let names = data.table_names("guilded").into_iter().map(|table| table.name().to_owned()).collect::<Vec<_>>();
+        assert_eq!(names, ["guilded_to_discord", "guilded_to_matrix"]);
+        assert_eq!(serde_json::to_value(&data).unwrap()["tables"]["guilded_to_matrix"]["!room"]["guilded_user"], "hook");
+    }
+
+    #[test]
+    fn saves_current_version() {
+        let saved = serde_json::to_value(DataFile::default()).unwrap();
+        assert_eq!(saved["version"], CURRENT_VERSION);
+    }
+}
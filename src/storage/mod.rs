@@ -0,0 +1,150 @@
+use crate::error::*;
+use crate::settings::Settings;
+use std::collections::BTreeMap;
+
+mod json;
+mod sqlite;
+mod encrypted;
+pub use json::JsonStorage;
+pub use sqlite::SqliteStorage;
+pub use encrypted::{EncryptedStorage, rotate_key, generate_key};
+use std::sync::Arc;
+
+/// Which webhooks a stored webhook belongs to. Each table maps a channel on one platform and a user from another
+/// (or the same) platform to a webhook that posts into that channel as that user.
+///
+/// Named `<user's platform>_to_<channel's platform>`, like `discord_to_guilded`.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct WebhookTable(String);
+impl WebhookTable {
+    pub fn new(user_platform: &str, channel_platform: &str) -> WebhookTable {
+        WebhookTable(format!("{}_to_{}", user_platform, channel_platform))
+    }
+    pub fn from_name(name: String) -> WebhookTable { WebhookTable(name) }
+    pub fn name(&self) -> &str { &self.0 }
+}
+
+/// Channel -> user -> webhook
+pub type Webhooks = BTreeMap<String, BTreeMap<String, String>>;
+
+/// A batch of webhooks and mappings to store in one go, see `Storage::put_all`
+#[derive(Default, Debug, PartialEq)]
+pub struct Contents {
+    pub webhooks: BTreeMap<WebhookTable, Webhooks>,
+    /// Kind -> key -> value
+    pub mappings: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// Everything the bridge remembers between runs.
+///
+/// Mappings are plain string key/value tables, grouped by `kind`, for whatever else needs remembering later on
+/// (ignored users, cached users...).
+#[async_trait::async_trait]
+pub trait Storage: Send + Sync {
+    async fn get_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<Option<String>, BridgeError>;
+    async fn put_webhook(&self, table: &WebhookTable, channel: &str, user: &str, webhook: &str) -> Result<(), BridgeError>;
+    async fn remove_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<(), BridgeError>;
+    async fn webhooks(&self, table: &WebhookTable) -> Result<Webhooks, BridgeError>;
+    /// Every table with at least one webhook in it
+    async fn webhook_tables(&self) -> Result<Vec<WebhookTable>, BridgeError>;
+
+    async fn get_mapping(&self, kind: &str, key: &str) -> Result<Option<String>, BridgeError>;
+    async fn put_mapping(&self, kind: &str, key: &str, value: &str) -> Result<(), BridgeError>;
+    async fn remove_mapping(&self, kind: &str, key: &str) -> Result<(), BridgeError>;
+    async fn mappings(&self, kind: &str) -> Result<BTreeMap<String, String>, BridgeError>;
+    /// Every kind with at least one mapping in it
+    async fn mapping_kinds(&self) -> Result<Vec<String>, BridgeError>;
+
+    /// Adds or replaces everything in `contents`. Either all of it is stored or, on an error, none of it is.
+    async fn put_all(&self, contents: &Contents) -> Result<(), BridgeError>;
+    /// Everything stored, the other way around from `put_all`
+    async fn contents(&self) -> Result<Contents, BridgeError> {
+        let mut contents = Contents::default();
+        for table in self.webhook_tables().await? {
+            let webhooks = self.webhooks(&table).await?;
+            contents.webhooks.insert(table, webhooks);
+        }
+        for kind in self.mapping_kinds().await? {
+            let mappings = self.mappings(&kind).await?;
+            contents.mappings.insert(kind, mappings);
+        }
+        Ok(contents)
+    }
+
+    /// Makes sure everything written so far is on disk, before the bridge exits
+    async fn flush(&self) -> Result<(), BridgeError> { Ok(()) }
+
+    /// Whether storage can still be read, for `/healthz`
+    async fn check(&self) -> Result<(), BridgeError> {
+        self.get_mapping("health", "check").await.map(|_| ())
+    }
+}
+
+/// The configured backend, encrypting webhooks if there's a `storage_key`
+pub async fn open_storage(settings: &Settings) -> Result<Box<dyn Storage>, BridgeError> {
+    let plain = open_plain_storage(settings).await?;
+    Ok(Box::new(EncryptedStorage::new(Arc::from(plain), settings.storage_key.as_ref())?))
+}
+
+async fn open_plain_storage(settings: &Settings) -> Result<Box<dyn Storage>, BridgeError> {
+    match &*settings.storage {
+        "json" => Ok(Box::new(JsonStorage::open(&settings.storage_path).await?)),
+        "sqlite" => Ok(Box::new(SqliteStorage::open(&settings.storage_path)?)),
+        other => Err(BridgeError::config(format!("Unknown storage backend {}, expected json or sqlite", other))),
+    }
+}
+
+/// `bridge7573 migrate-storage`: copies everything in the json files from `json_storage_path` into the configured sqlite database
+pub async fn migrate_storage(settings: &Settings) -> Result<(), BridgeError> {
+    if settings.storage != "sqlite" { return Err(BridgeError::config("migrate-storage copies the json files into sqlite, set storage to sqlite first")) };
+    let dir = settings.json_storage_path.trim_end_matches('/');
+    if !["gd_data.json", "dg_data.json"].iter().any(|file| std::path::Path::new(&format!("{}/{}", dir, file)).exists()) {
+        return Err(BridgeError::config(format!("There's no gd_data.json or dg_data.json in {} to migrate, set json_storage_path to where they are", dir)));
+    }
+    //Webhooks are copied as stored, encrypted or not
+    let from = JsonStorage::open(dir).await?;
+    let to = SqliteStorage::open(&settings.storage_path)?;
+    copy_storage(&from, &to).await
+}
+
+/// Copies everything in `from` into `to` at once, so a failed copy can simply be run again
+pub async fn copy_storage(from: &dyn Storage, to: &dyn Storage) -> Result<(), BridgeError> {
+    let contents = from.contents().await?;
+    to.put_all(&contents).await?;
+    for (table, webhooks) in &contents.webhooks {
+        println!("{}: {} webhooks", table.name(), webhooks.values().map(|users| users.len()).sum::<usize>());
+    }
+    for (kind, mappings) in &contents.mappings {
+        println!("{}: {} mappings", kind, mappings.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bridge7573-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[async_std::test]
+    async fn copies_webhooks_and_mappings_from_json_into_sqlite() {
+        let dir = temp_path("copy-json");
+        std::fs::create_dir(&dir).unwrap();
+        let json = JsonStorage::open(&dir).await.unwrap();
+        json.put_webhook(&WebhookTable::new("guilded", "discord"), "111", "guilded_user", "hook1").await.unwrap();
+        json.put_webhook(&WebhookTable::new("discord", "matrix"), "!room", "222", "hook2").await.unwrap();
+        json.put_mapping("message", "guilded_message", "discord_message").await.unwrap();
+        json.put_mapping("ignored_users", "discord:222", "true").await.unwrap();
+
+        let sqlite = SqliteStorage::open(&temp_path("copy-sqlite")).unwrap();
+        copy_storage(&json, &sqlite).await.unwrap();
+        assert_eq!(sqlite.contents().await.unwrap(), json.contents().await.unwrap());
+        assert_eq!(sqlite.get_mapping("ignored_users", "discord:222").await.unwrap().as_deref(), Some("true"));
+        assert_eq!(sqlite.get_webhook(&WebhookTable::new("discord", "matrix"), "!room", "222").await.unwrap().as_deref(), Some("hook2"));
+    }
+}
@@ -0,0 +1,177 @@
+use super::*;
+use rusqlite::{Connection, OptionalExtension, params};
+use std::sync::Mutex;
+
+/// Everything in one embedded sqlite database, changes only touch the rows involved
+pub struct SqliteStorage {
+    db: Mutex<Connection>,
+}
+
+/// `MIGRATIONS[n]` takes a database from `PRAGMA user_version` n to n + 1. Only ever append to this.
+const MIGRATIONS: &[&str] = &[
+    //Databases made before versioning already have these tables with a user_version of 0
+    "
+            CREATE TABLE IF NOT EXISTS webhooks (
+                webhook_table TEXT NOT NULL,
+                channel TEXT NOT NULL,
+                user TEXT NOT NULL,
+                webhook TEXT NOT NULL,
+                PRIMARY KEY (webhook_table, channel, user)
+            );
+            CREATE TABLE IF NOT EXISTS mappings (
+                kind TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (kind, key)
+            );
+    ",
+];
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, BridgeError> {
+        let mut db = Connection::open(path)?;
+        db.execute_batch("PRAGMA journal_mode = WAL;")?;
+        migrate(&mut db)?;
+        Ok(SqliteStorage { db: Mutex::new(db) })
+    }
+}
+
+fn migrate(db: &mut Connection) -> Result<(), BridgeError> {
+    let version = db.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    if version > MIGRATIONS.len() { return Err(BridgeError::storage(format!("The database is at version {}, written by a newer bridge7573 that this one (version {}) can't read", version, MIGRATIONS.len()))) };
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", from + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+//The queries are all single row lookups on a local file, not worth moving off the executor
+#[async_trait::async_trait]
+impl Storage for SqliteStorage {
+    async fn get_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<Option<String>, BridgeError> {
+        let db = self.db.lock().unwrap();
+        Ok(db.query_row("SELECT webhook FROM webhooks WHERE webhook_table = ?1 AND channel = ?2 AND user = ?3", params![table.name(), channel, user], |row| row.get(0)).optional()?)
+    }
+    async fn put_webhook(&self, table: &WebhookTable, channel: &str, user: &str, webhook: &str) -> Result<(), BridgeError> {
+        let db = self.db.lock().unwrap();
+        db.execute("INSERT OR REPLACE INTO webhooks (webhook_table, channel, user, webhook) VALUES (?1, ?2, ?3, ?4)", params![table.name(), channel, user, webhook])?;
+        Ok(())
+    }
+    async fn remove_webhook(&self, table: &WebhookTable, channel: &str, user: &str) -> Result<(), BridgeError> {
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM webhooks WHERE webhook_table = ?1 AND channel = ?2 AND user = ?3", params![table.name(), channel, user])?;
+        Ok(())
+    }
+    async fn webhooks(&self, table: &WebhookTable) -> Result<Webhooks, BridgeError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT channel, user, webhook FROM webhooks WHERE webhook_table = ?1")?;
+        let mut webhooks = Webhooks::new();
+        for row in statement.query_map(params![table.name()], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?)))? {
+            let (channel, user, webhook) = row?;
+            webhooks.entry(channel).or_default().insert(user, webhook);
+        }
+        Ok(webhooks)
+    }
+    async fn webhook_tables(&self) -> Result<Vec<WebhookTable>, BridgeError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT DISTINCT webhook_table FROM webhooks")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0).map(WebhookTable::from_name))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    async fn get_mapping(&self, kind: &str, key: &str) -> Result<Option<String>, BridgeError> {
+        let db = self.db.lock().unwrap();
+        Ok(db.query_row("SELECT value FROM mappings WHERE kind = ?1 AND key = ?2", params![kind, key], |row| row.get(0)).optional()?)
+    }
+    async fn put_mapping(&self, kind: &str, key: &str, value: &str) -> Result<(), BridgeError> {
+        let db = self.db.lock().unwrap();
+        db.execute("INSERT OR REPLACE INTO mappings (kind, key, value) VALUES (?1, ?2, ?3)", params![kind, key, value])?;
+        Ok(())
+    }
+    async fn remove_mapping(&self, kind: &str, key: &str) -> Result<(), BridgeError> {
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM mappings WHERE kind = ?1 AND key = ?2", params![kind, key])?;
+        Ok(())
+    }
+    async fn mappings(&self, kind: &str) -> Result<BTreeMap<String, String>, BridgeError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT key, value FROM mappings WHERE kind = ?1")?;
+        let rows = statement.query_map(params![kind], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+    async fn mapping_kinds(&self) -> Result<Vec<String>, BridgeError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT DISTINCT kind FROM mappings")?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+    async fn put_all(&self, contents: &Contents) -> Result<(), BridgeError> {
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        for (table, webhooks) in &contents.webhooks {
+            for (channel, users) in webhooks {
+                for (user, webhook) in users {
+                    tx.execute("INSERT OR REPLACE INTO webhooks (webhook_table, channel, user, webhook) VALUES (?1, ?2, ?3, ?4)", params![table.name(), channel, user, webhook])?;
+                }
+            }
+        }
+        for (kind, mappings) in &contents.mappings {
+            for (key, value) in mappings {
+                tx.execute("INSERT OR REPLACE INTO mappings (kind, key, value) VALUES (?1, ?2, ?3)", params![kind, key, value])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+    //Moves everything in the write-ahead log into the database file itself
+    async fn flush(&self) -> Result<(), BridgeError> {
+        self.db.lock().unwrap().execute_batch("PRAGMA wal_checkpoint(TRUNCATE);")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bridge7573-{}-{}.sqlite", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn user_version(path: &str) -> i64 {
+        Connection::open(path).unwrap().query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn creates_current_schema() {
+        let path = temp_db("fresh");
+        let storage = SqliteStorage::open(&path).unwrap();
+        async_std::task::block_on(storage.put_webhook(&WebhookTable::new("guilded", "discord"), "111", "guilded_user", "webhook")).unwrap();
+        assert_eq!(user_version(&path), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn upgrades_v0_database() {
+        let path = temp_db("v0");
+        let db = Connection::open(&path).unwrap();
+        db.execute_batch(include_str!("../../tests/fixtures/bridge7573.v0.sql")).unwrap();
+        drop(db);
+
+        let storage = SqliteStorage::open(&path).unwrap();
+        assert_eq!(user_version(&path), MIGRATIONS.len() as i64);
+        let webhook = async_std::task::block_on(storage.get_webhook(&WebhookTable::new("guilded", "discord"), "111", "guilded_user")).unwrap();
+        assert_eq!(webhook.as_deref(), Some("https://discord.com/api/webhooks/1/token"));
+    }
+
+    #[test]
+    fn refuses_newer_databases() {
+        let path = temp_db("newer");
+        Connection::open(&path).unwrap().execute_batch(&format!("PRAGMA user_version = {}", MIGRATIONS.len() + 1)).unwrap();
+        assert!(SqliteStorage::open(&path).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+use crate::*;
+use async_std::channel::{bounded, Receiver};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use futures::FutureExt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Fires once, for every clone at the same time
+#[derive(Clone)]
+pub struct Signal {
+    /// Never sent on, dropping the only sender is what wakes everyone waiting
+    trigger: Arc<std::sync::Mutex<Option<Sender<()>>>>,
+    fired: Receiver<()>,
+}
+impl Signal {
+    pub fn new() -> Signal {
+        let (trigger, fired) = bounded(1);
+        Signal { trigger: Arc::new(std::sync::Mutex::new(Some(trigger))), fired }
+    }
+    pub fn fire(&self) {
+        self.trigger.lock().unwrap().take();
+    }
+    pub fn has_fired(&self) -> bool {
+        self.fired.is_closed()
+    }
+    pub async fn wait(&self) {
+        let _ = self.fired.recv().await;
+    }
+}
+impl Default for Signal {
+    fn default() -> Signal { Signal::new() }
+}
+
+/// How the bridge stops: first `stop` ends intake, so platforms stop relaying new events while the router delivers
+/// what's queued, then `close` has them close their connections
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    pub stop: Signal,
+    pub close: Signal,
+}
+impl Shutdown {
+    pub fn new() -> Shutdown { Shutdown::default() }
+
+    /// Fires `stop` on the first SIGTERM or SIGINT. A second one exits right away.
+    pub fn stop_on_signals(&self) {
+        let mut signals = Signals::new([SIGTERM, SIGINT]).expect("Failed to listen for SIGTERM and SIGINT");
+        let stop = self.stop.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                if stop.has_fired() {
+                    warn!(signal, "Signalled again, exiting without waiting");
+                    std::process::exit(1);
+                }
+                info!(signal, "Shutting down, finishing what's queued");
+                stop.fire();
+            }
+        });
+    }
+}
+
+/// How long to wait before running a failed child again
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub first: Duration,
+    /// The wait doubles with every failure in a row, up to this
+    pub max: Duration,
+    /// A child that ran this long before failing was fine for a while, so it starts over from `first`
+    pub healthy_after: Duration,
+}
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff { first: Duration::from_secs(1), max: Duration::from_secs(300), healthy_after: Duration::from_secs(60) }
+    }
+}
+
+/// Runs `child` until it returns `Ok` or `stop` fires, running it again after `backoff` whenever it fails with
+/// something worth retrying. Returns the error that wasn't.
+pub async fn supervise<F, Fut>(name: &str, stop: &Signal, backoff: Backoff, mut child: F) -> Result<(), BridgeError>
+where F: FnMut() -> Fut, Fut: Future<Output = Result<(), BridgeError>> {
+    let mut wait = backoff.first;
+    loop {
+        let started = Instant::now();
+        let err = match child().await {
+            Ok(()) => return Ok(()),
+            Err(err) if !err.is_retryable() => return Err(err),
+            Err(err) => err,
+        };
+        if stop.has_fired() { return Ok(()) };
+        if started.elapsed() >= backoff.healthy_after { wait = backoff.first };
+        let wait_for = err.retry_after().unwrap_or(wait).max(wait);
+        warn!(task = name, error = %err, retry_in = ?wait_for, "Failed, running it again");
+        let stopped = select! {
+            _ = async_std::task::sleep(wait_for).fuse() => false,
+            _ = stop.wait().fuse() => true,
+        };
+        if stopped { return Ok(()) };
+        wait = (wait * 2).min(backoff.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const QUICK: Backoff = Backoff { first: Duration::from_millis(1), max: Duration::from_millis(4), healthy_after: Duration::from_secs(60) };
+
+    #[async_std::test]
+    async fn runs_failing_children_again_until_they_succeed() {
+        let runs = AtomicU32::new(0);
+        let result = supervise("test", &Signal::new(), QUICK, || async {
+            if runs.fetch_add(1, Ordering::Relaxed) < 3 { Err(BridgeError::transport("reset")) } else { Ok(()) }
+        }).await;
+        assert!(result.is_ok());
+        assert_eq!(runs.load(Ordering::Relaxed), 4);
+    }
+
+    #[async_std::test]
+    async fn gives_up_on_permanent_errors() {
+        let runs = AtomicU32::new(0);
+        let result = supervise("test", &Signal::new(), QUICK, || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            Err(BridgeError::config("bad password"))
+        }).await;
+        assert!(matches!(result, Err(BridgeError::Config { .. })));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+    }
+
+    #[async_std::test]
+    async fn stopping_ends_the_wait() {
+        let stop = Signal::new();
+        let slow = Backoff { first: Duration::from_secs(60), ..QUICK };
+        let stopper = stop.clone();
+        async_std::task::spawn(async move {
+            async_std::task::sleep(Duration::from_millis(20)).await;
+            stopper.fire();
+        });
+        let result = async_std::future::timeout(Duration::from_secs(2), supervise("test", &stop, slow, || async { Err(BridgeError::transport("reset")) })).await;
+        assert!(matches!(result, Ok(Ok(()))));
+        assert!(stop.has_fired());
+    }
+}
@@ -0,0 +1,43 @@
+//! What the integration tests share: free addresses to listen on, and environments like the bridge's own, with
+//! storage in memory.
+//Every test file only uses some of these
+#![allow(dead_code)]
+use bridge7573::*;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// An address on localhost nothing's listening on yet
+pub fn free_address() -> String {
+    std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string()
+}
+
+/// Waits up to 2 seconds for something to listen on `address`
+pub async fn wait_for(address: &str) {
+    for _ in 0..100 {
+        if async_std::net::TcpStream::connect(address).await.is_ok() { return };
+        async_std::task::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Nothing listening on {}", address);
+}
+
+/// Settings from `args` without a config file, and `routes` as the config
+pub fn environment(routes: BTreeMap<ChannelRef, Vec<ChannelRef>>, args: &[&str]) -> Arc<Environment> {
+    let args = ["--config", "/nonexistent/config.json"].iter().chain(args).map(|arg| arg.to_string()).collect::<Vec<_>>();
+    Arc::new(Environment {
+        settings: Settings::load(&args).unwrap(),
+        storage: Box::new(SqliteStorage::open(":memory:").unwrap()),
+        config: RwLock::new(Arc::new(Config { routes })),
+        config_error: Default::default(),
+    })
+}
+
+/// Settings from `args`, and the config loaded from `config_path` like the bridge does
+pub fn environment_with_config(config_path: &str, args: &[&str]) -> Arc<Environment> {
+    let args = ["--config", config_path].iter().chain(args).map(|arg| arg.to_string()).collect::<Vec<_>>();
+    Arc::new(Environment {
+        settings: Settings::load(&args).unwrap(),
+        storage: Box::new(SqliteStorage::open(":memory:").unwrap()),
+        config: RwLock::new(Arc::new(Config::load(config_path).unwrap())),
+        config_error: Default::default(),
+    })
+}
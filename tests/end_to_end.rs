@@ -0,0 +1,81 @@
+//! The whole bridge against fake Discord and Guilded servers: messages relayed both ways, the webhooks they're posted
+//! through, and the avatars those are given.
+mod common;
+mod fake;
+
+use bridge7573::*;
+use common::environment_with_config;
+use fake::{FakeDiscord, FakeGuilded, AVATAR};
+use serde_json::json;
+use std::time::Duration;
+
+fn environment(dir: &std::path::Path, discord: &FakeDiscord, guilded: &FakeGuilded) -> Arc<Environment> {
+    let config = dir.join("config.json").to_str().unwrap().to_owned();
+    std::fs::write(&config, r#"{ "text_channel_bindings": [{ "discord": "1", "guilded": "a" }] }"#).unwrap();
+    environment_with_config(&config, &[
+        "--discord-api", &discord.api,
+        "--discord-cdn", &discord.cdn,
+        "--guilded-api", &guilded.api,
+        "--guilded-socket", &guilded.socket,
+        "--guilded-media", &guilded.media,
+    ])
+}
+
+#[async_std::test]
+async fn relays_through_webhooks_with_avatars() {
+    let (discord, guilded) = (FakeDiscord::start().await, FakeGuilded::start().await);
+    let dir = std::env::temp_dir().join(format!("bridge7573_end_to_end_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let env = environment(&dir, &discord, &guilded);
+
+    let guilded_platform = Guilded::new(env.clone(), "bridge@example.org".to_owned(), Secret::new("guilded-password".to_owned()));
+    let discord_platform = Discord::new(env.clone(), Secret::new("Bot discord-token".to_owned()));
+    let mut router = Router::new(env.clone());
+    router.add_platform(guilded_platform.clone());
+    router.add_platform(discord_platform.clone());
+    let shutdown = Shutdown::new();
+    let bridge = async_std::task::spawn(router.run(shutdown.clone()));
+    for _ in 0..250 {
+        if discord_platform.status().connected && guilded_platform.status().connected { break };
+        async_std::task::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(discord.received.lock().unwrap().iter().any(|frame| frame.contains("\"op\": 2") && frame.contains("Bot discord-token")));
+
+    //Discord to Guilded, with the avatar from the CDN uploaded to Guilded's media host
+    for (id, content) in [("2", "hello from discord"), ("3", "again")] {
+        discord.dispatch("MESSAGE_CREATE", json!({
+            "id": id, "channel_id": "1", "content": content, "attachments": [],
+            "author": { "id": "3", "username": "bob", "avatar": "abc" },
+        })).await;
+    }
+    let created = guilded.requests.wait_for(1, "POST", "/api/webhooks").await;
+    assert_eq!(created[0].json(), json!({ "channelId": "a", "name": "💬 bob", "iconUrl": null }));
+    let sent = guilded.requests.wait_for(2, "POST", "/media/webhooks/").await;
+    assert_eq!(sent.iter().map(|request| request.json()["content"].clone()).collect::<Vec<_>>(), ["hello from discord", "again"]);
+    assert!(sent.iter().all(|request| request.path.ends_with("/guilded-webhook-token-1")), "{:?}", sent);
+    assert_eq!(guilded.requests.to("POST", "/api/webhooks").len(), 1);
+    assert_eq!(discord.requests.to("GET", "/cdn/avatars/3/abc.png").len(), 1);
+    let uploaded = guilded.requests.wait_for(1, "POST", "/media/media/upload").await;
+    assert!(uploaded[0].body.windows(AVATAR.len()).any(|window| window == AVATAR));
+    let updated = guilded.requests.wait_for(1, "PUT", "/api/webhooks/guilded-webhook-1").await;
+    assert_eq!(updated[0].json()["name"], "💬 bob");
+    assert!(updated[0].json()["iconUrl"].as_str().unwrap().starts_with(&format!("{}/uploaded/", guilded.media)));
+
+    //Guilded to Discord, with the avatar sent along when the webhook is made
+    guilded.event("ChatMessageCreated", json!({
+        "channelId": "a", "createdBy": "u1", "teamId": "t",
+        "message": { "id": "m1", "content": { "document": { "object": "document", "nodes": [
+            { "object": "block", "nodes": [{ "object": "text", "leaves": [{ "object": "leaf", "text": "hello from guilded" }] }] },
+        ] } } },
+    })).await;
+    let created = discord.requests.wait_for(1, "POST", "/api/channels/1/webhooks").await;
+    assert_eq!(created[0].json()["name"], "📀 Alice");
+    assert_eq!(created[0].json()["avatar"], format!("data:image/png;base64,{}", base64::encode(AVATAR)));
+    assert_eq!(guilded.requests.to("GET", "/media/avatars/u1.png").len(), 1);
+    let sent = discord.requests.wait_for(1, "POST", "/api/webhooks/").await;
+    assert_eq!(sent[0].json()["content"], "hello from guilded");
+
+    shutdown.stop.fire();
+    async_std::future::timeout(Duration::from_secs(10), bridge).await.expect("bridge never stopped").unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
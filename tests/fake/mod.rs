@@ -0,0 +1,207 @@
+//! Stand-ins for Discord and Guilded, over real HTTP and websockets, that remember everything the bridge asked them
+//! for. Both serve their REST API and media from one address, and a gateway or socket.io endpoint from another.
+use crate::common::{free_address, wait_for};
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_tungstenite::tungstenite::Message;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde_json::{json, Value as JsValue};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What every avatar is, whoever it's for
+pub const AVATAR: &[u8] = b"\x89PNG\r\n\x1a\nnot really a png";
+
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+impl Request {
+    pub fn json(&self) -> JsValue {
+        serde_json::from_slice(&self.body).unwrap_or(JsValue::Null)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Requests(Arc<Mutex<Vec<Request>>>);
+impl Requests {
+    /// Every `method` request to a path starting with `path` so far, in order
+    pub fn to(&self, method: &str, path: &str) -> Vec<Request> {
+        self.0.lock().unwrap().iter().filter(|request| request.method == method && request.path.starts_with(path)).cloned().collect()
+    }
+
+    /// Waits up to 5 seconds for `count` of `to(method, path)`, and returns them
+    pub async fn wait_for(&self, count: usize, method: &str, path: &str) -> Vec<Request> {
+        for _ in 0..250 {
+            let requests = self.to(method, path);
+            if requests.len() >= count { return requests };
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Never got {} {} {}, only {:#?}", count, method, path, self.0.lock().unwrap());
+    }
+}
+
+type Answer = Arc<dyn Fn(&str, &Request, usize) -> tide::Response + Send + Sync>;
+
+#[derive(Clone)]
+struct State {
+    base: String,
+    requests: Requests,
+    answer: Answer,
+}
+
+/// Records every request and answers it with `answer(base url, request, how many came before)`
+async fn serve_http(answer: impl Fn(&str, &Request, usize) -> tide::Response + Send + Sync + 'static) -> (String, Requests) {
+    let address = free_address();
+    let state = State { base: format!("http://{}", address), requests: Requests::default(), answer: Arc::new(answer) };
+    let mut app = tide::with_state(state.clone());
+    app.at("/*path").all(|mut req: tide::Request<State>| async move {
+        let body = req.body_bytes().await.unwrap_or_default();
+        let request = Request { method: req.method().to_string(), path: req.url().path().to_owned(), body };
+        let state = req.state();
+        let count = {
+            let mut requests = state.requests.0.lock().unwrap();
+            requests.push(request.clone());
+            requests.len() - 1
+        };
+        Ok((state.answer)(&state.base, &request, count))
+    });
+    let listen = address.clone();
+    async_std::task::spawn(async move { app.listen(listen).await.unwrap() });
+    wait_for(&address).await;
+    (state.base, state.requests)
+}
+
+fn json_response(body: JsValue) -> tide::Response {
+    tide::Response::builder(200).body(body).build()
+}
+
+fn avatar_response() -> tide::Response {
+    tide::Response::builder(200).header("Content-Type", "image/png").body(AVATAR).build()
+}
+
+/// Accepts websocket connections, one session at a time. Each one starts with `greet`, every text frame that comes
+/// in is answered with `answer(frame)`, and whatever's pushed into the returned sender is sent as is.
+async fn serve_websocket(greet: Vec<String>, answer: fn(&str) -> Vec<String>) -> (String, Sender<String>, Arc<Mutex<Vec<String>>>) {
+    let address = free_address();
+    let listener = async_std::net::TcpListener::bind(&address).await.unwrap();
+    let (push, pushed) = unbounded::<String>();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let log = received.clone();
+    async_std::task::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let ws = match async_tungstenite::accept_async(stream).await { Ok(ws) => ws, Err(_) => continue };
+            session(ws, &greet, answer, &pushed, &log).await;
+        }
+    });
+    (format!("ws://{}", address), push, received)
+}
+
+async fn session<S>(ws: async_tungstenite::WebSocketStream<S>, greet: &[String], answer: fn(&str) -> Vec<String>, pushed: &Receiver<String>, log: &Mutex<Vec<String>>)
+    where S: futures::AsyncRead + futures::AsyncWrite + Unpin {
+    let (mut to_bridge, mut from_bridge) = ws.split();
+    for frame in greet {
+        if to_bridge.send(Message::Text(frame.clone())).await.is_err() { return };
+    }
+    loop {
+        futures::select! {
+            frame = from_bridge.next().fuse() => match frame {
+                Some(Ok(Message::Text(frame))) => {
+                    log.lock().unwrap().push(frame.clone());
+                    for reply in answer(&frame) {
+                        if to_bridge.send(Message::Text(reply)).await.is_err() { return };
+                    }
+                },
+                Some(Ok(Message::Close(_))) => { let _ = to_bridge.send(Message::Close(None)).await; return },
+                Some(Ok(_)) => (),
+                _ => return,
+            },
+            frame = pushed.recv().fuse() => match frame {
+                Ok(frame) => if to_bridge.send(Message::Text(frame)).await.is_err() { return },
+                Err(_) => return,
+            },
+        }
+    }
+}
+
+/// Discord's REST API under `/api`, its CDN under `/cdn`, and a gateway that's ready as soon as it's identified to
+pub struct FakeDiscord {
+    pub api: String,
+    pub cdn: String,
+    pub requests: Requests,
+    gateway: Sender<String>,
+    /// Every frame the bridge sent the gateway
+    pub received: Arc<Mutex<Vec<String>>>,
+}
+
+impl FakeDiscord {
+    pub async fn start() -> FakeDiscord {
+        let (gateway_url, gateway, received) = serve_websocket(vec![json!({ "op": 10, "d": { "heartbeat_interval": 41250 } }).to_string()], |frame| {
+            match serde_json::from_str::<JsValue>(frame).unwrap_or(JsValue::Null)["op"].as_u64() {
+                Some(1) => vec![json!({ "op": 11 }).to_string()],
+                Some(2) => vec![json!({ "op": 0, "s": 1, "t": "READY", "d": { "session_id": "fake-session" } }).to_string()],
+                _ => vec![],
+            }
+        }).await;
+        let (base, requests) = serve_http(move |base, request, count| {
+            let path = request.path.as_str();
+            if path == "/api/gateway" || path == "/api/gateway/bot" {
+                json_response(json!({ "url": gateway_url }))
+            } else if request.method == "POST" && path.starts_with("/api/channels/") && path.ends_with("/webhooks") {
+                json_response(json!({ "id": count.to_string(), "token": format!("discord-webhook-token-{}", count) }))
+            } else if request.method == "POST" && path.starts_with("/api/webhooks/") {
+                json_response(json!({ "id": format!("discord-copy-{}", count) }))
+            } else if path.starts_with("/cdn/avatars/") {
+                avatar_response()
+            } else {
+                tide::Response::builder(404).body(format!("{} has no {}", base, path)).build()
+            }
+        }).await;
+        FakeDiscord { api: format!("{}/api", base), cdn: format!("{}/cdn", base), requests, gateway, received }
+    }
+
+    /// Sends a dispatch like `MESSAGE_CREATE` down the gateway
+    pub async fn dispatch(&self, event_type: &str, data: JsValue) {
+        self.gateway.send(json!({ "op": 0, "s": 2, "t": event_type, "d": data }).to_string()).await.unwrap();
+    }
+}
+
+/// Guilded's REST API under `/api`, its media host under `/media`, and a socket.io endpoint that answers heartbeats
+pub struct FakeGuilded {
+    pub api: String,
+    pub media: String,
+    pub socket: String,
+    pub requests: Requests,
+    events: Sender<String>,
+}
+
+impl FakeGuilded {
+    pub async fn start() -> FakeGuilded {
+        let open = json!({ "sid": "fake-sid", "upgrades": [], "pingInterval": 25000, "pingTimeout": 60000 });
+        let (socket, events, _) = serve_websocket(vec![format!("0{}", open), "40".to_owned()], |frame| {
+            if frame == "2" { vec!["3".to_owned()] } else { vec![] }
+        }).await;
+        let (base, requests) = serve_http(|base, request, count| {
+            let path = request.path.as_str();
+            match (request.method.as_str(), path) {
+                ("POST", "/api/login") => tide::Response::builder(200).header("Set-Cookie", "hmac_signed_session=fake-session; Path=/").build(),
+                ("GET", _) if path.starts_with("/api/users/") => json_response(json!({
+                    "user": { "name": "Alice", "profilePictureSm": format!("{}/media/avatars/{}.png", base, &path["/api/users/".len()..]) },
+                })),
+                ("POST", "/api/webhooks") => json_response(json!({ "id": format!("guilded-webhook-{}", count), "token": format!("guilded-webhook-token-{}", count) })),
+                ("PUT", _) if path.starts_with("/api/webhooks/") => json_response(json!({})),
+                ("POST", "/media/media/upload") => json_response(json!({ "url": format!("{}/media/uploaded/{}.png", base, count) })),
+                ("POST", _) if path.starts_with("/media/webhooks/") => json_response(json!({ "id": format!("guilded-copy-{}", count) })),
+                ("GET", _) if path.starts_with("/media/avatars/") => avatar_response(),
+                _ => tide::Response::builder(404).body(format!("{} has no {}", base, path)).build(),
+            }
+        }).await;
+        FakeGuilded { api: format!("{}/api", base), media: format!("{}/media", base), socket, requests, events }
+    }
+
+    /// Sends a socket.io event like `ChatMessageCreated`
+    pub async fn event(&self, event_type: &str, data: JsValue) {
+        self.events.send(format!("42{}", json!([event_type, data]))).await.unwrap();
+    }
+}
@@ -0,0 +1,14 @@
+CREATE TABLE webhooks (
+    webhook_table TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    user TEXT NOT NULL,
+    webhook TEXT NOT NULL,
+    PRIMARY KEY (webhook_table, channel, user)
+);
+CREATE TABLE mappings (
+    kind TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (kind, key)
+);
+INSERT INTO webhooks VALUES ('guilded_to_discord', '111', 'guilded_user', 'https://discord.com/api/webhooks/1/token');
@@ -0,0 +1,137 @@
+//! The IRC client against a fake server on plain TCP that plays the server's half of registration and SASL.
+mod common;
+
+use async_std::channel::unbounded;
+use async_std::io::BufReader;
+use async_std::net::TcpListener;
+use futures::{AsyncBufReadExt, AsyncWriteExt};
+use bridge7573::*;
+use common::environment;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[async_std::test]
+async fn registers_with_sasl_and_relays_both_ways() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let (seen_lines, seen) = unbounded::<String>();
+    async_std::task::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut writer = stream.clone();
+        let mut lines = BufReader::new(stream).lines();
+        while let Some(Ok(line)) = lines.next().await {
+            let reply = match line.as_str() {
+                "CAP REQ :sasl" => ":server CAP * ACK :sasl\r\n".to_owned(),
+                "AUTHENTICATE PLAIN" => "AUTHENTICATE +\r\n".to_owned(),
+                line if line.starts_with("AUTHENTICATE ") => ":server 903 bridge7573 :SASL authentication successful\r\n".to_owned(),
+                "NICK bridge7573" => ":server 433 * bridge7573 :Nickname is already in use\r\n".to_owned(),
+                "CAP END" => ":server 001 bridge7573_ :Welcome\r\nPING :keepalive\r\n".to_owned(),
+                "JOIN #general" => ":bridge7573_!b@host JOIN #general\r\n:alice!a@host PRIVMSG #General :\u{2}hi\u{2} there\r\n:alice!a@host PRIVMSG #general :\u{1}ACTION waves\u{1}\r\n".to_owned(),
+                _ => String::new(),
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            seen_lines.send(line).await.unwrap();
+        }
+    });
+
+    let general = ChannelRef::Irc("#general".to_owned());
+    let env = environment(BTreeMap::from([(general.clone(), vec![ChannelRef::Discord("1".to_owned())])]), &[]);
+    let settings = IrcSettings { server, tls: false, nick: "bridge7573".to_owned(), sasl: Some(("account".to_owned(), Secret::new("hunter2".to_owned()))) };
+    let irc = Irc::new(env, settings);
+    let (events, incoming) = unbounded();
+    let shutdown = Shutdown::new();
+    let session = async_std::task::spawn(irc.clone().run(events, shutdown.clone()));
+
+    //Registering takes a few seconds at one line per 300ms
+    for expected in ["hi there", "*waves*"] {
+        match async_std::future::timeout(Duration::from_secs(5), incoming.recv()).await {
+            Ok(Ok(BridgeEvent::MessageCreated(message))) => {
+                assert_eq!((message.channel, message.author.name.as_str()), (general.clone(), "alice"));
+                assert_eq!(&message.content, expected);
+            },
+            other => panic!("expected a message, got {:?}", other),
+        }
+    }
+
+    let long = "word ".repeat(200);
+    let message = BridgeMessage {
+        id: "1".to_owned(),
+        channel: ChannelRef::Discord("1".to_owned()),
+        author: BridgeUser { platform: DISCORD.to_owned(), id: "2".to_owned(), name: "bob".to_owned(), avatar_url: None },
+        content: format!("short\n{}", long),
+        attachments: vec![],
+    };
+    assert_eq!(irc.send_message("#general", &message).await.unwrap(), None);
+
+    //"short", then 999 bytes of words in pieces of at most 403 bytes, after what a 11 letter nick leaves
+    let mut lines = vec![];
+    while lines.iter().filter(|line: &&String| line.starts_with("PRIVMSG")).count() < 4 {
+        lines.push(async_std::future::timeout(Duration::from_secs(2), seen.recv()).await.unwrap().unwrap());
+    }
+    assert_eq!(lines[..4], ["CAP REQ :sasl", "NICK bridge7573", "USER bridge7573 0 * :bridge7573", "AUTHENTICATE PLAIN"]);
+    assert!(lines.contains(&format!("AUTHENTICATE {}", base64::encode("account\0account\0hunter2"))));
+    assert!(lines.contains(&"NICK bridge7573_".to_owned()));
+    assert!(lines.contains(&"PONG :keepalive".to_owned()));
+    let relayed = lines.iter().filter(|line| line.starts_with("PRIVMSG #general :")).collect::<Vec<_>>();
+    assert_eq!(relayed[0], "PRIVMSG #general :<💬 b\u{200b}ob> short");
+    assert!(relayed[1..].iter().all(|line| line.starts_with("PRIVMSG #general :<💬 b\u{200b}ob> word")));
+    assert!(relayed.iter().all(|line| line.len() - "PRIVMSG #general :".len() <= 403), "{:?}", relayed);
+
+    shutdown.stop.fire();
+    shutdown.close.fire();
+    async_std::future::timeout(Duration::from_secs(5), session).await.expect("never disconnected").unwrap();
+    let mut rest = vec![];
+    while let Ok(line) = seen.try_recv() { rest.push(line) };
+    assert_eq!(rest.last().map(|line| &**line), Some("QUIT :Bridge shutting down"));
+    assert!(irc.send_message("#general", &message).await.is_err());
+}
+
+#[async_std::test]
+async fn rejoins_when_kicked_and_joins_channels_added_by_a_reload() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = listener.local_addr().unwrap().to_string();
+    let (seen_lines, seen) = unbounded::<String>();
+    async_std::task::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut writer = stream.clone();
+        let mut lines = BufReader::new(stream).lines();
+        let mut kicked = false;
+        while let Some(Ok(line)) = lines.next().await {
+            let reply = match line.as_str() {
+                line if line.starts_with("USER ") => ":server 001 bridge7573 :Welcome\r\n".to_owned(),
+                "JOIN #general" if !kicked => {
+                    kicked = true;
+                    ":bridge7573!b@host JOIN #general\r\n:op!o@host KICK #general bridge7573 :bye\r\n".to_owned()
+                },
+                _ => String::new(),
+            };
+            writer.write_all(reply.as_bytes()).await.unwrap();
+            seen_lines.send(line).await.unwrap();
+        }
+    });
+
+    let general = ChannelRef::Irc("#general".to_owned());
+    let env = environment(BTreeMap::from([(general.clone(), vec![ChannelRef::Discord("1".to_owned())])]), &[]);
+    let settings = IrcSettings { server, tls: false, nick: "bridge7573".to_owned(), sasl: None };
+    let irc = Irc::new(env.clone(), settings);
+    let (events, _incoming) = unbounded();
+    let shutdown = Shutdown::new();
+    let session = async_std::task::spawn(irc.run(events, shutdown.clone()));
+
+    let next_join = || async {
+        loop {
+            let line = async_std::future::timeout(Duration::from_secs(10), seen.recv()).await.expect("no JOIN").unwrap();
+            if line.starts_with("JOIN ") { return line };
+        }
+    };
+    assert_eq!(next_join().await, "JOIN #general");
+    assert_eq!(next_join().await, "JOIN #general");
+
+    let added = ChannelRef::Irc("#added".to_owned());
+    *env.config.write().await = Arc::new(Config { routes: BTreeMap::from([(general, vec![added])]) });
+    assert_eq!(next_join().await, "JOIN #added");
+
+    shutdown.stop.fire();
+    shutdown.close.fire();
+    async_std::future::timeout(Duration::from_secs(5), session).await.expect("never disconnected").unwrap();
+}
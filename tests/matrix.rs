@@ -0,0 +1,169 @@
+//! The Matrix application service against a fake homeserver that answers everything and remembers what it was asked.
+mod common;
+
+use async_std::channel::{unbounded, Receiver};
+use bridge7573::*;
+use common::{environment, free_address, wait_for};
+use serde_json::{json, Value as JsValue};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+struct Seen {
+    method: String,
+    path: String,
+    user_id: Option<String>,
+    authorization: Option<String>,
+    body: JsValue,
+}
+type Requests = Arc<std::sync::Mutex<Vec<Seen>>>;
+
+async fn fake_homeserver(requests: Requests) -> String {
+    let address = free_address();
+    let mut app = tide::with_state(requests);
+    app.at("/_matrix/*path").all(|mut req: tide::Request<Requests>| async move {
+        let body = req.body_json::<JsValue>().await.unwrap_or(JsValue::Null);
+        let seen = Seen {
+            method: req.method().to_string(),
+            path: req.url().path().to_owned(),
+            user_id: req.url().query_pairs().find(|(key, _)| key == "user_id").map(|(_, user)| user.into_owned()),
+            authorization: req.header("Authorization").map(|header| header.as_str().to_owned()),
+            body,
+        };
+        let answer = if seen.path.ends_with("/register") { json!({ "user_id": "registered" }) }
+            else if seen.path.contains("/send/") { json!({ "event_id": "$relayed" }) }
+            else if seen.path.contains("/profile/") && seen.method == "GET" { json!({ "displayname": "Alice" }) }
+            else { json!({}) };
+        req.state().lock().unwrap().push(seen);
+        Ok(answer)
+    });
+    let listen = address.clone();
+    async_std::task::spawn(async move { app.listen(listen).await.unwrap() });
+    wait_for(&address).await;
+    format!("http://{}", address)
+}
+
+async fn matrix(homeserver: String) -> (Arc<Matrix>, String) {
+    let env = environment(BTreeMap::new(), &[]);
+    let listen = free_address();
+    let matrix_settings = MatrixSettings {
+        homeserver,
+        server_name: "test".to_owned(),
+        listen: listen.clone(),
+        as_token: Secret::new("as_token".to_owned()),
+        hs_token: Secret::new("hs_token".to_owned()),
+    };
+    (Matrix::new(env, matrix_settings), listen)
+}
+
+fn message_from_discord(content: &str) -> BridgeMessage {
+    BridgeMessage {
+        id: "5678".to_owned(),
+        channel: ChannelRef::Discord("1111".to_owned()),
+        author: BridgeUser { platform: DISCORD.to_owned(), id: "1234".to_owned(), name: "bob".to_owned(), avatar_url: None },
+        content: content.to_owned(),
+        attachments: vec![],
+    }
+}
+
+#[async_std::test]
+async fn relays_as_a_puppet_that_is_set_up_once() {
+    let requests = Requests::default();
+    let (matrix, _) = matrix(fake_homeserver(requests.clone()).await).await;
+
+    assert_eq!(matrix.send_message("!room:test", &message_from_discord("hi")).await.unwrap().as_deref(), Some("$relayed"));
+    assert_eq!(matrix.send_message("!room:test", &message_from_discord("again")).await.unwrap().as_deref(), Some("$relayed"));
+
+    let requests = requests.lock().unwrap().clone();
+    assert!(requests.iter().all(|seen| seen.authorization.as_deref() == Some("Bearer as_token")));
+    let puppet = "@_bridge7573_discord_1234:test";
+
+    let registers = requests.iter().filter(|seen| seen.path.ends_with("/register")).collect::<Vec<_>>();
+    assert_eq!(registers.len(), 1);
+    assert_eq!(registers[0].body["username"], "_bridge7573_discord_1234");
+
+    let displayname = requests.iter().find(|seen| seen.path.ends_with("/displayname")).unwrap();
+    assert_eq!(displayname.body["displayname"], "💬 bob");
+
+    let joins = requests.iter().filter(|seen| seen.path.contains("/join/")).collect::<Vec<_>>();
+    assert_eq!(joins.len(), 1);
+    assert_eq!(joins[0].user_id.as_deref(), Some(puppet));
+
+    let sends = requests.iter().filter(|seen| seen.path.contains("/send/m.room.message/")).collect::<Vec<_>>();
+    assert_eq!(sends.iter().map(|seen| seen.body["body"].as_str().unwrap()).collect::<Vec<_>>(), ["hi", "again"]);
+    assert!(sends.iter().all(|seen| seen.method == "PUT" && seen.user_id.as_deref() == Some(puppet)));
+    assert_ne!(sends[0].path, sends[1].path, "transaction ids are reused");
+}
+
+#[async_std::test]
+async fn edits_and_redacts_through_the_same_puppet() {
+    let requests = Requests::default();
+    let (matrix, _) = matrix(fake_homeserver(requests.clone()).await).await;
+    let message = message_from_discord("hi");
+    matrix.send_message("!room:test", &message).await.unwrap();
+    matrix.edit_message("!room:test", "$relayed", &message_from_discord("edited")).await.unwrap();
+    matrix.delete_message("!room:test", "$relayed", &message.author).await.unwrap();
+
+    let requests = requests.lock().unwrap().clone();
+    let edit = requests.iter().filter(|seen| seen.path.contains("/send/")).nth(1).unwrap();
+    assert_eq!(edit.body["m.new_content"]["body"], "edited");
+    assert_eq!(edit.body["m.relates_to"]["event_id"], "$relayed");
+    assert!(requests.iter().any(|seen| seen.path.contains("/redact/%24relayed/") && seen.user_id.as_deref() == Some("@_bridge7573_discord_1234:test")));
+}
+
+async fn put_transaction(listen: &str, txn: &str, token: &str, events: JsValue) -> surf::StatusCode {
+    surf::put(format!("http://{}/_matrix/app/v1/transactions/{}", listen, txn))
+        .header("Authorization", format!("Bearer {}", token))
+        .body(json!({ "events": events }))
+        .await.unwrap().status()
+}
+
+async fn next_event(events: &Receiver<BridgeEvent>) -> Option<BridgeEvent> {
+    async_std::future::timeout(Duration::from_millis(200), events.recv()).await.ok().and_then(|event| event.ok())
+}
+
+#[async_std::test]
+async fn turns_transactions_into_events() {
+    let (matrix, listen) = matrix(fake_homeserver(Requests::default()).await).await;
+    let (sender, events) = unbounded();
+    let shutdown = Shutdown::new();
+    let service = async_std::task::spawn(matrix.clone().run(sender, shutdown.clone()));
+    wait_for(&listen).await;
+
+    assert_eq!(put_transaction(&listen, "1", "wrong", json!([])).await, surf::StatusCode::Forbidden);
+
+    let transaction = json!([
+        { "type": "m.room.message", "room_id": "!room:test", "sender": "@alice:test", "event_id": "$1", "content": { "msgtype": "m.text", "body": "hello" } },
+        { "type": "m.room.message", "room_id": "!room:test", "sender": "@_bridge7573_discord_1234:test", "event_id": "$2", "content": { "msgtype": "m.text", "body": "relayed" } },
+        { "type": "m.room.message", "room_id": "!room:test", "sender": "@alice:test", "event_id": "$3", "content": {
+            "msgtype": "m.text", "body": "* hello!", "m.new_content": { "msgtype": "m.text", "body": "hello!" },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": "$1" } } },
+        { "type": "m.room.redaction", "room_id": "!room:test", "sender": "@alice:test", "event_id": "$4", "redacts": "$1", "content": {} },
+    ]);
+    assert!(put_transaction(&listen, "2", "hs_token", transaction.clone()).await.is_success());
+    //The homeserver resending a transaction mustn't relay it twice
+    assert!(put_transaction(&listen, "2", "hs_token", transaction).await.is_success());
+
+    match next_event(&events).await {
+        Some(BridgeEvent::MessageCreated(message)) => {
+            assert_eq!((message.id.as_str(), message.content.as_str(), message.channel), ("$1", "hello", ChannelRef::Matrix("!room:test".to_owned())));
+            assert_eq!((message.author.platform.as_str(), message.author.name.as_str()), (MATRIX, "Alice"));
+        },
+        other => panic!("expected the message, got {:?}", other),
+    }
+    match next_event(&events).await {
+        Some(BridgeEvent::MessageEdited(message)) => assert_eq!((message.id.as_str(), message.content.as_str()), ("$1", "hello!")),
+        other => panic!("expected the edit, got {:?}", other),
+    }
+    match next_event(&events).await {
+        Some(BridgeEvent::MessageDeleted { id, .. }) => assert_eq!(id, "$1"),
+        other => panic!("expected the redaction, got {:?}", other),
+    }
+    assert!(next_event(&events).await.is_none());
+
+    //Once stopping, transactions are left for the homeserver to send again later
+    shutdown.stop.fire();
+    assert_eq!(put_transaction(&listen, "3", "hs_token", json!([])).await, surf::StatusCode::ServiceUnavailable);
+    shutdown.close.fire();
+    async_std::future::timeout(Duration::from_secs(2), service).await.expect("never stopped listening").unwrap();
+}
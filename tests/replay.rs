@@ -0,0 +1,78 @@
+//! Recorded Discord and Guilded frames played back through the whole bridge, against a fake REST API for both.
+mod common;
+
+use bridge7573::*;
+use bridge7573::recording::Frame;
+use common::{environment_with_config, free_address, wait_for};
+use serde_json::{json, Value as JsValue};
+use std::time::Duration;
+
+type Requests = Arc<std::sync::Mutex<Vec<(String, String, JsValue)>>>;
+
+/// Discord under `/discord`, Guilded under `/guilded`
+async fn fake_apis(requests: Requests) -> String {
+    let address = free_address();
+    let mut app = tide::with_state(requests);
+    app.at("/*path").all(|mut req: tide::Request<Requests>| async move {
+        let body = req.body_json::<JsValue>().await.unwrap_or(JsValue::Null);
+        let (method, path) = (req.method().to_string(), req.url().path().to_owned());
+        req.state().lock().unwrap().push((method, path.clone(), body));
+        let response = tide::Response::builder(200);
+        Ok(if path == "/guilded/login" { response.header("Set-Cookie", "hmac_signed_session=fake-session; Path=/").build() }
+            else if path.starts_with("/guilded/users/") { response.body(json!({ "user": { "name": "Alice", "profilePictureSm": null } })).build() }
+            else if path.ends_with("/webhooks") { response.body(json!({ "id": "10", "token": "webhook-token" })).build() }
+            else { response.body(json!({ "id": "copy" })).build() })
+    });
+    let listen = address.clone();
+    async_std::task::spawn(async move { app.listen(listen).await.unwrap() });
+    wait_for(&address).await;
+    format!("http://{}", address)
+}
+
+fn frames() -> String {
+    let frames = [
+        Frame { platform: DISCORD.to_owned(), frame: r#"{"op":10,"d":{"heartbeat_interval":41250}}"#.to_owned() },
+        Frame { platform: DISCORD.to_owned(), frame: r#"{"op":0,"s":1,"t":"READY","d":{"session_id":"<redacted>"}}"#.to_owned() },
+        Frame { platform: GUILDED.to_owned(), frame: r#"42["ChatMessageCreated",{"channelId":"a","createdBy":"u1","teamId":"t","message":{"id":"m1","content":{"document":
+            {"object":"document","nodes":[{"object":"block","nodes":[{"object":"text","leaves":[{"object":"leaf","text":"hello from guilded"}]}]}]}}}}]"#.replace('\n', "") },
+        Frame { platform: DISCORD.to_owned(), frame: r#"{"op":0,"s":2,"t":"MESSAGE_CREATE","d":{"id":"2","channel_id":"1","content":"!bridge status","attachments":[],
+            "author":{"id":"3","username":"bob","avatar":null}}}"#.replace('\n', "") },
+    ];
+    frames.iter().map(|frame| serde_json::to_string(frame).unwrap() + "\n").collect()
+}
+
+#[async_std::test]
+async fn replays_recorded_frames_through_the_bridge() {
+    let requests = Requests::default();
+    let api = fake_apis(requests.clone()).await;
+    let dir = std::env::temp_dir().join(format!("bridge7573_replay_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+    std::fs::write(path("config.json"), r#"{ "text_channel_bindings": [{ "discord": "1", "guilded": "a", "direction": "guilded_to_discord" }] }"#).unwrap();
+    std::fs::write(path("frames.jsonl"), frames()).unwrap();
+
+    let env = environment_with_config(&path("config.json"), &[
+        "--replay", &path("frames.jsonl"),
+        "--discord-api", &format!("{}/discord", api),
+        "--guilded-api", &format!("{}/guilded", api),
+    ]);
+    let mut router = Router::new(env.clone());
+    router.add_platform(Guilded::new(env.clone(), "bridge@example.org".to_owned(), Secret::new("guilded-password".to_owned())));
+    router.add_platform(Discord::new(env.clone(), Secret::new("Bot discord-token".to_owned())));
+    async_std::future::timeout(Duration::from_secs(10), router.run(Shutdown::new())).await.expect("replay never finished").unwrap();
+
+    let requests = requests.lock().unwrap().clone();
+    let posted = |path: &str| requests.iter().filter(|(method, seen, _)| method == "POST" && seen == path).map(|(_, _, body)| body.clone()).collect::<Vec<_>>();
+    assert_eq!(posted("/guilded/login").len(), 1);
+    let webhooks = posted("/discord/channels/1/webhooks");
+    let mut names = webhooks.iter().map(|webhook| webhook["name"].as_str().unwrap()).collect::<Vec<_>>();
+    names.sort_unstable();
+    assert_eq!(names, ["bridge7573", "📀 Alice"]);
+    let mut sent = posted("/discord/webhooks/10/webhook-token").iter().map(|message| message["content"].as_str().unwrap().to_owned()).collect::<Vec<_>>();
+    sent.sort_unstable();
+    assert_eq!(sent.len(), 2, "{:?}", sent);
+    //The platforms' half of the status depends on whether their replay is over yet
+    assert!(sent[0].starts_with("discord:1 + guilded:a (guilded_to_discord)\n"), "{:?}", sent);
+    assert_eq!(sent[1], "hello from guilded");
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,197 @@
+//! The router against fake platforms: one that replays events and one that records what it's sent, slowly when asked.
+mod common;
+
+use async_std::channel::unbounded;
+use bridge7573::*;
+use common::environment_with_config;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Sends its events and then stops the bridge, like a SIGTERM right after they came in
+struct Replay(Vec<BridgeEvent>);
+
+#[async_trait::async_trait]
+impl Platform for Replay {
+    fn name(&self) -> &'static str { DISCORD }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        for event in &self.0 { events.send(event.clone()).await.unwrap() };
+        shutdown.stop.fire();
+        shutdown.close.wait().await;
+        Ok(())
+    }
+
+    async fn send_message(&self, _channel: &str, _message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        Ok(None)
+    }
+}
+
+/// Records `<channel> <content>` as each send starts and finishes, and when it's told to disconnect
+struct Record(Sender<String>);
+
+#[async_trait::async_trait]
+impl Platform for Record {
+    fn name(&self) -> &'static str { GUILDED }
+
+    async fn run(self: Arc<Self>, _events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        shutdown.close.wait().await;
+        self.0.send("closed".to_owned()).await.unwrap();
+        Ok(())
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        self.0.send(format!("start {} {}", channel, message.content)).await.unwrap();
+        if message.content == "slow" { async_std::task::sleep(Duration::from_millis(300)).await };
+        self.0.send(format!("end {} {}", channel, message.content)).await.unwrap();
+        Ok(None)
+    }
+}
+
+fn message(channel: &str, content: &str) -> BridgeEvent {
+    BridgeEvent::MessageCreated(BridgeMessage {
+        id: content.to_owned(),
+        channel: ChannelRef::Discord(channel.to_owned()),
+        author: BridgeUser { platform: DISCORD.to_owned(), id: "1".to_owned(), name: "bob".to_owned(), avatar_url: None },
+        content: content.to_owned(),
+        attachments: vec![],
+    })
+}
+
+fn environment(concurrency: &str) -> Arc<Environment> {
+    let routes = BTreeMap::from([
+        (ChannelRef::Discord("a".to_owned()), vec![ChannelRef::Guilded("a".to_owned())]),
+        (ChannelRef::Discord("b".to_owned()), vec![ChannelRef::Guilded("b".to_owned())]),
+    ]);
+    common::environment(routes, &["--relay-concurrency", concurrency])
+}
+
+async fn relay(concurrency: &str, events: Vec<BridgeEvent>) -> Vec<String> {
+    let (record, recorded) = unbounded();
+    let mut router = Router::new(environment(concurrency));
+    router.add_platform(Arc::new(Replay(events)));
+    router.add_platform(Arc::new(Record(record)));
+    async_std::future::timeout(Duration::from_secs(5), router.run(Shutdown::new())).await.expect("router never finished").unwrap();
+    std::iter::from_fn(|| recorded.try_recv().ok()).collect()
+}
+
+#[async_std::test]
+async fn a_slow_channel_does_not_hold_up_the_others() {
+    let recorded = relay("2", vec![message("a", "slow"), message("a", "after"), message("b", "fast")]).await;
+    assert_eq!(recorded, ["start a slow", "start b fast", "end b fast", "end a slow", "start a after", "end a after", "closed"]);
+    assert!(metrics::render().contains("bridge_messages_bridged_total{direction=\"discord_to_guilded\",binding=\"discord:b -> guilded:b\"}"));
+}
+
+#[async_std::test]
+async fn a_limit_of_one_relays_everything_in_order() {
+    let recorded = relay("1", vec![message("a", "slow"), message("b", "fast"), message("a", "after")]).await;
+    assert_eq!(recorded, ["start a slow", "end a slow", "start b fast", "end b fast", "start a after", "end a after", "closed"]);
+}
+
+/// Can't log in, which no amount of retrying fixes
+struct Broken;
+
+#[async_trait::async_trait]
+impl Platform for Broken {
+    fn name(&self) -> &'static str { MATRIX }
+
+    async fn run(self: Arc<Self>, _events: Sender<BridgeEvent>, _shutdown: Shutdown) -> Result<(), BridgeError> {
+        Err(BridgeError::config("bad password"))
+    }
+
+    async fn send_message(&self, _channel: &str, _message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        Ok(None)
+    }
+}
+
+#[async_std::test]
+async fn a_platform_that_cannot_run_stops_the_others() {
+    let (record, recorded) = unbounded();
+    let mut router = Router::new(environment("8"));
+    router.add_platform(Arc::new(Broken));
+    router.add_platform(Arc::new(Record(record)));
+    let result = async_std::future::timeout(Duration::from_secs(5), router.run(Shutdown::new())).await.expect("router never finished");
+    assert!(matches!(result, Err(BridgeError::Config { .. })), "{:?}", result.err());
+    assert_eq!(recorded.try_recv().ok().as_deref(), Some("closed"));
+}
+
+/// Sends its events, then stops the bridge once it's answered them all. Only "mod" is a moderator.
+struct Chat(Vec<BridgeEvent>, Sender<String>);
+
+#[async_trait::async_trait]
+impl Platform for Chat {
+    fn name(&self) -> &'static str { DISCORD }
+
+    async fn run(self: Arc<Self>, events: Sender<BridgeEvent>, shutdown: Shutdown) -> Result<(), BridgeError> {
+        for event in &self.0 { events.send(event.clone()).await.unwrap() };
+        shutdown.close.wait().await;
+        Ok(())
+    }
+
+    async fn send_message(&self, channel: &str, message: &BridgeMessage) -> Result<Option<String>, BridgeError> {
+        self.1.send(format!("{} {}: {}", channel, message.author.name, message.content)).await.unwrap();
+        Ok(None)
+    }
+
+    async fn is_moderator(&self, _channel: &str, user: &str) -> Result<bool, BridgeError> {
+        Ok(user == "mod")
+    }
+}
+
+fn command(user: &str, content: &str) -> BridgeEvent {
+    BridgeEvent::MessageCreated(BridgeMessage {
+        id: content.to_owned(),
+        channel: ChannelRef::Discord("1".to_owned()),
+        author: BridgeUser { platform: DISCORD.to_owned(), id: user.to_owned(), name: user.to_owned(), avatar_url: None },
+        content: content.to_owned(),
+        attachments: vec![],
+    })
+}
+
+#[async_std::test]
+async fn bridge_commands_are_answered_instead_of_relayed() {
+    let dir = std::env::temp_dir().join(format!("bridge7573_router_commands_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config_path = dir.join("config.json").to_str().unwrap().to_owned();
+    std::fs::write(&config_path, "{}").unwrap();
+    let env = environment_with_config(&config_path, &[]);
+
+    //A moderator on the Guilded side asked for the link already
+    let guilded_mod = BridgeUser { platform: GUILDED.to_owned(), id: "gmod".to_owned(), name: "gmod".to_owned(), avatar_url: None };
+    let asked = commands::answer(&env, &BTreeMap::new(), &ChannelRef::Guilded("a".to_owned()), &guilded_mod, commands::Command::Link(ChannelRef::Discord("1".to_owned())), true).await;
+    assert!(asked.starts_with("A moderator of discord:1 has to run"), "{}", asked);
+
+    let (answers, answered) = unbounded();
+    let mut router = Router::new(env.clone());
+    router.add_platform(Arc::new(Chat(vec![
+        command("bob", "!bridge link a"),
+        command("mod", "!bridge link a"),
+        command("bob", "!bridge pause"),
+        command("mod", "!bridge pause"),
+        command("bob", "!bridge status"),
+        command("mod", "!bridge ignore-user <@bob>"),
+    ], answers)));
+    let shutdown = Shutdown::new();
+    let running = async_std::task::spawn(router.run(shutdown.clone()));
+    let answered = async_std::future::timeout(Duration::from_secs(5), async {
+        let mut all = vec![];
+        for _ in 0..6 { all.push(answered.recv().await.unwrap()) };
+        all
+    }).await.expect("never answered");
+    shutdown.stop.fire();
+    running.await.unwrap();
+
+    assert_eq!(answered, [
+        "1 bridge7573: Only moderators can do that",
+        "1 bridge7573: Linked discord:1 and guilded:a",
+        "1 bridge7573: Only moderators can do that",
+        "1 bridge7573: Paused discord:1 + guilded:a (both)",
+        "1 bridge7573: discord:1 + guilded:a (both, paused)\ndiscord connected",
+        "1 bridge7573: Not relaying anything from discord user bob in discord:1 anymore",
+    ]);
+    let bob = BridgeUser { platform: DISCORD.to_owned(), id: "bob".to_owned(), name: "bob".to_owned(), avatar_url: None };
+    assert!(admin::is_ignored(&env, &ChannelRef::Discord("1".to_owned()), &bob).await.unwrap());
+    assert!(!admin::is_ignored(&env, &ChannelRef::Discord("2".to_owned()), &bob).await.unwrap());
+    assert!(env.config.read().await.routes_from(&ChannelRef::Discord("1".to_owned())).is_empty());
+    assert!(std::fs::read_to_string(&config_path).unwrap().contains("\"paused\": true"));
+    std::fs::remove_dir_all(dir).unwrap();
+}